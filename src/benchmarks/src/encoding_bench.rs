@@ -39,6 +39,9 @@ impl EncodingBench {
                 num_rows: 1,
                 time_range: (1..2).into(),
                 size: 1,
+                primary_key_stats: None,
+                deduplicated: false,
+                merged: false,
             },
         );
         let sstfiles = vec![sstfile.clone(); config.record_count];
@@ -21,8 +21,158 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
+    /// Errors without a specific kind, they are not expected to be fixed by
+    /// retrying.
     #[error(transparent)]
     Internal(#[from] anyhow::Error),
+
+    #[error("Not found, {0}")]
+    NotFound(String),
+
+    /// Schema is invalid, or conflicts with the persisted one.
+    #[error("Invalid schema, {0}")]
+    InvalidSchema(String),
+
+    /// Request is invalid, such as data out of the given time range.
+    #[error("Invalid request, {0}")]
+    InvalidRequest(String),
+
+    /// Writes are stopped too long since background jobs can't catch up,
+    /// it's fine to retry later.
+    #[error("Write stalled, reason:{0}")]
+    WriteStalled(String),
+
+    /// Persisted data, such as manifest or wal, can't be decoded.
+    #[error("Data corrupted, {0}")]
+    Corruption(String),
+
+    /// Object store failed, most of such errors are transient.
+    #[error("Object store error, {context}, err:{source}")]
+    ObjectStore {
+        context: String,
+        source: object_store::Error,
+    },
+
+    /// Operation timed out, such as a request to object store.
+    #[error("Timeout, {0}")]
+    Timeout(String),
+}
+
+impl Error {
+    /// Whether the failed operation may succeed when retried as is.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::WriteStalled(_) | Error::Timeout(_) => true,
+            Error::ObjectStore { source, .. } => !matches!(
+                source,
+                object_store::Error::AlreadyExists { .. }
+                    | object_store::Error::InvalidPath { .. }
+                    | object_store::Error::NotSupported { .. }
+                    | object_store::Error::NotImplemented
+                    | object_store::Error::UnknownConfigurationKey { .. }
+            ),
+            Error::Internal(_)
+            | Error::NotFound(_)
+            | Error::InvalidSchema(_)
+            | Error::InvalidRequest(_)
+            | Error::Corruption(_) => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Attach context to errors of object store, like [`anyhow::Context`].
+///
+/// Objects not found are converted to [`Error::NotFound`], and timed out
+/// requests are converted to [`Error::Timeout`].
+pub trait StoreContext<T> {
+    fn store_context<C>(self, context: C) -> Result<T>
+    where
+        C: Into<String>;
+
+    fn with_store_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
+}
+
+impl<T> StoreContext<T> for std::result::Result<T, object_store::Error> {
+    fn store_context<C>(self, context: C) -> Result<T>
+    where
+        C: Into<String>,
+    {
+        self.with_store_context(|| context)
+    }
+
+    fn with_store_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|source| {
+            let context = f().into();
+            match source {
+                object_store::Error::NotFound { path, .. } => {
+                    Error::NotFound(format!("{context}, path:{path}"))
+                }
+                source if is_timed_out(&source) => {
+                    Error::Timeout(format!("{context}, err:{source}"))
+                }
+                source => Error::ObjectStore { context, source },
+            }
+        })
+    }
+}
+
+/// Whether `err` is caused by an io timeout, which is how both local files
+/// and http clients of object store report it.
+fn is_timed_out(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_context() {
+        let res: std::result::Result<(), _> = Err(object_store::Error::NotFound {
+            path: "a/b".to_string(),
+            source: "missing".into(),
+        });
+        let err = res.store_context("get file").unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)));
+        assert_eq!("Not found, get file, path:a/b", err.to_string());
+        assert!(!err.is_retryable());
+
+        let res: std::result::Result<(), _> = Err(object_store::Error::Generic {
+            store: "S3",
+            source: "connection reset".into(),
+        });
+        let err = res.with_store_context(|| "put file").unwrap_err();
+        assert!(matches!(err, Error::ObjectStore { .. }));
+        assert!(err.is_retryable());
+
+        let res: std::result::Result<(), _> = Err(object_store::Error::Generic {
+            store: "S3",
+            source: Box::new(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "request timed out",
+            )),
+        });
+        let err = res.store_context("get file").unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)));
+        assert!(err.is_retryable());
+    }
+}
@@ -19,6 +19,6 @@ mod error;
 mod size_ext;
 mod time_ext;
 
-pub use error::{AnyhowError, Error, Result};
+pub use error::{AnyhowError, Error, Result, StoreContext};
 pub use size_ext::ReadableSize;
 pub use time_ext::{now, ReadableDuration};
@@ -68,6 +68,7 @@ pub struct MetricEngineConfig {
 pub struct ThreadConfig {
     pub manifest_thread_num: usize,
     pub sst_thread_num: usize,
+    pub read_thread_num: usize,
 }
 
 impl Default for ThreadConfig {
@@ -75,6 +76,7 @@ impl Default for ThreadConfig {
         Self {
             manifest_thread_num: 2,
             sst_thread_num: 2,
+            read_thread_num: 4,
         }
     }
 }
@@ -106,7 +106,9 @@ pub fn main() {
     );
     let sst_compact_runtime =
         build_multi_runtime("sst-compact", config.metric_engine.threads.sst_thread_num);
-    let runtimes = StorageRuntimes::new(manifest_compact_runtime, sst_compact_runtime);
+    let read_runtime = build_multi_runtime("read", config.metric_engine.threads.read_thread_num);
+    let runtimes =
+        StorageRuntimes::new(manifest_compact_runtime, sst_compact_runtime, read_runtime);
     let object_store_config = match config.metric_engine.storage.object_store {
         ObjectStorageConfig::Local(v) => v,
         ObjectStorageConfig::S3Like(_) => panic!("S3 not support yet"),
@@ -222,6 +224,7 @@ fn bench_write(
                         batch,
                         enable_check: false,
                         time_range: (now..now + 1).into(),
+                        idempotency_key: None,
                     })
                     .await
                 {
@@ -0,0 +1,515 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Aggregations evaluated by scan after rows are merged.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use arrow::{
+    array::{Array, RecordBatch},
+    compute::concat_batches,
+    datatypes::{DataType, Schema},
+};
+use datafusion::{
+    common::ScalarValue,
+    datasource::streaming::StreamingTable,
+    execution::SendableRecordBatchStream,
+    functions_aggregate::expr_fn::{count, last_value, max, min, sum},
+    logical_expr::{cast, lit, ExprFunctionExt},
+    physical_plan::stream::RecordBatchStreamAdapter,
+    prelude::{ident, SessionContext},
+};
+use parquet::{
+    arrow::arrow_reader::statistics::StatisticsConverter, file::metadata::ParquetMetaData,
+};
+
+use crate::{
+    ensure,
+    sst::MERGED_METADATA_KEY,
+    types::{StorageSchema, RESERVED_COLUMN_NAME},
+    write::OnceStream,
+    Error, Result,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    /// Number of non-null values.
+    Count,
+    Min,
+    Max,
+    Sum,
+    /// Value with the latest timestamp, it requires a timestamp column.
+    Last,
+}
+
+impl fmt::Display for AggregateFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AggregateFunc::Count => "count",
+            AggregateFunc::Min => "min",
+            AggregateFunc::Max => "max",
+            AggregateFunc::Sum => "sum",
+            AggregateFunc::Last => "last",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Aggregate `column` by `func`, output column is named as `func(column)`,
+/// such as `max(value)`.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub func: AggregateFunc,
+    pub column: String,
+}
+
+impl Aggregate {
+    pub fn new(func: AggregateFunc, column: impl Into<String>) -> Self {
+        Self {
+            func,
+            column: column.into(),
+        }
+    }
+
+    pub fn output_name(&self) -> String {
+        format!("{}({})", self.func, self.column)
+    }
+}
+
+/// Aggregate rows of scan after they are merged.
+///
+/// Rows are grouped by the first `group_by_primary_keys` primary keys, and
+/// by buckets of timestamp column when `time_bucket` is set. Output contains
+/// group columns followed by aggregates, and is sorted by group columns.
+#[derive(Debug, Clone, Default)]
+pub struct AggregateRequest {
+    pub group_by_primary_keys: usize,
+    /// Width of time buckets, the bucket column is named after the timestamp
+    /// column, and its value is the start of bucket as `Int64`.
+    pub time_bucket: Option<Duration>,
+    pub aggregates: Vec<Aggregate>,
+}
+
+impl AggregateRequest {
+    pub(crate) fn check(&self, schema: &StorageSchema) -> Result<()> {
+        ensure!(
+            !self.aggregates.is_empty(),
+            Error::InvalidRequest("aggregates are empty".to_string())
+        );
+        ensure!(
+            self.group_by_primary_keys <= schema.num_primary_keys,
+            Error::InvalidRequest(format!(
+                "too many primary keys to group by, num_primary_keys:{}, given:{}",
+                schema.num_primary_keys, self.group_by_primary_keys
+            ))
+        );
+        if let Some(bucket) = self.time_bucket {
+            ensure!(
+                bucket.as_millis() > 0,
+                Error::InvalidRequest("time bucket should be at least 1ms".to_string())
+            );
+            ensure!(
+                schema
+                    .timestamp_idx
+                    .is_some_and(|idx| idx >= self.group_by_primary_keys),
+                Error::InvalidRequest(
+                    "time bucket requires timestamp column not grouped by".to_string()
+                )
+            );
+        }
+        for aggregate in &self.aggregates {
+            ensure!(
+                aggregate.func != AggregateFunc::Last || schema.timestamp_idx.is_some(),
+                Error::InvalidRequest("last requires timestamp column".to_string())
+            );
+            let found = schema
+                .arrow_schema
+                .index_of(&aggregate.column)
+                .is_ok_and(|idx| idx != schema.seq_idx && idx != schema.reserved_idx);
+            ensure!(
+                found,
+                Error::InvalidRequest(format!(
+                    "aggregate column not found, name:{}",
+                    aggregate.column
+                ))
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Columns required to aggregate.
+    pub(crate) fn projections(&self, schema: &StorageSchema) -> Result<Vec<usize>> {
+        let mut projections = (0..self.group_by_primary_keys).collect::<Vec<_>>();
+        projections.extend(schema.timestamp_idx);
+        for aggregate in &self.aggregates {
+            let idx = schema
+                .arrow_schema
+                .index_of(&aggregate.column)
+                .map_err(|_| {
+                    Error::InvalidRequest(format!(
+                        "aggregate column not found, name:{}",
+                        aggregate.column
+                    ))
+                })?;
+            if !projections.contains(&idx) {
+                projections.push(idx);
+            }
+        }
+
+        Ok(projections)
+    }
+
+    /// Whether aggregates can be computed from statistics of ssts, which
+    /// requires rows not grouped, and min/max are exact.
+    pub(crate) fn support_stats(&self, schema: &StorageSchema) -> bool {
+        self.group_by_primary_keys == 0
+            && self.time_bucket.is_none()
+            && self
+                .aggregates
+                .iter()
+                .all(|aggregate| match aggregate.func {
+                    AggregateFunc::Count => true,
+                    // Statistics of binary columns may be truncated.
+                    AggregateFunc::Min | AggregateFunc::Max => schema
+                        .arrow_schema
+                        .field_with_name(&aggregate.column)
+                        .is_ok_and(|f| f.data_type().is_primitive()),
+                    AggregateFunc::Sum | AggregateFunc::Last => false,
+                })
+    }
+
+    /// Aggregate merged `rows`, partial results of `stats` are merged into
+    /// the output when given.
+    pub(crate) async fn execute(
+        &self,
+        schema: &StorageSchema,
+        rows: SendableRecordBatchStream,
+        reverse: bool,
+        stats: Option<StatsAggregator>,
+    ) -> Result<SendableRecordBatchStream> {
+        let ctx = SessionContext::default();
+        let table = StreamingTable::try_new(rows.schema(), vec![Arc::new(OnceStream::new(rows))])
+            .context("build rows table")?;
+        let df = ctx.read_table(Arc::new(table)).context("read rows table")?;
+
+        let timestamp = schema.timestamp_column();
+        let mut group_columns = schema.arrow_schema.fields()[..self.group_by_primary_keys]
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        let mut group_exprs = group_columns
+            .iter()
+            .map(|name| ident(*name))
+            .collect::<Vec<_>>();
+        if let (Some(bucket), Some(name)) = (self.time_bucket, timestamp) {
+            let width = lit(bucket.as_millis() as i64);
+            let bucket_expr = cast(ident(name), DataType::Int64) / width.clone() * width;
+            group_exprs.push(bucket_expr.alias(name));
+            group_columns.push(name);
+        }
+        let aggr_exprs = self
+            .aggregates
+            .iter()
+            .map(|aggregate| {
+                let column = ident(&aggregate.column);
+                let expr = match aggregate.func {
+                    AggregateFunc::Count => count(column),
+                    AggregateFunc::Min => min(column),
+                    AggregateFunc::Max => max(column),
+                    AggregateFunc::Sum => sum(column),
+                    AggregateFunc::Last => last_value(vec![column])
+                        .order_by(vec![ident(timestamp.unwrap()).sort(true, true)])
+                        .build()
+                        .context("build last value")?,
+                };
+                Ok(expr.alias(aggregate.output_name()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut df = df
+            .aggregate(group_exprs, aggr_exprs)
+            .context("build aggregate")?;
+        if !group_columns.is_empty() {
+            let sort_exprs = group_columns
+                .into_iter()
+                .map(|name| {
+                    ident(name).sort(!reverse /* asc */, !reverse /* nulls_first */)
+                })
+                .collect();
+            df = df.sort(sort_exprs).context("build aggregate sort")?;
+        }
+
+        let Some(stats) = stats else {
+            return Ok(df.execute_stream().await.context("execute aggregate")?);
+        };
+        let output_schema = Arc::new(Schema::from(df.schema()));
+        let batches = df.collect().await.context("execute aggregate")?;
+        let batch = concat_batches(&output_schema, &batches).context("concat aggregates")?;
+        let batch = stats.merge_into(batch)?;
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            output_schema,
+            futures::stream::iter([Ok(batch)]),
+        )))
+    }
+}
+
+/// Partial results of aggregates computed from statistics of ssts.
+pub(crate) struct StatsAggregator {
+    aggregates: Vec<Aggregate>,
+    values: Vec<ScalarValue>,
+}
+
+impl StatsAggregator {
+    pub fn new(aggregates: Vec<Aggregate>) -> Self {
+        let values = vec![ScalarValue::Null; aggregates.len()];
+        Self { aggregates, values }
+    }
+
+    /// Update by statistics of one sst, `false` is returned and nothing is
+    /// updated when they can't be used.
+    ///
+    /// Only ssts with merged rows and without tombstones can be used, and
+    /// they should not be merged with other ssts when read.
+    pub fn try_update(&mut self, metadata: &ParquetMetaData, file_schema: &Schema) -> Result<bool> {
+        let merged = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .is_some_and(|kvs| kvs.iter().any(|kv| kv.key == MERGED_METADATA_KEY));
+        if !merged {
+            return Ok(false);
+        }
+
+        let parquet_schema = metadata.file_metadata().schema_descr();
+        let row_groups = metadata.row_groups();
+        let num_rows = metadata.file_metadata().num_rows() as u64;
+        // Non-null counts of row groups, `None` means statistics are missing.
+        let non_null_counts = |column: &str| -> Result<Option<Vec<u64>>> {
+            let converter = StatisticsConverter::try_new(column, file_schema, parquet_schema)
+                .context("create statistics converter")?;
+            let null_counts = converter
+                .row_group_null_counts(row_groups)
+                .context("get null counts")?;
+            if null_counts.null_count() > 0 {
+                return Ok(None);
+            }
+            let counts = row_groups
+                .iter()
+                .zip(null_counts.values())
+                .map(|(row_group, nulls)| row_group.num_rows() as u64 - nulls)
+                .collect();
+            Ok(Some(counts))
+        };
+        // Reserved column is only set for tombstones.
+        match non_null_counts(RESERVED_COLUMN_NAME)? {
+            Some(counts) if counts.iter().all(|n| *n == 0) => {}
+            _ => return Ok(false),
+        }
+
+        let mut values = Vec::with_capacity(self.aggregates.len());
+        for aggregate in &self.aggregates {
+            if file_schema.field_with_name(&aggregate.column).is_err() {
+                return Ok(false);
+            }
+            let Some(counts) = non_null_counts(&aggregate.column)? else {
+                return Ok(false);
+            };
+            let value = match aggregate.func {
+                AggregateFunc::Count => {
+                    let count: u64 = counts.iter().sum();
+                    debug_assert!(count <= num_rows);
+                    ScalarValue::Int64(Some(count as i64))
+                }
+                AggregateFunc::Min | AggregateFunc::Max => {
+                    let converter = StatisticsConverter::try_new(
+                        &aggregate.column,
+                        file_schema,
+                        parquet_schema,
+                    )
+                    .context("create statistics converter")?;
+                    let stats = if aggregate.func == AggregateFunc::Min {
+                        converter.row_group_mins(row_groups)
+                    } else {
+                        converter.row_group_maxes(row_groups)
+                    }
+                    .context("get min/max statistics")?;
+                    let mut value = ScalarValue::Null;
+                    for (i, count) in counts.iter().enumerate() {
+                        if *count == 0 {
+                            continue;
+                        }
+                        if stats.is_null(i) {
+                            return Ok(false);
+                        }
+                        let stat =
+                            ScalarValue::try_from_array(&stats, i).context("convert statistics")?;
+                        value = Self::merge_value(aggregate.func, value, stat)?;
+                    }
+                    value
+                }
+                AggregateFunc::Sum | AggregateFunc::Last => return Ok(false),
+            };
+            values.push(value);
+        }
+
+        for (i, value) in values.into_iter().enumerate() {
+            let old = std::mem::replace(&mut self.values[i], ScalarValue::Null);
+            self.values[i] = Self::merge_value(self.aggregates[i].func, old, value)?;
+        }
+        Ok(true)
+    }
+
+    fn merge_value(func: AggregateFunc, lhs: ScalarValue, rhs: ScalarValue) -> Result<ScalarValue> {
+        if lhs.is_null() {
+            return Ok(rhs);
+        }
+        if rhs.is_null() {
+            return Ok(lhs);
+        }
+
+        let value = match func {
+            AggregateFunc::Count => lhs.add(&rhs).context("add counts")?,
+            AggregateFunc::Min if rhs < lhs => rhs,
+            AggregateFunc::Max if rhs > lhs => rhs,
+            _ => lhs,
+        };
+        Ok(value)
+    }
+
+    /// Merge partial results into the one row output of aggregating other
+    /// rows.
+    fn merge_into(self, batch: RecordBatch) -> Result<RecordBatch> {
+        ensure!(
+            batch.num_rows() == 1 && batch.num_columns() == self.aggregates.len(),
+            "aggregate without group should output one row, rows:{}, columns:{}",
+            batch.num_rows(),
+            batch.num_columns()
+        );
+        let mut columns = Vec::with_capacity(batch.num_columns());
+        for ((aggregate, value), column) in
+            self.aggregates.iter().zip(self.values).zip(batch.columns())
+        {
+            let data_type = column.data_type();
+            let value = if value.is_null() {
+                value
+            } else {
+                value.cast_to(data_type).context("cast statistics")?
+            };
+            let current =
+                ScalarValue::try_from_array(column, 0).context("convert aggregate result")?;
+            let merged = Self::merge_value(aggregate.func, current, value)?;
+            let array = if merged.is_null() {
+                arrow::array::new_null_array(data_type, 1)
+            } else {
+                merged.to_array().context("build aggregate result")?
+            };
+            columns.push(array);
+        }
+
+        let batch =
+            RecordBatch::try_new(batch.schema(), columns).context("build aggregate batch")?;
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use parquet::{
+        arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+        file::{metadata::KeyValue, properties::WriterProperties},
+    };
+
+    use super::*;
+    use crate::{record_batch, types::SEQ_COLUMN_NAME};
+
+    fn build_file(batch: RecordBatch, merged: bool) -> ParquetRecordBatchReaderBuilder<Bytes> {
+        let mut buf = Vec::new();
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(2)
+            .build();
+        let mut writer = ArrowWriter::try_new(&mut buf, batch.schema(), Some(props)).unwrap();
+        writer.write(&batch).unwrap();
+        if merged {
+            writer.append_key_value_metadata(KeyValue::new(
+                MERGED_METADATA_KEY.to_string(),
+                "true".to_string(),
+            ));
+        }
+        writer.close().unwrap();
+        ParquetRecordBatchReaderBuilder::try_new(Bytes::from(buf)).unwrap()
+    }
+
+    #[test]
+    fn test_stats_aggregator() {
+        let aggregates = vec![
+            Aggregate::new(AggregateFunc::Count, "value"),
+            Aggregate::new(AggregateFunc::Min, "value"),
+            Aggregate::new(AggregateFunc::Max, "pk1"),
+        ];
+        let build_batch = |reserved: Vec<Option<u64>>| {
+            record_batch!(
+                ("pk1", UInt8, vec![1, 2, 3]),
+                ("value", Int64, vec![Some(5), None, Some(3)]),
+                (SEQ_COLUMN_NAME, UInt64, vec![1, 2, 3]),
+                (RESERVED_COLUMN_NAME, UInt64, reserved)
+            )
+            .unwrap()
+        };
+        let mut aggregator = StatsAggregator::new(aggregates.clone());
+
+        // Not merged.
+        let file = build_file(build_batch(vec![None; 3]), false);
+        assert!(!aggregator
+            .try_update(file.metadata(), file.schema())
+            .unwrap());
+        // Contains tombstones.
+        let file = build_file(build_batch(vec![None, Some(1), None]), true);
+        assert!(!aggregator
+            .try_update(file.metadata(), file.schema())
+            .unwrap());
+
+        let file = build_file(build_batch(vec![None; 3]), true);
+        assert!(aggregator
+            .try_update(file.metadata(), file.schema())
+            .unwrap());
+        assert_eq!(
+            vec![
+                ScalarValue::Int64(Some(2)),
+                ScalarValue::Int64(Some(3)),
+                ScalarValue::UInt8(Some(3))
+            ],
+            aggregator.values
+        );
+
+        // Merged with aggregates of other rows.
+        let batch = record_batch!(
+            ("count(value)", Int64, vec![4]),
+            ("min(value)", Int64, vec![1]),
+            ("max(pk1)", UInt8, vec![None])
+        )
+        .unwrap();
+        let expected = record_batch!(
+            ("count(value)", Int64, vec![6]),
+            ("min(value)", Int64, vec![1]),
+            ("max(pk1)", UInt8, vec![3])
+        )
+        .unwrap();
+        assert_eq!(expected, aggregator.merge_into(batch).unwrap());
+    }
+}
@@ -15,21 +15,26 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::Context;
-use async_scoped::TokioScope;
+use arrow::{
+    array::{AsArray, BooleanArray, RecordBatch},
+    compute::filter_record_batch,
+    datatypes::UInt64Type,
+};
+use common::StoreContext;
 use datafusion::{execution::TaskContext, physical_plan::execute_stream};
 use futures::StreamExt;
 use object_store::path::Path;
-use parquet::{
-    arrow::{async_writer::ParquetObjectWriter, AsyncArrowWriter},
-    file::properties::WriterProperties,
-};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
 use tracing::{debug, error, trace};
 
 use crate::{
@@ -38,7 +43,8 @@ use crate::{
     manifest::{ManifestRef, ManifestUpdate},
     read::ParquetReader,
     sst::{FileId, FileMeta, SstFile, SstPathGenerator},
-    types::{ObjectStoreRef, RuntimeRef, StorageSchema},
+    types::{ObjectStoreRef, RowKind, RuntimeRef, TimeRange},
+    write::ParquetWriter,
     Result,
 };
 
@@ -49,15 +55,15 @@ pub struct Executor {
 
 struct Inner {
     runtime: RuntimeRef,
-    store: ObjectStoreRef,
-    schema: StorageSchema,
     manifest: ManifestRef,
-    sst_path_gen: Arc<SstPathGenerator>,
+    segment_duration: Duration,
     parquet_reader: Arc<ParquetReader>,
-    write_props: WriterProperties,
-    inused_memory: AtomicU64,
+    parquet_writer: Arc<ParquetWriter>,
+    inused_memory: Arc<AtomicU64>,
     mem_limit: u64,
     trigger_tx: Sender<()>,
+    /// Ids of obsolete ssts not held by anyone are sent to it to be deleted.
+    purge_tx: UnboundedSender<FileId>,
 }
 
 impl Executor {
@@ -65,25 +71,27 @@ impl Executor {
     pub fn new(
         runtime: RuntimeRef,
         store: ObjectStoreRef,
-        schema: StorageSchema,
         manifest: ManifestRef,
+        segment_duration: Duration,
         sst_path_gen: Arc<SstPathGenerator>,
         parquet_reader: Arc<ParquetReader>,
-        write_props: WriterProperties,
+        parquet_writer: Arc<ParquetWriter>,
+        inused_memory: Arc<AtomicU64>,
         mem_limit: u64,
         trigger_tx: Sender<()>,
     ) -> Self {
+        let (purge_tx, purge_rx) = mpsc::unbounded_channel();
+        runtime.spawn(Self::purge_loop(store, sst_path_gen, purge_rx));
         let inner = Inner {
             runtime,
-            store,
-            schema,
             manifest,
-            sst_path_gen,
+            segment_duration,
             parquet_reader,
-            write_props,
+            parquet_writer,
             mem_limit,
-            inused_memory: AtomicU64::new(0),
+            inused_memory,
             trigger_tx,
+            purge_tx,
         };
         Self {
             inner: Arc::new(inner),
@@ -150,6 +158,27 @@ impl Executor {
         }
     }
 
+    /// Tombstones are required to delete rows in other ssts of the segment,
+    /// so they can only be dropped when all ssts of the segment are inputs.
+    async fn can_drop_tombstones(&self, task: &Task) -> bool {
+        let segment = task.inputs[0]
+            .meta()
+            .time_range
+            .start
+            .truncate_by(self.inner.segment_duration);
+        let segment_range = TimeRange::new(
+            segment,
+            segment + self.inner.segment_duration.as_millis() as i64,
+        );
+        let inputs = task.inputs.iter().map(|f| f.id()).collect::<HashSet<_>>();
+        self.inner
+            .manifest
+            .find_ssts(&segment_range)
+            .await
+            .iter()
+            .all(|f| inputs.contains(&f.id()))
+    }
+
     // TODO: Merge input sst files into one new sst file
     // and delete the expired sst files
     pub async fn do_compaction(&self, task: &Task) -> Result<()> {
@@ -158,51 +187,53 @@ impl Executor {
 
         debug!(input_len = task.inputs.len(), "Start do compaction");
         let mut time_range = task.inputs[0].meta().time_range.clone();
+        let mut max_sequence = task.inputs[0].meta().max_sequence;
         for f in &task.inputs[1..] {
             time_range.merge(&f.meta().time_range);
+            max_sequence = max_sequence.max(f.meta().max_sequence);
         }
         let plan = self.inner.parquet_reader.build_df_plan(
             task.inputs.clone(),
+            Vec::new(), // mem_batches
             None,       // projection
             Vec::new(), // predicate
             true,       // keep_builtin
+            None,       // max_sequence
         )?;
+        let drop_tombstones = self.can_drop_tombstones(task).await;
         let mut stream = execute_stream(plan, Arc::new(TaskContext::default()))
             .context("execute datafusion plan")?;
 
         let file_id = SstFile::allocate_id();
-        let file_path = self.inner.sst_path_gen.generate(file_id);
-        let file_path = Path::from(file_path);
-        let object_store_writer =
-            ParquetObjectWriter::new(self.inner.store.clone(), file_path.clone());
-        let mut writer = AsyncArrowWriter::try_new(
-            object_store_writer,
-            self.inner.schema.arrow_schema.clone(),
-            Some(self.inner.write_props.clone()),
-        )
-        .context("create arrow writer")?;
-        let mut num_rows = 0;
         // TODO: support multi-part write
+        let mut writer = self.inner.parquet_writer.open(file_id)?;
         while let Some(batch) = stream.next().await {
             let batch = batch.context("execute plan")?;
-            num_rows += batch.num_rows();
-            writer.write(&batch).await.context("write batch")?;
+            let batch = if drop_tombstones {
+                remove_tombstones(batch)?
+            } else {
+                batch
+            };
+            writer.write(batch).await?;
         }
-        writer.close().await.context("close writer")?;
-        let object_meta = self
-            .inner
-            .store
-            .head(&file_path)
-            .await
-            .context("get object meta")?;
+        writer.mark_merged();
+        let summary = writer.close().await?;
         let file_meta = FileMeta {
-            max_sequence: file_id,
-            num_rows: num_rows as u32,
-            size: object_meta.size as u32,
-            time_range: time_range.clone(),
+            // Rows keep their original sequence, so does the max one.
+            max_sequence,
+            num_rows: summary.num_rows as u32,
+            size: summary.size as u32,
+            // Input ranges may be wider than the data, since some rows may be
+            // merged away, so prefer the range of output rows.
+            time_range: summary.time_range.unwrap_or(time_range),
+            primary_key_stats: summary.primary_key_stats,
+            deduplicated: summary.deduplicated,
+            merged: true,
         };
         debug!(file_meta = ?file_meta, "Compact output new sst");
-        // First add new sst to manifest, then delete expired/old sst
+        // First add new sst to manifest, then delete expired/old sst.
+        // The new sst is added even if all rows are deleted, since its max
+        // sequence is required by wal replay.
         let to_adds = vec![SstFile::new(file_id, file_meta)];
         let to_deletes = task
             .expireds
@@ -212,47 +243,52 @@ impl Executor {
             .collect::<Vec<_>>();
         self.inner
             .manifest
-            .update(ManifestUpdate::new(to_adds, to_deletes.clone()))
+            .update(ManifestUpdate::new(to_adds, to_deletes))
             .await?;
 
         // From now on, no error should be returned!
         // Because we have already updated manifest.
-        self.delete_ssts(to_deletes.into_iter());
+        // Old ssts may still be read by scans pinning older versions, so they
+        // are deleted after those scans finish.
+        for f in task.inputs.iter().chain(task.expireds.iter()) {
+            f.mark_obsolete(self.inner.purge_tx.clone());
+        }
         Ok(())
     }
 
-    fn delete_ssts<I>(&self, ids: I)
-    where
-        I: Iterator<Item = FileId>,
-    {
-        let (_, results) = TokioScope::scope_and_block(|scope| {
-            for id in ids {
-                let path = Path::from(self.inner.sst_path_gen.generate(id));
-                trace!(id, "Delete sst file");
-                scope.spawn(async move {
-                    self.inner
-                        .store
-                        .delete(&path)
-                        .await
-                        .with_context(|| format!("failed to delete file, path:{path}"))
-                });
-            }
-        });
-        for res in results {
-            match res {
-                Err(e) => {
-                    error!("Failed to join delete task, err:{e}")
-                }
-                Ok(v) => {
-                    if let Err(e) = v {
-                        error!("Failed to delete sst, err:{e}")
-                    }
-                }
+    /// Delete obsolete ssts received from `purge_rx`.
+    async fn purge_loop(
+        store: ObjectStoreRef,
+        sst_path_gen: Arc<SstPathGenerator>,
+        mut purge_rx: UnboundedReceiver<FileId>,
+    ) {
+        while let Some(id) = purge_rx.recv().await {
+            let path = Path::from(sst_path_gen.generate(id));
+            trace!(id, "Delete sst file");
+            if let Err(e) = store
+                .delete(&path)
+                .await
+                .with_store_context(|| format!("failed to delete file, path:{path}"))
+            {
+                error!("Failed to delete sst, err:{e}")
             }
         }
     }
 }
 
+fn remove_tombstones(batch: RecordBatch) -> Result<RecordBatch> {
+    // Reserved column is always the last one.
+    let reserved = batch
+        .column(batch.num_columns() - 1)
+        .as_primitive::<UInt64Type>();
+    let predicate = (0..batch.num_rows())
+        .map(|idx| Some(RowKind::from_reserved(reserved, idx) == RowKind::Put))
+        .collect::<BooleanArray>();
+    let batch = filter_record_batch(&batch, &predicate).context("remove tombstones")?;
+
+    Ok(batch)
+}
+
 pub struct Runnable {
     executor: Executor,
     task: Task,
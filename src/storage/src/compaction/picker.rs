@@ -212,6 +212,9 @@ mod tests {
                         num_rows: i as u32,
                         size: (100 - i) as u32, // size desc
                         time_range: (i * 10..(i * 10 + 10)).into(),
+                        primary_key_stats: None,
+                        deduplicated: false,
+                        merged: false,
                     },
                 )
             })
@@ -15,10 +15,12 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 
 use anyhow::Context;
-use parquet::file::properties::WriterProperties;
 use tokio::{
     sync::mpsc::{self, Receiver, Sender},
     task::JoinHandle,
@@ -33,7 +35,8 @@ use crate::{
     manifest::ManifestRef,
     read::ParquetReader,
     sst::SstPathGenerator,
-    types::{ObjectStoreRef, RuntimeRef, StorageSchema},
+    types::{ObjectStoreRef, RuntimeRef},
+    write::ParquetWriter,
     Result,
 };
 
@@ -44,6 +47,9 @@ pub struct Scheduler {
     trigger_tx: Sender<()>,
     task_handle: JoinHandle<()>,
     picker_handle: JoinHandle<()>,
+    /// Memory used by running compaction tasks.
+    inused_memory: Arc<AtomicU64>,
+    memory_limit: u64,
 }
 
 impl Scheduler {
@@ -52,26 +58,27 @@ impl Scheduler {
         runtime: RuntimeRef,
         manifest: ManifestRef,
         store: ObjectStoreRef,
-        schema: StorageSchema,
         segment_duration: Duration,
         sst_path_gen: Arc<SstPathGenerator>,
         parquet_reader: Arc<ParquetReader>,
+        parquet_writer: Arc<ParquetWriter>,
         config: SchedulerConfig,
-        write_props: WriterProperties,
     ) -> Self {
         let (task_tx, task_rx) = mpsc::channel(config.max_pending_compaction_tasks);
         let (trigger_tx, trigger_rx) = mpsc::channel::<()>(1);
+        let inused_memory = Arc::new(AtomicU64::new(0));
         let task_handle = {
             let store = store.clone();
             let manifest = manifest.clone();
             let executor = Executor::new(
                 runtime.clone(),
                 store,
-                schema,
                 manifest,
+                segment_duration,
                 sst_path_gen,
                 parquet_reader,
-                write_props,
+                parquet_writer,
+                inused_memory.clone(),
                 config.memory_limit.0,
                 trigger_tx.clone(),
             );
@@ -100,9 +107,19 @@ impl Scheduler {
             trigger_tx,
             task_handle,
             picker_handle,
+            inused_memory,
+            memory_limit: config.memory_limit.0,
         }
     }
 
+    pub fn inused_memory(&self) -> Arc<AtomicU64> {
+        self.inused_memory.clone()
+    }
+
+    pub fn memory_limit(&self) -> u64 {
+        self.memory_limit
+    }
+
     pub fn trigger_compaction(&self) -> Result<()> {
         self.trigger_tx
             .try_send(())
@@ -115,6 +115,9 @@ pub struct WriteConfig {
     pub compression: ParquetCompression,
     // use to set column props with column name
     pub column_options: Option<HashMap<String, ColumnOptions>>,
+    /// Memory used to sort streaming writes, sorted runs are spilled to disk
+    /// once it's exceeded.
+    pub sort_memory_limit: ReadableSize,
 }
 
 impl Default for WriteConfig {
@@ -128,6 +131,7 @@ impl Default for WriteConfig {
             encoding: ParquetEncoding::Plain,
             compression: ParquetCompression::Snappy,
             column_options: None,
+            sort_memory_limit: ReadableSize::mb(256),
         }
     }
 }
@@ -140,6 +144,9 @@ pub struct ManifestConfig {
     pub min_merge_threshold: usize,
     pub hard_merge_threshold: usize,
     pub soft_merge_threshold: usize,
+    /// Retries of a write with the same idempotency key are ignored within
+    /// this window since the first one is accepted.
+    pub idempotency_window: ReadableDuration,
 }
 
 impl Default for ManifestConfig {
@@ -150,6 +157,120 @@ impl Default for ManifestConfig {
             min_merge_threshold: 10,
             soft_merge_threshold: 50,
             hard_merge_threshold: 90,
+            idempotency_window: ReadableDuration::minutes(10),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalWalConfig {
+    pub dir: String,
+    /// Active segment will be rolled over once its size exceeds this.
+    pub segment_max_size: ReadableSize,
+}
+
+impl Default for LocalWalConfig {
+    fn default() -> Self {
+        Self {
+            dir: "/tmp/horaedb/wal".to_string(),
+            segment_max_size: ReadableSize::mb(64),
+        }
+    }
+}
+
+/// Write-ahead log used to make writes durable before they are acknowledged.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type", deny_unknown_fields)]
+pub enum WalConfig {
+    #[default]
+    Disabled,
+    /// WAL is stored in local disk, and fsynced on every append.
+    Local(LocalWalConfig),
+    /// WAL is stored in the same object store with data, every append will
+    /// be persisted as a new segment object.
+    ObjectStore,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemTableConfig {
+    /// Writes are buffered in memtable when enabled, it should be used along
+    /// with wal, otherwise unflushed writes will be lost after crash.
+    pub enable: bool,
+    // Memtable will be flushed when any of the following threshold is reached.
+    pub max_size: ReadableSize,
+    pub max_rows: usize,
+    pub max_age: ReadableDuration,
+    /// Interval to check whether memtables exceed `max_age`.
+    pub check_interval: ReadableDuration,
+}
+
+impl Default for MemTableConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_size: ReadableSize::mb(64),
+            max_rows: 1_000_000,
+            max_age: ReadableDuration::minutes(5),
+            check_interval: ReadableDuration::secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// Max segments read concurrently when scanned in time order, following
+    /// segments are read ahead while the current one is returned.
+    ///
+    /// Segments are always read all together when scanned in primary key
+    /// order, since rows of them are merged.
+    pub max_concurrent_segments: usize,
+    /// Max batches buffered for each segment read concurrently.
+    pub buffer_batches: usize,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_segments: 4,
+            buffer_batches: 2,
+        }
+    }
+}
+
+/// Writes are slowed down or stopped when background jobs can't catch up.
+///
+/// Manifest delta files are checked against `soft_merge_threshold` and
+/// `hard_merge_threshold` in [`ManifestConfig`].
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteStallConfig {
+    pub enable: bool,
+    /// Writes are delayed when ssts of any segment exceed this.
+    pub slowdown_ssts_per_segment: usize,
+    /// Writes are stopped when ssts of any segment exceed this.
+    pub stop_ssts_per_segment: usize,
+    /// Writes are delayed when memory used by compaction exceeds this ratio
+    /// of its limit.
+    pub slowdown_compaction_memory_ratio: f64,
+    /// How long each write is delayed when slowed down, it's also the
+    /// interval to check whether stopped writes can be resumed.
+    pub slowdown_delay: ReadableDuration,
+    /// Stopped write fails after waiting this long.
+    pub max_stall_duration: ReadableDuration,
+}
+
+impl Default for WriteStallConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            slowdown_ssts_per_segment: 100,
+            stop_ssts_per_segment: 300,
+            slowdown_compaction_memory_ratio: 0.9,
+            slowdown_delay: ReadableDuration::millis(50),
+            max_stall_duration: ReadableDuration::secs(30),
         }
     }
 }
@@ -161,9 +282,28 @@ pub struct StorageConfig {
     pub manifest: ManifestConfig,
     pub scheduler: SchedulerConfig,
     pub update_mode: UpdateMode,
+    /// Name of the timestamp column, rows of one write will be partitioned
+    /// into segments by it when set.
+    pub timestamp_column: Option<String>,
+    /// Append timestamp column to the sort key after primary keys, so rows
+    /// with the same primary keys but different timestamps are kept
+    /// separately, instead of being merged.
+    pub sort_by_timestamp: bool,
+    /// Names of merge operators of value columns, keyed by column name.
+    /// Builtin ones are `last_value`, `bytes_merge`, `sum`, `min`, `max`,
+    /// `counter` and `hll`, see [`crate::operator::AggregateFunc`], others
+    /// should be registered by [`crate::operator::register_merge_operator`]
+    /// before open.
+    ///
+    /// Columns not set use the default operator of `update_mode`.
+    pub merge_operators: HashMap<String, String>,
+    pub wal: WalConfig,
+    pub memtable: MemTableConfig,
+    pub write_stall: WriteStallConfig,
+    pub scan: ScanConfig,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub enum UpdateMode {
     #[default]
@@ -0,0 +1,310 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Import external parquet files as ssts.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use arrow::{
+    array::{Array, AsArray},
+    compute::{max, min},
+    datatypes::{DataType, Int64Type, Schema, UInt64Type},
+    row::{OwnedRow, RowConverter, SortField},
+};
+use common::StoreContext;
+use datafusion::{error::DataFusionError, physical_plan::stream::RecordBatchStreamAdapter};
+use futures::StreamExt;
+use object_store::path::Path;
+use parquet::arrow::ProjectionMask;
+use tracing::{debug, info};
+
+use crate::{
+    ensure,
+    sst::{FileMeta, SstFile},
+    storage::{CloudObjectStorage, IngestRequest},
+    types::{StorageSchema, TimeRange, Timestamp, SEQ_COLUMN_NAME},
+    Error, Result,
+};
+
+impl CloudObjectStorage {
+    /// Import parquet files as ssts, rows of all files are visible atomically
+    /// once this returns.
+    ///
+    /// Columns of files should be a subset of the storage schema, including
+    /// primary keys. Files already sorted by primary keys and sequence, with
+    /// builtin columns and within one segment, are copied as ssts directly,
+    /// others are sorted and rewritten with a new sequence.
+    pub async fn ingest_external_files(&self, req: IngestRequest) -> Result<()> {
+        self.write_controller
+            .admit(std::slice::from_ref(&req.time_range))
+            .await?;
+        let schema = self.storage_schema();
+        let sequence = Self::allocate_sequence();
+        let mut to_adds = Vec::new();
+        for file in &req.files {
+            let path = Path::from(file.as_str());
+            let file_schema = self.open_parquet_file(&path).await?.schema().clone();
+            Self::check_external_schema(&schema, &file_schema)
+                .with_context(|| format!("check schema of external file, path:{path}"))?;
+            if let Some(sst) = self
+                .try_copy_external_file(&schema, &path, sequence, &req.time_range)
+                .await?
+            {
+                debug!(path = %path, sst = ?sst, "Copy external file");
+                to_adds.push(sst);
+                continue;
+            }
+
+            let ssts = self
+                .rewrite_external_file(&schema, &path, sequence, &req.time_range)
+                .await?;
+            debug!(path = %path, ssts = ?ssts, "Rewrite external file");
+            to_adds.extend(ssts);
+        }
+
+        let num_ssts = to_adds.len();
+        self.commit_ssts(to_adds).await?;
+        info!(
+            sequence,
+            num_files = req.files.len(),
+            num_ssts,
+            "Ingest external files finished"
+        );
+
+        Ok(())
+    }
+
+    fn check_external_schema(schema: &StorageSchema, file_schema: &Schema) -> Result<()> {
+        for field in file_schema.fields() {
+            let (_, expected) = schema
+                .arrow_schema
+                .column_with_name(field.name())
+                .ok_or_else(|| {
+                    Error::InvalidSchema(format!("unknown column, name:{}", field.name()))
+                })?;
+            ensure!(
+                field.data_type() == expected.data_type(),
+                Error::InvalidSchema(format!(
+                    "column type mismatch, name:{}, expected:{}, given:{}",
+                    field.name(),
+                    expected.data_type(),
+                    field.data_type()
+                ))
+            );
+        }
+        for field in &schema.arrow_schema.fields()[..schema.num_primary_keys] {
+            ensure!(
+                file_schema.column_with_name(field.name()).is_some(),
+                Error::InvalidSchema(format!("primary key is missing, name:{}", field.name()))
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Copy file as a sst if it can be used directly, `None` means it needs
+    /// to be rewritten.
+    ///
+    /// Only primary keys, builtin and timestamp columns are read to check.
+    async fn try_copy_external_file(
+        &self,
+        schema: &StorageSchema,
+        path: &Path,
+        sequence: u64,
+        time_range: &TimeRange,
+    ) -> Result<Option<SstFile>> {
+        let builder = self.open_parquet_file(path).await?;
+        let file_schema = builder.schema().clone();
+        let sort_key_idxes = schema.sort_key_idxes();
+        let mut required = sort_key_idxes
+            .iter()
+            .map(|i| schema.arrow_schema.fields()[*i].clone())
+            .collect::<Vec<_>>();
+        required.push(schema.arrow_schema.field(schema.seq_idx).clone().into());
+        required.push(
+            schema
+                .arrow_schema
+                .field(schema.reserved_idx)
+                .clone()
+                .into(),
+        );
+        let mut indices = Vec::with_capacity(required.len() + 1);
+        for field in &required {
+            let Some((idx, _)) = file_schema.column_with_name(field.name()) else {
+                return Ok(None);
+            };
+            indices.push(idx);
+        }
+        let timestamp_column = schema.timestamp_column();
+        if let Some(name) = timestamp_column {
+            let (idx, _) = file_schema
+                .column_with_name(name)
+                .with_context(|| format!("timestamp column is missing, name:{name}"))?;
+            indices.push(idx);
+        }
+        let num_rows = builder.metadata().file_metadata().num_rows() as u32;
+        let projection = ProjectionMask::roots(builder.parquet_schema(), indices);
+        let mut stream = builder
+            .with_projection(projection)
+            .build()
+            .with_context(|| format!("read external file, path:{path}"))?;
+
+        // Sort key and sequence, rows should be sorted by them.
+        let sort_fields = required[..=sort_key_idxes.len()]
+            .iter()
+            .map(|f| SortField::new(f.data_type().clone()))
+            .collect::<Vec<_>>();
+        let converter = RowConverter::new(sort_fields).context("create row converter")?;
+        let mut last_row: Option<OwnedRow> = None;
+        let mut max_sequence = 0;
+        let mut data_range: Option<TimeRange> = None;
+        while let Some(batch) = stream.next().await {
+            let batch = batch.with_context(|| format!("read external file, path:{path}"))?;
+            let sort_columns = required[..=sort_key_idxes.len()]
+                .iter()
+                .map(|f| {
+                    let column = batch
+                        .column_by_name(f.name())
+                        .with_context(|| format!("column is missing, name:{}", f.name()))?;
+                    Ok(column.clone())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let rows = converter
+                .convert_columns(&sort_columns)
+                .context("convert sort columns")?;
+            let is_sorted = last_row
+                .iter()
+                .map(|row| row.row())
+                .chain(rows.iter())
+                .is_sorted();
+            if !is_sorted {
+                return Ok(None);
+            }
+            if rows.num_rows() > 0 {
+                last_row = Some(rows.row(rows.num_rows() - 1).owned());
+            }
+
+            let sequences = batch
+                .column_by_name(SEQ_COLUMN_NAME)
+                .context("sequence column is missing")?
+                .as_primitive::<UInt64Type>();
+            if sequences.null_count() > 0 {
+                return Ok(None);
+            }
+            max_sequence = max(sequences).unwrap_or_default().max(max_sequence);
+            if let Some(name) = timestamp_column {
+                let timestamps = batch
+                    .column_by_name(name)
+                    .with_context(|| format!("timestamp column is missing, name:{name}"))?;
+                let timestamps = arrow::compute::cast(timestamps, &DataType::Int64)
+                    .context("cast timestamp column")?;
+                let timestamps = timestamps.as_primitive::<Int64Type>();
+                ensure!(
+                    timestamps.null_count() == 0,
+                    Error::InvalidRequest("timestamp column should not contain null".to_string())
+                );
+                if let Some((start, end)) = min(timestamps).zip(max(timestamps)) {
+                    let batch_range = TimeRange::new(start.into(), (end + 1).into());
+                    match data_range.as_mut() {
+                        Some(data_range) => data_range.merge(&batch_range),
+                        None => data_range = Some(batch_range),
+                    }
+                }
+            }
+        }
+
+        let time_range = data_range.unwrap_or_else(|| time_range.clone());
+        // Rows in file must be older than later writes, and belong to one
+        // segment.
+        if max_sequence > sequence
+            || self.segment_of(&time_range)
+                != Timestamp(time_range.end.0 - 1).truncate_by(self.segment_duration)
+        {
+            return Ok(None);
+        }
+
+        let file_id = SstFile::allocate_id();
+        let sst_path = Path::from(self.sst_path_gen.generate(file_id));
+        self.store
+            .copy(path, &sst_path)
+            .await
+            .with_store_context(|| format!("copy external file, from:{path}, to:{sst_path}"))?;
+        let size = self
+            .store
+            .head(&sst_path)
+            .await
+            .with_store_context(|| format!("get object meta, path:{sst_path}"))?
+            .size;
+        let file_meta = FileMeta {
+            max_sequence,
+            num_rows,
+            size: size as u32,
+            time_range,
+            // Stats of external files are unknown, so they are never pruned.
+            primary_key_stats: None,
+            deduplicated: false,
+            merged: false,
+        };
+
+        Ok(Some(SstFile::new(file_id, file_meta)))
+    }
+
+    /// Sort rows of file by primary keys, and write them to new ssts with
+    /// builtin columns filled.
+    async fn rewrite_external_file(
+        &self,
+        schema: &Arc<StorageSchema>,
+        path: &Path,
+        sequence: u64,
+        time_range: &TimeRange,
+    ) -> Result<Vec<SstFile>> {
+        let builder = self.open_parquet_file(path).await?;
+        // Builtin columns in file are ignored, since rows will be assigned a
+        // new sequence.
+        let indices = builder
+            .schema()
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !StorageSchema::is_builtin_field(f))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let projection = ProjectionMask::roots(builder.parquet_schema(), indices);
+        let stream = builder
+            .with_projection(projection)
+            .build()
+            .with_context(|| format!("read external file, path:{path}"))?;
+        let checked_stream = {
+            let schema = schema.clone();
+            let segment_duration = self.segment_duration;
+            let time_range = time_range.clone();
+            stream.map(move |batch| {
+                let batch = batch.map_err(DataFusionError::ParquetError)?;
+                Self::check_batch(&schema, segment_duration, batch, &time_range, true)
+                    .map_err(|e| DataFusionError::External(Box::new(e)))
+            })
+        };
+        let checked_stream = Box::pin(RecordBatchStreamAdapter::new(
+            schema.user_schema(),
+            checked_stream,
+        ));
+
+        self.write_unsorted_ssts(schema, checked_stream, sequence, time_range)
+            .await
+    }
+}
@@ -18,17 +18,24 @@
 //! Storage Engine for metrics.
 
 #![feature(duration_constructors)]
+pub mod aggregate;
 mod compaction;
 pub mod config;
+mod ingest;
 mod macros;
 pub mod manifest;
+mod memtable;
 pub mod operator;
+pub mod provider;
 mod read;
 pub mod sst;
 pub mod storage;
 #[cfg(test)]
 mod test_util;
 pub mod types;
+pub mod wal;
+mod write;
+mod write_controller;
 
 // Re-export error types.
 pub type AnyhowError = common::AnyhowError;
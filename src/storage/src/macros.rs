@@ -15,20 +15,6 @@
 // specific language governing permissions and limitations
 // under the License.
 
-#[macro_export]
-macro_rules! compare_primitive_columns {
-    ($lhs_col:expr, $rhs_col:expr, $lhs_idx:expr, $rhs_idx:expr, $($type:ty),+) => {
-        $(
-            if let Some(lhs_col) = $lhs_col.as_primitive_opt::<$type>() {
-                let rhs_col = $rhs_col.as_primitive::<$type>();
-                if !lhs_col.value($lhs_idx).eq(&rhs_col.value($rhs_idx)) {
-                    return false;
-                }
-            }
-        )+
-    };
-}
-
 /// Util for working with anyhow + thiserror
 /// Works like anyhow's [ensure](https://docs.rs/anyhow/latest/anyhow/macro.ensure.html)
 /// But return `Return<T, ErrorFromAnyhow>`
@@ -23,8 +23,8 @@ use bytes::{Buf, Bytes};
 
 use crate::{
     ensure,
-    sst::{FileId, FileMeta, SstFile},
-    types::TimeRange,
+    sst::{FileId, FileMeta, PrimaryKeyStats, SstFile},
+    types::{TimeRange, Timestamp},
     Error, Result,
 };
 
@@ -32,6 +32,8 @@ use crate::{
 pub struct ManifestUpdate {
     pub to_adds: Vec<SstFile>,
     pub to_deletes: Vec<FileId>,
+    /// Keys of writes whose data is added by this update.
+    pub write_keys: Vec<WriteKey>,
 }
 
 impl ManifestUpdate {
@@ -39,8 +41,14 @@ impl ManifestUpdate {
         Self {
             to_adds,
             to_deletes,
+            write_keys: Vec::new(),
         }
     }
+
+    pub fn with_write_keys(mut self, write_keys: Vec<WriteKey>) -> Self {
+        self.write_keys = write_keys;
+        self
+    }
 }
 
 impl TryFrom<pb_types::ManifestUpdate> for ManifestUpdate {
@@ -52,10 +60,12 @@ impl TryFrom<pb_types::ManifestUpdate> for ManifestUpdate {
             .into_iter()
             .map(SstFile::try_from)
             .collect::<Result<Vec<_>>>()?;
+        let write_keys = value.write_keys.into_iter().map(WriteKey::from).collect();
 
         Ok(Self {
             to_adds,
             to_deletes: value.to_deletes,
+            write_keys,
         })
     }
 }
@@ -67,10 +77,86 @@ impl From<ManifestUpdate> for pb_types::ManifestUpdate {
             .into_iter()
             .map(pb_types::SstFile::from)
             .collect();
+        let write_keys = value
+            .write_keys
+            .into_iter()
+            .map(pb_types::WriteKey::from)
+            .collect();
 
         pb_types::ManifestUpdate {
             to_adds,
             to_deletes: value.to_deletes,
+            write_keys,
+        }
+    }
+}
+
+/// Idempotency key of a write, along with the time it's accepted.
+///
+/// The layout when encoded in binary:
+/// ```plaintext
+/// +----------------+-------------+------------------+
+/// | timestamp(i64) | length(u32) | key(length bytes)|
+/// +----------------+-------------+------------------+
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteKey {
+    pub key: String,
+    pub timestamp: Timestamp,
+}
+
+impl WriteKey {
+    pub fn write_to<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        writer
+            .write_i64::<LittleEndian>(*self.timestamp)
+            .context("write shall not fail.")?;
+        writer
+            .write_u32::<LittleEndian>(self.key.len() as u32)
+            .context("write shall not fail.")?;
+        writer
+            .write_all(self.key.as_bytes())
+            .context("write shall not fail.")?;
+        Ok(())
+    }
+
+    pub fn try_new<R>(mut reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let timestamp = reader
+            .read_i64::<LittleEndian>()
+            .context("read write key timestamp")?;
+        let length = reader
+            .read_u32::<LittleEndian>()
+            .context("read write key length")?;
+        let mut buf = vec![0; length as usize];
+        reader.read_exact(&mut buf).context("read write key")?;
+        let key = String::from_utf8(buf).context("write key is not utf8")?;
+
+        Ok(Self {
+            key,
+            timestamp: Timestamp(timestamp),
+        })
+    }
+}
+
+impl From<pb_types::WriteKey> for WriteKey {
+    fn from(value: pb_types::WriteKey) -> Self {
+        Self {
+            key: value.key,
+            timestamp: Timestamp(value.timestamp),
+        }
+    }
+}
+
+impl From<WriteKey> for pb_types::WriteKey {
+    fn from(value: WriteKey) -> Self {
+        pb_types::WriteKey {
+            key: value.key,
+            timestamp: value.timestamp.0,
         }
     }
 }
@@ -83,10 +169,13 @@ impl From<ManifestUpdate> for pb_types::ManifestUpdate {
 /// ```
 /// - The Magic field (u32) is used to ensure the validity of the data source.
 /// - The Flags field (u8) is reserved for future extensibility, such as
-///   enabling compression or supporting additional features.
+///   enabling compression or supporting additional features. `FLAG_WRITE_KEYS`
+///   means write keys follow the records, they are encoded as `num(u32)` and
+///   then each [`WriteKey`].
 /// - The length field (u64) represents the total length of the subsequent
 ///   records and serves as a straightforward method for verifying their
-///   integrity. (length = record_length * record_count)
+///   integrity. Records are of fixed length before version 3, so length =
+///   record_length * record_count for them.
 #[derive(Debug, PartialEq, Eq)]
 pub struct SnapshotHeader {
     pub magic: u32,
@@ -96,6 +185,7 @@ pub struct SnapshotHeader {
 }
 
 impl SnapshotHeader {
+    pub const FLAG_WRITE_KEYS: u8 = 0b1;
     pub const LENGTH: usize = 4 /*magic*/ + 1 /*version*/ + 1 /*flag*/ + 8 /*length*/;
     pub const MAGIC: u32 = 0xCAFE_1234;
 
@@ -117,7 +207,7 @@ impl SnapshotHeader {
             .context("read snapshot header magic")?;
         ensure!(
             magic == SnapshotHeader::MAGIC,
-            "invalid bytes to convert to header."
+            Error::Corruption(format!("invalid snapshot header magic, value:{magic}"))
         );
         let version = reader.read_u8().context("read snapshot header version")?;
         let flag = reader.read_u8().context("read snapshot header flag")?;
@@ -154,21 +244,39 @@ impl SnapshotHeader {
 
 /// The layout for manifest Record:
 /// ```plaintext
-/// +---------+-------------------+------------+-----------------+
-/// | id(u64) | time_range(i64*2) | size(u32)  |  num_rows(u32)  |
-/// +---------+-------------------+------------+-----------------+
+/// +---------+-------------------+-----------+---------------+-------------------+-------------------+----------+
+/// | id(u64) | time_range(i64*2) | size(u32) | num_rows(u32) | max_sequence(u64) | primary_key_stats | flag(u8) |
+/// +---------+-------------------+-----------+---------------+-------------------+-------------------+----------+
 /// ```
+/// `max_sequence` is added in version 2, for version 1 it's the same with id.
+///
+/// `primary_key_stats` is added in version 3, it's encoded as `num(u32)`, and
+/// then `num` min values followed by `num` max values, each value is encoded
+/// as `length(u32)` and then bytes. `num` is 0 when stats are unknown.
+///
+/// `flag` is added in version 4, `FLAG_DEDUPLICATED` means rows of the sst
+/// are deduplicated, see [`FileMeta::deduplicated`], and `FLAG_MERGED` means
+/// rows are merged, see [`FileMeta::merged`].
 #[derive(Debug, PartialEq, Eq)]
 pub struct SnapshotRecord {
     id: u64,
     time_range: TimeRange,
     size: u32,
     num_rows: u32,
+    max_sequence: u64,
+    primary_key_stats: Option<PrimaryKeyStats>,
+    deduplicated: bool,
+    merged: bool,
 }
 
 impl SnapshotRecord {
-    const LENGTH: usize = 8 /*id*/+ 16 /*time range*/ + 4 /*size*/ + 4 /*num rows*/;
-    pub const VERSION: u8 = 1;
+    const FLAG_DEDUPLICATED: u8 = 0b1;
+    const FLAG_MERGED: u8 = 0b10;
+    const LENGTH: usize = Self::LENGTH_V3 + 1 /*flag*/;
+    const LENGTH_V1: usize = 8 /*id*/+ 16 /*time range*/ + 4 /*size*/ + 4 /*num rows*/;
+    const LENGTH_V2: usize = Self::LENGTH_V1 + 8 /*max sequence*/;
+    const LENGTH_V3: usize = Self::LENGTH_V2 + 4 /*num of primary key stats*/;
+    pub const VERSION: u8 = 4;
 
     pub fn write_to<W>(&self, mut writer: W) -> Result<()>
     where
@@ -189,12 +297,53 @@ impl SnapshotRecord {
         writer
             .write_u32::<LittleEndian>(self.num_rows)
             .context("write shall not fail.")?;
+        writer
+            .write_u64::<LittleEndian>(self.max_sequence)
+            .context("write shall not fail.")?;
+
+        let (mins, maxs) = self.encoded_stats();
+        writer
+            .write_u32::<LittleEndian>(mins.len() as u32)
+            .context("write shall not fail.")?;
+        for value in mins.iter().chain(maxs.iter()) {
+            writer
+                .write_u32::<LittleEndian>(value.len() as u32)
+                .context("write shall not fail.")?;
+            writer.write_all(value).context("write shall not fail.")?;
+        }
+
+        let mut flag = 0;
+        if self.deduplicated {
+            flag |= Self::FLAG_DEDUPLICATED;
+        }
+        if self.merged {
+            flag |= Self::FLAG_MERGED;
+        }
+        writer.write_u8(flag).context("write shall not fail.")?;
         Ok(())
     }
 
     pub fn id(&self) -> u64 {
         self.id
     }
+
+    /// Length of the record encoded in the latest version.
+    fn encoded_len(&self) -> usize {
+        let (mins, maxs) = self.encoded_stats();
+        Self::LENGTH
+            + mins
+                .iter()
+                .chain(maxs.iter())
+                .map(|v| 4 /*length*/ + v.len())
+                .sum::<usize>()
+    }
+
+    fn encoded_stats(&self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        self.primary_key_stats
+            .as_ref()
+            .map(|stats| stats.encode())
+            .unwrap_or_default()
+    }
 }
 
 impl From<SstFile> for SnapshotRecord {
@@ -204,12 +353,29 @@ impl From<SstFile> for SnapshotRecord {
             time_range: value.meta().time_range.clone(),
             size: value.meta().size,
             num_rows: value.meta().num_rows,
+            max_sequence: value.meta().max_sequence,
+            primary_key_stats: value.meta().primary_key_stats.clone(),
+            deduplicated: value.meta().deduplicated,
+            merged: value.meta().merged,
         }
     }
 }
 
 impl SnapshotRecord {
-    fn try_new<R>(mut reader: R) -> Result<Self>
+    /// Length of fixed part of records.
+    fn length(version: u8) -> Result<usize> {
+        match version {
+            1 => Ok(Self::LENGTH_V1),
+            2 => Ok(Self::LENGTH_V2),
+            3 => Ok(Self::LENGTH_V3),
+            Self::VERSION => Ok(Self::LENGTH),
+            _ => Err(Error::Corruption(format!(
+                "unknown snapshot version:{version}"
+            ))),
+        }
+    }
+
+    fn try_new<R>(mut reader: R, version: u8) -> Result<Self>
     where
         R: Read,
     {
@@ -228,30 +394,81 @@ impl SnapshotRecord {
         let num_rows = reader
             .read_u32::<LittleEndian>()
             .context("read record num_rows")?;
+        let max_sequence = if version == 1 {
+            id
+        } else {
+            reader
+                .read_u64::<LittleEndian>()
+                .context("read record max_sequence")?
+        };
+        let primary_key_stats = if version < 3 {
+            None
+        } else {
+            Self::read_stats(&mut reader)?
+        };
+        let flag = if version < 4 {
+            0
+        } else {
+            reader.read_u8().context("read record flag")?
+        };
         Ok(SnapshotRecord {
             id,
             time_range: (start..end).into(),
             size,
             num_rows,
+            max_sequence,
+            primary_key_stats,
+            deduplicated: flag & Self::FLAG_DEDUPLICATED != 0,
+            merged: flag & Self::FLAG_MERGED != 0,
         })
     }
+
+    fn read_stats<R>(mut reader: R) -> Result<Option<PrimaryKeyStats>>
+    where
+        R: Read,
+    {
+        let num = reader
+            .read_u32::<LittleEndian>()
+            .context("read record primary key stats num")? as usize;
+        if num == 0 {
+            return Ok(None);
+        }
+
+        let mut values = Vec::with_capacity(num * 2);
+        for _ in 0..num * 2 {
+            let length = reader
+                .read_u32::<LittleEndian>()
+                .context("read record primary key stats length")?;
+            let mut buf = vec![0; length as usize];
+            reader
+                .read_exact(&mut buf)
+                .context("read record primary key stats")?;
+            values.push(buf);
+        }
+        let (mins, maxs) = values.split_at(num);
+        Ok(Some(PrimaryKeyStats::try_new(mins, maxs)?))
+    }
 }
 
 impl From<SnapshotRecord> for SstFile {
     fn from(record: SnapshotRecord) -> Self {
         let file_meta = FileMeta {
-            max_sequence: record.id(),
+            max_sequence: record.max_sequence,
             num_rows: record.num_rows,
             size: record.size,
             time_range: record.time_range.clone(),
+            primary_key_stats: record.primary_key_stats,
+            deduplicated: record.deduplicated,
+            merged: record.merged,
         };
-        SstFile::new(record.id(), file_meta)
+        SstFile::new(record.id, file_meta)
     }
 }
 
 pub struct Snapshot {
     header: SnapshotHeader,
     pub records: Vec<SnapshotRecord>,
+    pub write_keys: Vec<WriteKey>,
 }
 
 impl Default for Snapshot {
@@ -261,6 +478,7 @@ impl Default for Snapshot {
         Self {
             header,
             records: Vec::new(),
+            write_keys: Vec::new(),
         }
     }
 }
@@ -274,21 +492,56 @@ impl TryFrom<Bytes> for Snapshot {
         }
         let bytes_len = bytes.len();
         let mut cursor = Cursor::new(bytes);
-        let header = SnapshotHeader::try_new(&mut cursor)?;
+        let mut header = SnapshotHeader::try_new(&mut cursor)?;
+        let record_length = SnapshotRecord::length(header.version)?;
         let record_total_length = header.length as usize;
+        let has_write_keys = header.flag & SnapshotHeader::FLAG_WRITE_KEYS != 0;
+        let records_end = record_total_length + SnapshotHeader::LENGTH;
+        // Records are of variable length since version 3.
+        let fixed_length = header.version < 3;
         ensure!(
-            record_total_length > 0
-                && record_total_length % SnapshotRecord::LENGTH == 0
-                && record_total_length + SnapshotHeader::LENGTH == bytes_len,
-            "create snapshot from bytes failed, header:{header:?}, bytes_length: {bytes_len}",
+            (record_total_length > 0 || has_write_keys)
+                && (!fixed_length || record_total_length % record_length == 0)
+                && (records_end == bytes_len || has_write_keys && records_end < bytes_len),
+            Error::Corruption(format!(
+                "create snapshot from bytes failed, header:{header:?}, bytes_length: {bytes_len}"
+            ))
         );
-        let mut records = Vec::with_capacity(record_total_length / SnapshotRecord::LENGTH);
-        while cursor.has_remaining() {
-            let record = SnapshotRecord::try_new(&mut cursor)?;
+        let mut records = Vec::with_capacity(record_total_length / record_length);
+        while (cursor.position() as usize) < records_end {
+            let record = SnapshotRecord::try_new(&mut cursor, header.version)?;
             records.push(record);
         }
+        ensure!(
+            cursor.position() as usize == records_end,
+            Error::Corruption(format!(
+                "records exceed length in header, header:{header:?}, bytes_length: {bytes_len}"
+            ))
+        );
+        let mut write_keys = Vec::new();
+        if has_write_keys {
+            let num = cursor
+                .read_u32::<LittleEndian>()
+                .context("read write keys num")?;
+            for _ in 0..num {
+                write_keys.push(WriteKey::try_new(&mut cursor)?);
+            }
+            ensure!(
+                !cursor.has_remaining(),
+                Error::Corruption(format!(
+                    "unexpected bytes after write keys, header:{header:?}, bytes_length: {bytes_len}"
+                ))
+            );
+        }
+        // Records are always persisted using the latest version.
+        header.version = SnapshotRecord::VERSION;
+        header.length = Self::records_length(&records);
 
-        Ok(Self { header, records })
+        Ok(Self {
+            header,
+            records,
+            write_keys,
+        })
     }
 }
 
@@ -306,7 +559,7 @@ impl Snapshot {
     pub fn add_records(&mut self, ssts: Vec<SstFile>) {
         self.records
             .extend(ssts.into_iter().map(SnapshotRecord::from));
-        self.header.length = (self.records.len() * SnapshotRecord::LENGTH) as u64;
+        self.header.length = Self::records_length(&self.records);
     }
 
     pub fn delete_records(&mut self, to_deletes: Vec<FileId>) {
@@ -322,17 +575,40 @@ impl Snapshot {
 
         self.records
             .retain(|record| !to_deletes.contains(&record.id));
-        self.header.length = (self.records.len() * SnapshotRecord::LENGTH) as u64;
+        self.header.length = Self::records_length(&self.records);
+    }
+
+    fn records_length(records: &[SnapshotRecord]) -> u64 {
+        records.iter().map(|r| r.encoded_len() as u64).sum()
+    }
+
+    /// Add keys of merged deltas, and drop those accepted before `expire_at`.
+    pub fn merge_write_keys(&mut self, write_keys: Vec<WriteKey>, expire_at: Timestamp) {
+        self.write_keys.extend(write_keys);
+        self.write_keys.retain(|k| k.timestamp >= expire_at);
     }
 
-    pub fn into_bytes(self) -> Result<Bytes> {
+    pub fn into_bytes(mut self) -> Result<Bytes> {
         let buf = Vec::with_capacity(self.header.length as usize + SnapshotHeader::LENGTH);
         let mut cursor = Cursor::new(buf);
 
+        if self.write_keys.is_empty() {
+            self.header.flag &= !SnapshotHeader::FLAG_WRITE_KEYS;
+        } else {
+            self.header.flag |= SnapshotHeader::FLAG_WRITE_KEYS;
+        }
         self.header.write_to(&mut cursor)?;
         for record in self.records {
             record.write_to(&mut cursor)?;
         }
+        if !self.write_keys.is_empty() {
+            cursor
+                .write_u32::<LittleEndian>(self.write_keys.len() as u32)
+                .context("write shall not fail.")?;
+            for write_key in &self.write_keys {
+                write_key.write_to(&mut cursor)?;
+            }
+        }
         Ok(Bytes::from(cursor.into_inner()))
     }
 }
@@ -340,6 +616,8 @@ impl Snapshot {
 #[cfg(test)]
 mod tests {
 
+    use datafusion::common::ScalarValue;
+
     use super::*;
 
     #[test]
@@ -355,7 +633,7 @@ mod tests {
         assert_eq!(
             SnapshotHeader {
                 magic: SnapshotHeader::MAGIC,
-                version: 1,
+                version: SnapshotRecord::VERSION,
                 flag: 0,
                 length: 0
             },
@@ -365,31 +643,170 @@ mod tests {
 
     #[test]
     fn test_snapshot_record() {
-        let sstfile = SstFile::new(
+        let primary_key_stats = PrimaryKeyStats::new(
+            vec![
+                ScalarValue::Int64(Some(1)),
+                ScalarValue::Utf8(Some("a".into())),
+            ],
+            vec![
+                ScalarValue::Int64(Some(9)),
+                ScalarValue::Utf8(Some("z".into())),
+            ],
+        );
+        for primary_key_stats in [None, primary_key_stats] {
+            let sstfile = SstFile::new(
+                99,
+                FileMeta {
+                    max_sequence: 101,
+                    num_rows: 100,
+                    size: 938,
+                    time_range: (100..200).into(),
+                    primary_key_stats: primary_key_stats.clone(),
+                    deduplicated: false,
+                    merged: false,
+                },
+            );
+            let record: SnapshotRecord = sstfile.into();
+            let mut vec: Vec<u8> = vec![0u8; record.encoded_len()];
+            let mut writer = vec.as_mut_slice();
+            record.write_to(&mut writer).unwrap();
+
+            assert!(writer.is_empty());
+            let cursor = Cursor::new(vec);
+            let record = SnapshotRecord::try_new(cursor, SnapshotRecord::VERSION).unwrap();
+            assert_eq!(
+                SnapshotRecord {
+                    id: 99,
+                    time_range: (100..200).into(),
+                    size: 938,
+                    num_rows: 100,
+                    max_sequence: 101,
+                    primary_key_stats,
+                    deduplicated: false,
+                    merged: false,
+                },
+                record
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_variable_length_records() {
+        let sst = |id, primary_key_stats| {
+            SstFile::new(
+                id,
+                FileMeta {
+                    max_sequence: id,
+                    num_rows: 100,
+                    size: 938,
+                    time_range: (100..200).into(),
+                    primary_key_stats,
+                    deduplicated: id == 3,
+                    merged: id != 1,
+                },
+            )
+        };
+        let stats = PrimaryKeyStats::new(
+            vec![ScalarValue::Binary(Some(b"abc".to_vec()))],
+            vec![ScalarValue::Binary(Some(b"abcdef".to_vec()))],
+        );
+        let mut snapshot = Snapshot::default();
+        snapshot.add_records(vec![sst(1, stats.clone()), sst(2, None), sst(3, stats)]);
+        snapshot.delete_records(vec![2]);
+
+        let snapshot = Snapshot::try_from(snapshot.into_bytes().unwrap()).unwrap();
+        let ssts = snapshot.into_ssts();
+        assert_eq!(vec![1, 3], ssts.iter().map(|s| s.id()).collect::<Vec<_>>());
+        assert_eq!(
+            vec![ScalarValue::Binary(Some(b"abcdef".to_vec()))],
+            ssts[1].meta().primary_key_stats.as_ref().unwrap().max
+        );
+        assert!(!ssts[0].meta().deduplicated);
+        assert!(ssts[1].meta().deduplicated);
+        assert!(!ssts[0].meta().merged);
+        assert!(ssts[1].meta().merged);
+    }
+
+    #[test]
+    fn test_snapshot_v1_compatible() {
+        let mut cursor = Cursor::new(Vec::new());
+        SnapshotHeader {
+            magic: SnapshotHeader::MAGIC,
+            version: 1,
+            flag: 0,
+            length: SnapshotRecord::LENGTH_V1 as u64,
+        }
+        .write_to(&mut cursor)
+        .unwrap();
+        let record = SnapshotRecord {
+            id: 99,
+            time_range: (100..200).into(),
+            size: 938,
+            num_rows: 100,
+            max_sequence: 101,
+            primary_key_stats: None,
+            deduplicated: false,
+            merged: false,
+        };
+        record.write_to(&mut cursor).unwrap();
+        let mut bytes = cursor.into_inner();
+        // Drop max_sequence, primary key stats and flag, which don't exist in
+        // version 1.
+        bytes.truncate(bytes.len() - 8 - 4 - 1);
+
+        let snapshot = Snapshot::try_from(Bytes::from(bytes)).unwrap();
+        let ssts = snapshot.into_ssts();
+        assert_eq!(1, ssts.len());
+        assert_eq!(99, ssts[0].meta().max_sequence);
+
+        // Upgraded to latest version when persisted again.
+        let mut snapshot = Snapshot::default();
+        snapshot.add_records(ssts);
+        let snapshot = Snapshot::try_from(snapshot.into_bytes().unwrap()).unwrap();
+        assert_eq!(SnapshotRecord::VERSION, snapshot.header.version);
+    }
+
+    #[test]
+    fn test_snapshot_write_keys() {
+        let write_key = |key: &str, timestamp: i64| WriteKey {
+            key: key.to_string(),
+            timestamp: Timestamp(timestamp),
+        };
+        let mut snapshot = Snapshot::default();
+        snapshot.merge_write_keys(vec![write_key("a", 100), write_key("b", 200)], Timestamp(0));
+        // Snapshot without records is allowed when there are write keys.
+        let mut snapshot = Snapshot::try_from(snapshot.into_bytes().unwrap()).unwrap();
+        assert!(snapshot.records.is_empty());
+        assert_eq!(
+            vec![write_key("a", 100), write_key("b", 200)],
+            snapshot.write_keys
+        );
+
+        snapshot.add_records(vec![SstFile::new(
             99,
             FileMeta {
-                max_sequence: 99,
+                max_sequence: 101,
                 num_rows: 100,
                 size: 938,
                 time_range: (100..200).into(),
+                primary_key_stats: None,
+                deduplicated: false,
+                merged: false,
             },
-        );
-        let record: SnapshotRecord = sstfile.into();
-        let mut vec: Vec<u8> = vec![0u8; SnapshotRecord::LENGTH];
-        let mut writer = vec.as_mut_slice();
-        record.write_to(&mut writer).unwrap();
-
-        assert!(writer.is_empty());
-        let cursor = Cursor::new(vec);
-        let record = SnapshotRecord::try_new(cursor).unwrap();
+        )]);
+        snapshot.merge_write_keys(vec![write_key("c", 300)], Timestamp(150));
+        let snapshot = Snapshot::try_from(snapshot.into_bytes().unwrap()).unwrap();
         assert_eq!(
-            SnapshotRecord {
-                id: 99,
-                time_range: (100..200).into(),
-                size: 938,
-                num_rows: 100
-            },
-            record
+            vec![write_key("b", 200), write_key("c", 300)],
+            snapshot.write_keys
         );
+        assert_eq!(1, snapshot.into_ssts().len());
+
+        // Flag is cleared once all keys expire.
+        let mut snapshot = Snapshot::default();
+        snapshot.merge_write_keys(vec![write_key("a", 100)], Timestamp(200));
+        let bytes = snapshot.into_bytes().unwrap();
+        let header = SnapshotHeader::try_new(Cursor::new(bytes)).unwrap();
+        assert_eq!(0, header.flag);
     }
 }
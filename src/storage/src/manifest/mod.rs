@@ -16,10 +16,12 @@
 // under the License.
 
 mod encoding;
+pub mod schema;
 use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
     sync::{
         atomic::{AtomicU64, AtomicUsize, Ordering},
-        Arc, LazyLock,
+        Arc, LazyLock, Mutex,
     },
     time::{Duration, SystemTime},
 };
@@ -27,7 +29,8 @@ use std::{
 use anyhow::Context;
 use async_scoped::TokioScope;
 use bytes::Bytes;
-pub use encoding::{ManifestUpdate, Snapshot};
+use common::{now, StoreContext};
+pub use encoding::{ManifestUpdate, Snapshot, WriteKey};
 use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
 use object_store::{path::Path, PutPayload};
@@ -41,8 +44,8 @@ use tracing::{debug, error, info, trace};
 use crate::{
     config::ManifestConfig,
     sst::{FileId, FileMeta, SstFile},
-    types::{ObjectStoreRef, RuntimeRef, TimeRange},
-    AnyhowError, Result,
+    types::{ObjectStoreRef, RuntimeRef, TimeRange, Timestamp},
+    Error, Result,
 };
 
 pub const PREFIX_PATH: &str = "manifest";
@@ -64,12 +67,72 @@ static NEXT_ID: LazyLock<AtomicU64> = LazyLock::new(|| {
 
 pub type ManifestRef = Arc<Manifest>;
 
+/// Ssts of the manifest at some point, it's immutable and a new one is
+/// created by each update.
+///
+/// Readers hold a version to pin ssts of it, ssts removed from the manifest
+/// are only deleted after all versions holding them are dropped, see
+/// [`SstFile::mark_obsolete`].
+#[derive(Debug, Default)]
+pub struct Version {
+    ssts: Vec<SstFile>,
+    /// Number of ssts in each segment.
+    num_ssts_by_segment: BTreeMap<Timestamp, usize>,
+    /// Min sequence each segment can be read as of, it's the max sequence of
+    /// merged ssts, since older states of merged rows are lost.
+    min_readable_sequences: BTreeMap<Timestamp, u64>,
+}
+
+pub type VersionRef = Arc<Version>;
+
+impl Version {
+    fn new(ssts: Vec<SstFile>, segment_duration: Duration) -> Self {
+        let mut num_ssts_by_segment = BTreeMap::new();
+        let mut min_readable_sequences = BTreeMap::new();
+        for sst in &ssts {
+            let segment = sst.meta().time_range.start.truncate_by(segment_duration);
+            *num_ssts_by_segment.entry(segment).or_default() += 1;
+            if sst.meta().merged {
+                let sequence = min_readable_sequences.entry(segment).or_default();
+                *sequence = sst.meta().max_sequence.max(*sequence);
+            }
+        }
+        Self {
+            ssts,
+            num_ssts_by_segment,
+            min_readable_sequences,
+        }
+    }
+
+    pub fn ssts(&self) -> &[SstFile] {
+        &self.ssts
+    }
+
+    /// Min sequence the `segment` can be read as of.
+    pub fn min_readable_sequence(&self, segment: Timestamp) -> u64 {
+        self.min_readable_sequences
+            .get(&segment)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn find_ssts(&self, time_range: &TimeRange) -> Vec<SstFile> {
+        self.ssts
+            .iter()
+            .filter(move |f| f.meta().time_range.overlaps(time_range))
+            .cloned()
+            .collect()
+    }
+}
+
 pub struct Manifest {
     delta_dir: Path,
+    segment_duration: Duration,
     store: ObjectStoreRef,
     merger: Arc<ManifestMerger>,
 
-    ssts: RwLock<Vec<SstFile>>,
+    version: RwLock<VersionRef>,
+    write_keys: Mutex<RecentWriteKeys>,
 }
 
 impl Manifest {
@@ -77,8 +140,10 @@ impl Manifest {
         root_dir: String,
         store: ObjectStoreRef,
         runtime: RuntimeRef,
+        segment_duration: Duration,
         merge_options: ManifestConfig,
     ) -> Result<Self> {
+        let idempotency_window = merge_options.idempotency_window.0;
         let snapshot_path = Path::from(format!("{root_dir}/{PREFIX_PATH}/{SNAPSHOT_FILENAME}"));
         let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
 
@@ -89,7 +154,9 @@ impl Manifest {
             merge_options,
         )
         .await?;
-        let snapshot = read_snapshot(&store, &snapshot_path).await?;
+        let mut snapshot = read_snapshot(&store, &snapshot_path).await?;
+        let mut write_keys = RecentWriteKeys::new(idempotency_window);
+        write_keys.extend(std::mem::take(&mut snapshot.write_keys));
         let ssts = snapshot.into_ssts();
         debug!(
             sst_len = ssts.len(),
@@ -106,9 +173,11 @@ impl Manifest {
 
         Ok(Self {
             delta_dir,
+            segment_duration,
             store,
             merger,
-            ssts: RwLock::new(ssts),
+            version: RwLock::new(Arc::new(Version::new(ssts, segment_duration))),
+            write_keys: Mutex::new(write_keys),
         })
     }
 
@@ -140,35 +209,66 @@ impl Manifest {
         self.store
             .put(&path, PutPayload::from_bytes(Bytes::from(buf)))
             .await
-            .with_context(|| format!("Failed to write delta manifest, path:{}", path))?;
+            .with_store_context(|| format!("Failed to write delta manifest, path:{}", path))?;
 
         // 2. Update cached payload
         {
-            let mut ssts = self.ssts.write().await;
+            let mut version = self.version.write().await;
+            let mut ssts = version.ssts.clone();
             for file in update.to_adds {
                 ssts.push(file);
             }
             // TODO: sort files in payload, so we can delete files more
             // efficiently.
             ssts.retain(|file| !update.to_deletes.contains(&file.id()));
+            *version = Arc::new(Version::new(ssts, self.segment_duration));
+        }
+        if !update.write_keys.is_empty() {
+            self.write_keys.lock().unwrap().extend(update.write_keys);
         }
 
         Ok(())
     }
 
+    /// Whether a write with `key` is added to manifest within the
+    /// idempotency window.
+    pub fn contains_write_key(&self, key: &str) -> bool {
+        self.write_keys.lock().unwrap().contains(key)
+    }
+
+    /// Returns the latest version, ssts of it are kept until it's dropped.
+    pub async fn current_version(&self) -> VersionRef {
+        self.version.read().await.clone()
+    }
+
     // TODO: avoid clone
     pub async fn all_ssts(&self) -> Vec<SstFile> {
-        let ssts = self.ssts.read().await;
-        ssts.clone()
+        self.current_version().await.ssts.clone()
     }
 
     pub async fn find_ssts(&self, time_range: &TimeRange) -> Vec<SstFile> {
-        let ssts = self.ssts.read().await;
+        self.current_version().await.find_ssts(time_range)
+    }
 
-        ssts.iter()
-            .filter(move |f| f.meta().time_range.overlaps(time_range))
-            .cloned()
-            .collect()
+    /// Returns the max number of ssts within segments overlapping with
+    /// `time_range`.
+    pub async fn max_ssts_per_segment(&self, time_range: &TimeRange) -> usize {
+        let start = time_range.start.truncate_by(self.segment_duration);
+        if start >= time_range.end {
+            return 0;
+        }
+        self.current_version()
+            .await
+            .num_ssts_by_segment
+            .range(start..time_range.end)
+            .map(|(_, num)| *num)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Number of delta files not merged into snapshot yet.
+    pub fn delta_num(&self) -> usize {
+        self.merger.deltas_num.load(Ordering::Relaxed)
     }
 
     fn allocate_id() -> u64 {
@@ -176,6 +276,58 @@ impl Manifest {
     }
 }
 
+/// Keys of writes added recently, keys out of the window are dropped.
+struct RecentWriteKeys {
+    window: Duration,
+    keys: HashMap<String, Timestamp>,
+    /// Keys in the order of insertion, which is roughly the order of
+    /// timestamp, so expired keys can be found quickly.
+    ordered_keys: VecDeque<(Timestamp, String)>,
+}
+
+impl RecentWriteKeys {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            keys: HashMap::new(),
+            ordered_keys: VecDeque::new(),
+        }
+    }
+
+    fn expire_at(&self) -> Timestamp {
+        Timestamp(now() - self.window.as_millis() as i64)
+    }
+
+    fn extend(&mut self, write_keys: Vec<WriteKey>) {
+        let expire_at = self.expire_at();
+        for WriteKey { key, timestamp } in write_keys {
+            if timestamp < expire_at {
+                continue;
+            }
+            let latest = self.keys.entry(key.clone()).or_insert(timestamp);
+            *latest = timestamp.max(*latest);
+            self.ordered_keys.push_back((timestamp, key));
+        }
+
+        while let Some((timestamp, _)) = self.ordered_keys.front() {
+            if *timestamp >= expire_at {
+                break;
+            }
+            let (timestamp, key) = self.ordered_keys.pop_front().unwrap();
+            // Key may be added again later.
+            if self.keys.get(&key) == Some(&timestamp) {
+                self.keys.remove(&key);
+            }
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.keys
+            .get(key)
+            .is_some_and(|timestamp| *timestamp >= self.expire_at())
+    }
+}
+
 enum MergeType {
     Hard,
     Soft,
@@ -250,10 +402,9 @@ impl ManifestMerger {
         let hard_limit = self.merge_options.hard_merge_threshold;
         if current_num > hard_limit {
             self.schedule_merge(MergeType::Hard);
-            return Err(AnyhowError::msg(format!(
+            return Err(Error::WriteStalled(format!(
                 "Manifest has too many delta files, value:{current_num}, hard_limit:{hard_limit}"
-            ))
-            .into());
+            )));
         } else if current_num > self.merge_options.soft_merge_threshold {
             self.schedule_merge(MergeType::Soft);
         }
@@ -291,12 +442,17 @@ impl ManifestMerger {
         // Since the deltas is unsorted, so we have to first add all new files, then
         // delete old files.
         let mut to_deletes = Vec::new();
+        let mut write_keys = Vec::new();
         for res in results {
             let manifest_update = res.context("Failed to join read delta files task")??;
             snapshot.add_records(manifest_update.to_adds);
             to_deletes.extend(manifest_update.to_deletes);
+            write_keys.extend(manifest_update.write_keys);
         }
         snapshot.delete_records(to_deletes);
+        let expire_at =
+            Timestamp(now() - self.merge_options.idempotency_window.0.as_millis() as i64);
+        snapshot.merge_write_keys(write_keys, expire_at);
         trace!(sst_ids = ?snapshot.records.iter().map(|r| r.id()).collect_vec(), "After snapshot merge deltas");
         let snapshot_bytes = snapshot.into_bytes()?;
         let put_payload = PutPayload::from_bytes(snapshot_bytes);
@@ -304,7 +460,9 @@ impl ManifestMerger {
         self.store
             .put(&self.snapshot_path, put_payload)
             .await
-            .with_context(|| format!("Failed to update manifest, path:{}", self.snapshot_path))?;
+            .with_store_context(|| {
+                format!("Failed to update manifest, path:{}", self.snapshot_path)
+            })?;
 
         // 2. Delete the merged manifest files
         let (_, results) = TokioScope::scope_and_block(|scope| {
@@ -334,22 +492,20 @@ impl ManifestMerger {
 }
 
 async fn read_snapshot(store: &ObjectStoreRef, path: &Path) -> Result<Snapshot> {
-    match store.get(path).await {
+    let res = store
+        .get(path)
+        .await
+        .with_store_context(|| format!("Failed to read manifest snapshot, path:{path}"));
+    match res {
         Ok(v) => {
             let bytes = v
                 .bytes()
                 .await
-                .with_context(|| format!("Failed to read manifest snapshot, path:{path}"))?;
+                .with_store_context(|| format!("Failed to read manifest snapshot, path:{path}"))?;
             Snapshot::try_from(bytes)
         }
-        Err(err) => {
-            if err.to_string().contains("not found") {
-                Ok(Snapshot::default())
-            } else {
-                let context = format!("Failed to read manifest snapshot, path:{path}");
-                Err(AnyhowError::new(err).context(context).into())
-            }
-        }
+        Err(Error::NotFound(_)) => Ok(Snapshot::default()),
+        Err(e) => Err(e),
     }
 }
 
@@ -357,24 +513,25 @@ async fn read_delta_file(store: &ObjectStoreRef, sst_path: &Path) -> Result<Mani
     let bytes = store
         .get(sst_path)
         .await
-        .with_context(|| format!("failed to get delta file, path:{sst_path}"))?
+        .with_store_context(|| format!("failed to get delta file, path:{sst_path}"))?
         .bytes()
         .await
-        .with_context(|| format!("failed to read delta file, path:{sst_path}"))?;
+        .with_store_context(|| format!("failed to read delta file, path:{sst_path}"))?;
 
-    let pb_update = pb_types::ManifestUpdate::decode(bytes)
-        .with_context(|| format!("failed to decode delta file, path:{sst_path}"))?;
+    let pb_update = pb_types::ManifestUpdate::decode(bytes).map_err(|e| {
+        Error::Corruption(format!(
+            "failed to decode delta file, path:{sst_path}, err:{e}"
+        ))
+    })?;
 
-    let update = ManifestUpdate::try_from(pb_update)
-        .with_context(|| format!("failed to convert delta file, path:{sst_path}"))?;
-    Ok(update)
+    ManifestUpdate::try_from(pb_update)
 }
 
 async fn delete_delta_file(store: &ObjectStoreRef, path: &Path) -> Result<()> {
     store
         .delete(path)
         .await
-        .with_context(|| format!("Failed to delete delta files, path:{path}"))?;
+        .with_store_context(|| format!("Failed to delete delta files, path:{path}"))?;
 
     Ok(())
 }
@@ -383,9 +540,9 @@ async fn list_delta_paths(store: &ObjectStoreRef, delta_dir: &Path) -> Result<Ve
     let paths = store
         .list(Some(delta_dir))
         .map(|value| {
-            value
-                .map(|v| v.location)
-                .with_context(|| format!("Failed to list delta paths, delta dir:{}", delta_dir))
+            value.map(|v| v.location).with_store_context(|| {
+                format!("Failed to list delta paths, delta dir:{}", delta_dir)
+            })
         })
         .try_collect::<Vec<_>>()
         .await?;
@@ -397,6 +554,7 @@ async fn list_delta_paths(store: &ObjectStoreRef, delta_dir: &Path) -> Result<Ve
 mod tests {
     use std::sync::Arc;
 
+    use common::ReadableDuration;
     use object_store::local::LocalFileSystem;
     use tokio::time::sleep;
 
@@ -414,6 +572,7 @@ mod tests {
                 root_dir.path().to_string_lossy().to_string(),
                 store,
                 runtime.clone(),
+                Duration::from_secs(3600),
                 ManifestConfig::default(),
             )
             .await
@@ -426,6 +585,9 @@ mod tests {
                     num_rows: i as u32,
                     size: i as u32,
                     time_range,
+                    primary_key_stats: None,
+                    deduplicated: false,
+                    merged: false,
                 };
                 manifest.add_file(i as u64, meta).await.unwrap();
             }
@@ -442,6 +604,9 @@ mod tests {
                         num_rows: i as u32,
                         size: i as u32,
                         time_range,
+                        primary_key_stats: None,
+                        deduplicated: false,
+                        merged: false,
                     };
                     SstFile::new(id, meta)
                 })
@@ -471,6 +636,7 @@ mod tests {
                 root_dir,
                 store.clone(),
                 runtime.clone(),
+                Duration::from_secs(3600),
                 ManifestConfig {
                     merge_interval_seconds: 1,
                     ..Default::default()
@@ -487,6 +653,9 @@ mod tests {
                     num_rows: i as u32,
                     size: i as u32,
                     time_range,
+                    primary_key_stats: None,
+                    deduplicated: false,
+                    merged: false,
                 };
                 manifest.add_file(i as u64, meta).await.unwrap();
             }
@@ -494,7 +663,7 @@ mod tests {
             // Wait for merge manifest to finish
             sleep(Duration::from_secs(2)).await;
 
-            let mut mem_ssts = manifest.ssts.read().await.clone();
+            let mut mem_ssts = manifest.all_ssts().await;
             let snapshot = read_snapshot(&store, &snapshot_path).await.unwrap();
             let mut ssts = snapshot.into_ssts();
 
@@ -506,4 +675,86 @@ mod tests {
             assert!(delta_paths.is_empty());
         })
     }
+
+    #[test]
+    fn test_manifest_write_keys() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let path = root_dir.path().to_string_lossy().to_string();
+
+        rt.block_on(async move {
+            let manifest = Manifest::try_new(
+                path.clone(),
+                store.clone(),
+                runtime.clone(),
+                Duration::from_secs(3600),
+                ManifestConfig::default(),
+            )
+            .await
+            .unwrap();
+            let meta = FileMeta {
+                max_sequence: 1,
+                num_rows: 1,
+                size: 1,
+                time_range: (0..1).into(),
+                primary_key_stats: None,
+                deduplicated: false,
+                merged: false,
+            };
+            let write_keys = vec![
+                WriteKey {
+                    key: "k1".to_string(),
+                    timestamp: Timestamp(now()),
+                },
+                // Expired already.
+                WriteKey {
+                    key: "k2".to_string(),
+                    timestamp: Timestamp(now() - 3_600_000),
+                },
+            ];
+            manifest
+                .update(
+                    ManifestUpdate::new(vec![SstFile::new(1, meta)], Vec::new())
+                        .with_write_keys(write_keys),
+                )
+                .await
+                .unwrap();
+            assert!(manifest.contains_write_key("k1"));
+            assert!(!manifest.contains_write_key("k2"));
+            assert!(!manifest.contains_write_key("k3"));
+            drop(manifest);
+
+            // Keys are merged into snapshot when reopen.
+            let manifest = Manifest::try_new(
+                path.clone(),
+                store.clone(),
+                runtime.clone(),
+                Duration::from_secs(3600),
+                ManifestConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert!(manifest.contains_write_key("k1"));
+            assert!(!manifest.contains_write_key("k2"));
+            assert_eq!(1, manifest.all_ssts().await.len());
+            drop(manifest);
+
+            let manifest = Manifest::try_new(
+                path,
+                store,
+                runtime,
+                Duration::from_secs(3600),
+                ManifestConfig {
+                    idempotency_window: ReadableDuration::millis(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            sleep(Duration::from_millis(10)).await;
+            assert!(!manifest.contains_write_key("k1"));
+        });
+    }
 }
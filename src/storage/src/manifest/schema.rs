@@ -0,0 +1,329 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Persisted schema of the storage.
+//!
+//! Each version is stored in its own file under `{root_dir}/manifest/schema`,
+//! named by the version, so the latest one is the file with largest name.
+//! Options fixed at creation are stored along with it, so reopen can be
+//! checked against them.
+
+use std::{io::Cursor, time::Duration};
+
+use anyhow::Context;
+use arrow::ipc::{reader::StreamReader, writer::StreamWriter};
+use common::StoreContext;
+use futures::TryStreamExt;
+use object_store::{path::Path, PutMode, PutPayload};
+use prost::Message;
+
+use crate::{
+    config::UpdateMode,
+    ensure,
+    manifest::PREFIX_PATH,
+    types::{ObjectStoreRef, StorageSchema},
+    Error, Result,
+};
+
+pub const SCHEMA_PREFIX: &str = "schema";
+
+/// Schema and options which can't be changed after the storage is created.
+#[derive(Debug, Clone)]
+pub struct TableDescriptor {
+    pub schema: StorageSchema,
+    pub segment_duration: Duration,
+}
+
+impl TableDescriptor {
+    /// Check the descriptor given when reopen is the same as the persisted
+    /// one.
+    pub fn check(&self, given: &TableDescriptor) -> Result<()> {
+        let persisted = &self.schema;
+        let schema = &given.schema;
+        ensure!(
+            persisted.user_schema().fields() == schema.user_schema().fields(),
+            Error::InvalidSchema(format!(
+                "schema mismatch with persisted one, version:{}, persisted:{:?}, given:{:?}",
+                persisted.version,
+                persisted.user_schema().fields(),
+                schema.user_schema().fields()
+            ))
+        );
+        ensure!(
+            persisted.num_primary_keys == schema.num_primary_keys,
+            Error::InvalidSchema(format!(
+                "num_primary_keys mismatch with persisted one, persisted:{}, given:{}",
+                persisted.num_primary_keys, schema.num_primary_keys
+            ))
+        );
+        ensure!(
+            persisted.update_mode == schema.update_mode,
+            Error::InvalidSchema(format!(
+                "update_mode mismatch with persisted one, persisted:{:?}, given:{:?}",
+                persisted.update_mode, schema.update_mode
+            ))
+        );
+        ensure!(
+            persisted.timestamp_column() == schema.timestamp_column(),
+            Error::InvalidSchema(format!(
+                "timestamp_column mismatch with persisted one, persisted:{:?}, given:{:?}",
+                persisted.timestamp_column(),
+                schema.timestamp_column()
+            ))
+        );
+        ensure!(
+            persisted.sort_by_timestamp == schema.sort_by_timestamp,
+            Error::InvalidSchema(format!(
+                "sort_by_timestamp mismatch with persisted one, persisted:{}, given:{}",
+                persisted.sort_by_timestamp, schema.sort_by_timestamp
+            ))
+        );
+        ensure!(
+            persisted.merge_operators == schema.merge_operators,
+            Error::InvalidSchema(format!(
+                "merge_operators mismatch with persisted one, persisted:{:?}, given:{:?}",
+                persisted.merge_operators, schema.merge_operators
+            ))
+        );
+        ensure!(
+            self.segment_duration == given.segment_duration,
+            Error::InvalidSchema(format!(
+                "segment_duration mismatch with persisted one, persisted:{:?}, given:{:?}",
+                self.segment_duration, given.segment_duration
+            ))
+        );
+
+        Ok(())
+    }
+}
+
+impl TryFrom<pb_types::StorageSchema> for TableDescriptor {
+    type Error = Error;
+
+    fn try_from(value: pb_types::StorageSchema) -> Result<Self> {
+        let reader = StreamReader::try_new(Cursor::new(&value.arrow_schema), None)
+            .context("decode arrow schema")?;
+        let update_mode = match value.update_mode() {
+            pb_types::UpdateMode::Overwrite => UpdateMode::Overwrite,
+            pb_types::UpdateMode::Append => UpdateMode::Append,
+        };
+        let timestamp_column =
+            (!value.timestamp_column.is_empty()).then_some(value.timestamp_column.as_str());
+        let mut schema = StorageSchema::try_new(
+            reader.schema(),
+            value.num_primary_keys as usize,
+            timestamp_column,
+            update_mode,
+        )?
+        .with_sort_by_timestamp(value.sort_by_timestamp)?
+        .with_merge_operators(value.merge_operators.into_iter().collect())?;
+        schema.version = value.version;
+        schema.dropped_columns = value.dropped_columns;
+
+        Ok(Self {
+            schema,
+            segment_duration: Duration::from_millis(value.segment_duration_ms),
+        })
+    }
+}
+
+impl TryFrom<&TableDescriptor> for pb_types::StorageSchema {
+    type Error = Error;
+
+    fn try_from(value: &TableDescriptor) -> Result<Self> {
+        let schema = &value.schema;
+        let mut arrow_schema = Vec::new();
+        {
+            let mut writer = StreamWriter::try_new(&mut arrow_schema, &schema.user_schema())
+                .context("create ipc writer")?;
+            writer.finish().context("finish ipc writer")?;
+        }
+        let update_mode = match schema.update_mode {
+            UpdateMode::Overwrite => pb_types::UpdateMode::Overwrite,
+            UpdateMode::Append => pb_types::UpdateMode::Append,
+        };
+
+        Ok(Self {
+            version: schema.version,
+            arrow_schema,
+            dropped_columns: schema.dropped_columns.clone(),
+            num_primary_keys: schema.num_primary_keys as u32,
+            update_mode: update_mode.into(),
+            timestamp_column: schema.timestamp_column().unwrap_or_default().to_string(),
+            segment_duration_ms: value.segment_duration.as_millis() as u64,
+            sort_by_timestamp: schema.sort_by_timestamp,
+            merge_operators: schema
+                .merge_operators
+                .iter()
+                .map(|(column, name)| (column.clone(), name.clone()))
+                .collect(),
+        })
+    }
+}
+
+pub struct SchemaStore {
+    schema_dir: Path,
+    store: ObjectStoreRef,
+}
+
+impl SchemaStore {
+    pub fn new(root_dir: &str, store: ObjectStoreRef) -> Self {
+        Self {
+            schema_dir: Path::from(format!("{root_dir}/{PREFIX_PATH}/{SCHEMA_PREFIX}")),
+            store,
+        }
+    }
+
+    /// Load the latest version, `None` means nothing is persisted yet.
+    pub async fn load(&self) -> Result<Option<TableDescriptor>> {
+        let paths = self
+            .store
+            .list(Some(&self.schema_dir))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await
+            .with_store_context(|| format!("list schema files, dir:{}", self.schema_dir))?;
+        let Some(path) = paths.into_iter().max() else {
+            return Ok(None);
+        };
+
+        let bytes = self
+            .store
+            .get(&path)
+            .await
+            .with_store_context(|| format!("get schema file, path:{path}"))?
+            .bytes()
+            .await
+            .with_store_context(|| format!("read schema file, path:{path}"))?;
+        let pb_schema = pb_types::StorageSchema::decode(bytes)
+            .map_err(|e| Error::Corruption(format!("decode schema file, path:{path}, err:{e}")))?;
+
+        TableDescriptor::try_from(pb_schema).map(Some)
+    }
+
+    /// Persist a new version, it fails when the version already exists.
+    pub async fn persist(&self, descriptor: &TableDescriptor) -> Result<()> {
+        let pb_schema = pb_types::StorageSchema::try_from(descriptor)?;
+        let path = Path::from(format!("{}/{:020}", self.schema_dir, pb_schema.version));
+        self.store
+            .put_opts(
+                &path,
+                PutPayload::from(pb_schema.encode_to_vec()),
+                PutMode::Create.into(),
+            )
+            .await
+            .with_store_context(|| format!("put schema file, path:{path}"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use arrow::datatypes::{DataType, Field};
+    use object_store::local::LocalFileSystem;
+
+    use super::*;
+    use crate::{arrow_schema, operator::LAST_VALUE_OPERATOR};
+
+    #[tokio::test]
+    async fn test_schema_store() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let schema_store = SchemaStore::new(&root_dir.path().to_string_lossy(), store);
+        assert!(schema_store.load().await.unwrap().is_none());
+
+        let schema = StorageSchema::try_new(
+            arrow_schema!(("pk1", UInt8), ("ts", Int64), ("v1", Int64)),
+            1,
+            Some("ts"),
+            UpdateMode::Append,
+        )
+        .unwrap()
+        .with_sort_by_timestamp(true)
+        .unwrap()
+        .with_merge_operators(HashMap::from([(
+            "v1".to_string(),
+            LAST_VALUE_OPERATOR.to_string(),
+        )]))
+        .unwrap();
+        let descriptor = TableDescriptor {
+            schema: schema.clone(),
+            segment_duration: Duration::from_secs(3600),
+        };
+        schema_store.persist(&descriptor).await.unwrap();
+        let new_descriptor = TableDescriptor {
+            schema: schema
+                .alter(
+                    &[Arc::new(Field::new("v2", DataType::Utf8, true))],
+                    &["v1".to_string()],
+                )
+                .unwrap(),
+            ..descriptor.clone()
+        };
+        schema_store.persist(&new_descriptor).await.unwrap();
+        // Same version can't be persisted twice.
+        let err = schema_store.persist(&new_descriptor).await.unwrap_err();
+        assert!(!err.is_retryable());
+
+        let loaded = schema_store.load().await.unwrap().unwrap();
+        assert_eq!(2, loaded.schema.version);
+        assert_eq!(vec!["v1".to_string()], loaded.schema.dropped_columns);
+        assert_eq!(
+            new_descriptor.schema.arrow_schema,
+            loaded.schema.arrow_schema
+        );
+        assert_eq!(Some(1), loaded.schema.timestamp_idx);
+        assert_eq!(UpdateMode::Append, loaded.schema.update_mode);
+        assert!(loaded.schema.sort_by_timestamp);
+        assert_eq!(descriptor.segment_duration, loaded.segment_duration);
+        loaded.check(&new_descriptor).unwrap();
+
+        // Old schema or different options are rejected.
+        assert!(matches!(
+            loaded.check(&descriptor),
+            Err(Error::InvalidSchema(_))
+        ));
+        let mut given = new_descriptor.clone();
+        given.segment_duration = Duration::from_secs(60);
+        assert!(loaded.check(&given).is_err());
+        let mut given = new_descriptor.clone();
+        given.schema.sort_by_timestamp = false;
+        assert!(loaded.check(&given).is_err());
+        let mut given = new_descriptor.clone();
+        given.schema.update_mode = UpdateMode::Overwrite;
+        assert!(loaded.check(&given).is_err());
+        let mut given = new_descriptor.clone();
+        given.schema.num_primary_keys = 2;
+        assert!(loaded.check(&given).is_err());
+
+        // Operators of dropped columns are removed, others are persisted.
+        assert!(loaded.schema.merge_operators.is_empty());
+        let loaded =
+            TableDescriptor::try_from(pb_types::StorageSchema::try_from(&descriptor).unwrap())
+                .unwrap();
+        assert_eq!(
+            descriptor.schema.merge_operators,
+            loaded.schema.merge_operators
+        );
+        let mut given = descriptor.clone();
+        given.schema.merge_operators.clear();
+        assert!(loaded.check(&given).is_err());
+    }
+}
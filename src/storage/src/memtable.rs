@@ -0,0 +1,625 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Memtable buffers writes in memory, and flushes them to sst when it's full
+//! or old enough.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use arrow::{array::RecordBatch, compute::concat_batches};
+use futures::StreamExt;
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Notify, RwLock, RwLockReadGuard,
+    },
+    time::sleep,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::MemTableConfig,
+    manifest::{ManifestRef, ManifestUpdate, WriteKey},
+    sst::{FileMeta, SstFile},
+    types::{SharedSchema, TimeRange, Timestamp},
+    wal::WalRef,
+    write::ParquetWriter,
+    Result,
+};
+
+/// Writes of one segment buffered in memory.
+///
+/// Each batch is sorted by primary keys and filled with builtin columns.
+#[derive(Debug)]
+pub struct MemTable {
+    segment: Timestamp,
+    batches: Vec<RecordBatch>,
+    time_range: TimeRange,
+    min_sequence: u64,
+    max_sequence: u64,
+    num_rows: usize,
+    memory_size: usize,
+    created_at: Instant,
+    /// Idempotency keys of writes inserted, recorded in manifest when
+    /// flushed.
+    write_keys: Vec<WriteKey>,
+}
+
+impl MemTable {
+    fn new(segment: Timestamp, time_range: TimeRange, sequence: u64) -> Self {
+        Self {
+            segment,
+            batches: Vec::new(),
+            time_range,
+            min_sequence: sequence,
+            max_sequence: sequence,
+            num_rows: 0,
+            memory_size: 0,
+            created_at: Instant::now(),
+            write_keys: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, batch: RecordBatch, time_range: &TimeRange, sequence: u64) {
+        self.num_rows += batch.num_rows();
+        self.memory_size += batch.get_array_memory_size();
+        self.time_range.merge(time_range);
+        self.max_sequence = self.max_sequence.max(sequence);
+        self.batches.push(batch);
+    }
+
+    fn should_flush(&self, config: &MemTableConfig) -> bool {
+        self.memory_size as u64 >= config.max_size.as_byte()
+            || self.num_rows >= config.max_rows
+            || self.created_at.elapsed() >= config.max_age.0
+    }
+}
+
+#[derive(Default)]
+struct State {
+    actives: BTreeMap<Timestamp, MemTable>,
+    /// Memtables waiting for flush, ordered by sequence.
+    immutables: Vec<Arc<MemTable>>,
+    /// Sequence of the latest write inserted.
+    last_sequence: u64,
+}
+
+/// Memtables of all segments.
+pub struct MemTables {
+    segment_duration: Duration,
+    config: MemTableConfig,
+    state: Mutex<State>,
+    flush_tx: UnboundedSender<Arc<MemTable>>,
+    /// Notified after each memtable is flushed.
+    flushed: Notify,
+    /// Held exclusively while a flushed memtable is moved to manifest.
+    flush_lock: RwLock<()>,
+}
+
+impl MemTables {
+    pub fn new(
+        segment_duration: Duration,
+        config: MemTableConfig,
+    ) -> (Self, UnboundedReceiver<Arc<MemTable>>) {
+        let (flush_tx, flush_rx) = mpsc::unbounded_channel();
+        let memtables = Self {
+            segment_duration,
+            config,
+            state: Mutex::new(State::default()),
+            flush_tx,
+            flushed: Notify::new(),
+            flush_lock: RwLock::new(()),
+        };
+        (memtables, flush_rx)
+    }
+
+    /// Insert batches of one write into memtables of their segments, they are
+    /// visible to readers at the same time. Sequence should be increasing.
+    ///
+    /// `write_keys` are kept by memtables of all segments, so they are
+    /// recorded in manifest by whichever is flushed first.
+    pub fn insert(
+        &self,
+        batches: Vec<(TimeRange, RecordBatch)>,
+        sequence: u64,
+        write_keys: Vec<WriteKey>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let mut segments = Vec::with_capacity(batches.len());
+        for (time_range, batch) in batches {
+            let segment = time_range.start.truncate_by(self.segment_duration);
+            let memtable = state
+                .actives
+                .entry(segment)
+                .or_insert_with(|| MemTable::new(segment, time_range.clone(), sequence));
+            memtable.insert(batch, &time_range, sequence);
+            memtable.write_keys.extend(write_keys.iter().cloned());
+            segments.push(segment);
+        }
+        for segment in segments {
+            let should_flush = state
+                .actives
+                .get(&segment)
+                .is_some_and(|memtable| memtable.should_flush(&self.config));
+            if should_flush {
+                self.freeze(&mut state, segment);
+            }
+        }
+        state.last_sequence = sequence;
+    }
+
+    /// Freeze memtables exceed the age limit.
+    pub fn freeze_expired(&self) {
+        let mut state = self.state.lock().unwrap();
+        let expireds = state
+            .actives
+            .iter()
+            .filter(|(_, memtable)| memtable.should_flush(&self.config))
+            .map(|(segment, _)| *segment)
+            .collect::<Vec<_>>();
+        for segment in expireds {
+            self.freeze(&mut state, segment);
+        }
+    }
+
+    /// Freeze all active memtables, and wait until all writes inserted
+    /// before are flushed.
+    pub async fn flush_all(&self) {
+        let last_sequence = {
+            let mut state = self.state.lock().unwrap();
+            let segments = state.actives.keys().copied().collect::<Vec<_>>();
+            for segment in segments {
+                self.freeze(&mut state, segment);
+            }
+            state.last_sequence
+        };
+
+        loop {
+            // Created before checking, so notification between them is not
+            // missed.
+            let flushed = self.flushed.notified();
+            if self.flushed_sequence() >= last_sequence {
+                return;
+            }
+            flushed.await;
+        }
+    }
+
+    fn freeze(&self, state: &mut State, segment: Timestamp) {
+        let Some(memtable) = state.actives.remove(&segment) else {
+            return;
+        };
+        debug!(
+            segment = ?memtable.segment,
+            num_rows = memtable.num_rows,
+            memory_size = memtable.memory_size,
+            "Freeze memtable"
+        );
+        let memtable = Arc::new(memtable);
+        state.immutables.push(memtable.clone());
+        if self.flush_tx.send(memtable).is_err() {
+            error!("Flush task is stopped, memtable will never be flushed");
+        }
+    }
+
+    /// Blocks flushed memtables from moving to manifest until the guard is
+    /// dropped, so rows read from manifest and memtables meanwhile are
+    /// consistent.
+    pub async fn pause_flush(&self) -> RwLockReadGuard<'_, ()> {
+        self.flush_lock.read().await
+    }
+
+    /// Returns memtables whose segment overlaps with `time_range`, grouped by
+    /// segment.
+    pub fn find_memtables(&self, time_range: &TimeRange) -> BTreeMap<Timestamp, Vec<RecordBatch>> {
+        let state = self.state.lock().unwrap();
+        let mut res = BTreeMap::new();
+        let memtables = state
+            .immutables
+            .iter()
+            .map(|memtable| memtable.as_ref())
+            .chain(state.actives.values());
+        for memtable in memtables {
+            if memtable.time_range.overlaps(time_range) {
+                res.entry(memtable.segment)
+                    .or_insert_with(Vec::new)
+                    .extend(memtable.batches.iter().cloned());
+            }
+        }
+
+        res
+    }
+
+    /// Whether a write with `key` is inserted and not flushed yet.
+    pub fn contains_write_key(&self, key: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .immutables
+            .iter()
+            .map(|memtable| memtable.as_ref())
+            .chain(state.actives.values())
+            .any(|memtable| memtable.write_keys.iter().any(|k| k.key == key))
+    }
+
+    fn remove_immutable(&self, memtable: &MemTable) {
+        let mut state = self.state.lock().unwrap();
+        // Memtables of one write share the same sequence, so they are
+        // identified by address.
+        state
+            .immutables
+            .retain(|m| !std::ptr::eq(m.as_ref(), memtable));
+        self.flushed.notify_waiters();
+    }
+
+    /// Writes with sequence less than or equal to the returned value are all
+    /// flushed.
+    fn flushed_sequence(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .immutables
+            .iter()
+            .map(|memtable| memtable.as_ref())
+            .chain(state.actives.values())
+            .map(|memtable| memtable.min_sequence - 1)
+            .min()
+            .unwrap_or(state.last_sequence)
+    }
+}
+
+/// Background job to flush frozen memtables one by one, so ssts within a
+/// segment are added to manifest in the order of sequence.
+pub struct Flusher {
+    memtables: Arc<MemTables>,
+    schema: SharedSchema,
+    parquet_writer: Arc<ParquetWriter>,
+    manifest: ManifestRef,
+    wal: Option<WalRef>,
+}
+
+impl Flusher {
+    const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        memtables: Arc<MemTables>,
+        schema: SharedSchema,
+        parquet_writer: Arc<ParquetWriter>,
+        manifest: ManifestRef,
+        wal: Option<WalRef>,
+    ) -> Self {
+        Self {
+            memtables,
+            schema,
+            parquet_writer,
+            manifest,
+            wal,
+        }
+    }
+
+    pub async fn run(self, mut flush_rx: UnboundedReceiver<Arc<MemTable>>) {
+        info!("Memtable flush job started");
+        while let Some(memtable) = flush_rx.recv().await {
+            // Retry until success, otherwise later memtables of the same
+            // segment will be flushed before this one.
+            while let Err(e) = self.flush(&memtable).await {
+                error!("Flush memtable failed, err:{e:?}");
+                sleep(Self::RETRY_INTERVAL).await;
+            }
+            self.truncate_wal().await;
+        }
+        info!("Memtable flush job stopped");
+    }
+
+    async fn flush(&self, memtable: &MemTable) -> Result<()> {
+        let file_id = SstFile::allocate_id();
+        // Schema may be altered after batches are inserted.
+        let schema = self.schema.load();
+        let batches = memtable
+            .batches
+            .iter()
+            .map(|batch| schema.project_batch(batch.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let batch =
+            concat_batches(&schema.arrow_schema, &batches).context("concat memtable batches")?;
+        let batches = self
+            .parquet_writer
+            .sort_batch(batch, true /* sort_seq */)
+            .await?
+            .map(|batch| Ok(batch.context("sort memtable batch")?));
+        let summary = self.parquet_writer.write(file_id, batches).await?;
+        let file_meta = FileMeta {
+            max_sequence: memtable.max_sequence,
+            num_rows: summary.num_rows as u32,
+            size: summary.size as u32,
+            time_range: summary
+                .time_range
+                .unwrap_or_else(|| memtable.time_range.clone()),
+            primary_key_stats: summary.primary_key_stats,
+            deduplicated: false,
+            merged: false,
+        };
+        debug!(file_id, file_meta = ?file_meta, "Flush memtable to sst");
+        let update = ManifestUpdate::new(vec![SstFile::new(file_id, file_meta)], Vec::new())
+            .with_write_keys(memtable.write_keys.clone());
+        {
+            // Readers see rows of the memtable either in manifest or in
+            // memtables, but not both.
+            let _guard = self.memtables.flush_lock.write().await;
+            self.manifest.update(update).await?;
+            self.memtables.remove_immutable(memtable);
+        }
+
+        Ok(())
+    }
+
+    /// Truncate wal entries of flushed memtables. It's not retried since the
+    /// memtable is flushed already, entries left are truncated by later
+    /// flushes.
+    async fn truncate_wal(&self) {
+        let Some(wal) = &self.wal else {
+            return;
+        };
+        if let Err(e) = wal.truncate(self.memtables.flushed_sequence()).await {
+            warn!("Truncate wal failed, err:{e:?}");
+        }
+    }
+}
+
+/// Periodically freeze memtables exceed the age limit.
+pub async fn freeze_expired_loop(memtables: Arc<MemTables>, check_interval: Duration) {
+    loop {
+        sleep(check_interval).await;
+        memtables.freeze_expired();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use common::{ReadableDuration, ReadableSize};
+    use object_store::local::LocalFileSystem;
+    use parquet::file::properties::WriterProperties;
+
+    use super::*;
+    use crate::{
+        config::{ManifestConfig, UpdateMode},
+        manifest::Manifest,
+        record_batch,
+        sst::SstPathGenerator,
+        types::{ObjectStoreRef, RowKind, StorageSchema},
+        wal::{LocalDiskWal, Wal, WalEntry},
+    };
+
+    fn build_batch(num_rows: usize) -> RecordBatch {
+        record_batch!(
+            ("pk1", UInt8, vec![1; num_rows]),
+            ("value", Int64, vec![2; num_rows])
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_memtables() {
+        let config = MemTableConfig {
+            enable: true,
+            max_size: ReadableSize::gb(1),
+            max_rows: 5,
+            max_age: ReadableDuration::hours(1),
+            check_interval: ReadableDuration::secs(1),
+        };
+        let (memtables, mut flush_rx) = MemTables::new(Duration::from_millis(10), config);
+
+        // Two segments: [0, 10), [10, 20)
+        memtables.insert(vec![((1..2).into(), build_batch(2))], 1, Vec::new());
+        memtables.insert(vec![((11..12).into(), build_batch(2))], 2, Vec::new());
+        memtables.insert(vec![((2..3).into(), build_batch(2))], 3, Vec::new());
+        assert_eq!(0, memtables.flushed_sequence());
+        assert!(flush_rx.try_recv().is_err());
+
+        let found = memtables.find_memtables(&(0..10).into());
+        assert_eq!(1, found.len());
+        assert_eq!(2, found[&Timestamp(0)].len());
+
+        // Exceed max rows.
+        let write_key = WriteKey {
+            key: "k4".to_string(),
+            timestamp: Timestamp(4),
+        };
+        memtables.insert(
+            vec![((3..4).into(), build_batch(2))],
+            4,
+            vec![write_key.clone()],
+        );
+        let frozen = flush_rx.try_recv().unwrap();
+        assert_eq!(Timestamp(0), frozen.segment);
+        assert_eq!(TimeRange::from(1..4), frozen.time_range);
+        assert_eq!((1, 4), (frozen.min_sequence, frozen.max_sequence));
+        assert_eq!(6, frozen.num_rows);
+        assert_eq!(vec![write_key], frozen.write_keys);
+        assert!(memtables.contains_write_key("k4"));
+
+        // Frozen memtable is still visible before flushed.
+        assert_eq!(2, memtables.find_memtables(&(0..20).into()).len());
+        memtables.remove_immutable(&frozen);
+        assert_eq!(1, memtables.find_memtables(&(0..20).into()).len());
+        assert_eq!(1, memtables.flushed_sequence());
+        assert!(!memtables.contains_write_key("k4"));
+    }
+
+    #[tokio::test]
+    async fn test_memtables_flush_all() {
+        let (memtables, mut flush_rx) =
+            MemTables::new(Duration::from_millis(10), MemTableConfig::default());
+        let memtables = Arc::new(memtables);
+        // One write across two segments.
+        memtables.insert(
+            vec![
+                ((1..2).into(), build_batch(2)),
+                ((11..12).into(), build_batch(2)),
+            ],
+            1,
+            Vec::new(),
+        );
+        assert_eq!(2, memtables.find_memtables(&(0..20).into()).len());
+
+        let flush_all = tokio::spawn({
+            let memtables = memtables.clone();
+            async move { memtables.flush_all().await }
+        });
+        for _ in 0..2 {
+            let frozen = flush_rx.recv().await.unwrap();
+            assert!(!flush_all.is_finished());
+            memtables.remove_immutable(&frozen);
+        }
+        flush_all.await.unwrap();
+        assert_eq!(1, memtables.flushed_sequence());
+    }
+
+    #[tokio::test]
+    async fn test_memtables_flush_sibling() {
+        let wal_dir = temp_dir::TempDir::new().unwrap();
+        let wal_dir = wal_dir.path().to_string_lossy().to_string();
+        // Each entry is in its own segment, so it's deleted once truncated.
+        let wal = LocalDiskWal::try_new(wal_dir.clone(), 1).await.unwrap();
+        let (memtables, mut flush_rx) =
+            MemTables::new(Duration::from_millis(10), MemTableConfig::default());
+        // One write across two segments.
+        let batches = vec![
+            ((1..2).into(), build_batch(2)),
+            ((11..12).into(), build_batch(2)),
+        ];
+        wal.append(&WalEntry {
+            sequence: 1,
+            kind: RowKind::Put,
+            batches: batches.clone(),
+            write_keys: Vec::new(),
+        })
+        .await
+        .unwrap();
+        memtables.insert(batches, 1, Vec::new());
+        {
+            let mut state = memtables.state.lock().unwrap();
+            memtables.freeze(&mut state, Timestamp(0));
+            memtables.freeze(&mut state, Timestamp(10));
+        }
+
+        // Flush one of the memtables of the write.
+        let frozen = flush_rx.recv().await.unwrap();
+        memtables.remove_immutable(&frozen);
+        let found = memtables.find_memtables(&(0..20).into());
+        assert_eq!(1, found.len());
+        assert!(!found.contains_key(&frozen.segment));
+        assert_eq!(0, memtables.flushed_sequence());
+
+        // The write is kept in wal until its sibling is flushed.
+        wal.truncate(memtables.flushed_sequence()).await.unwrap();
+        let reopened = LocalDiskWal::try_new(wal_dir.clone(), 1).await.unwrap();
+        assert_eq!(1, reopened.replay().await.unwrap().len());
+        let frozen = flush_rx.recv().await.unwrap();
+        memtables.remove_immutable(&frozen);
+        assert!(memtables.find_memtables(&(0..20).into()).is_empty());
+        wal.truncate(memtables.flushed_sequence()).await.unwrap();
+        let reopened = LocalDiskWal::try_new(wal_dir, 1).await.unwrap();
+        assert!(reopened.replay().await.unwrap().is_empty());
+    }
+
+    /// Wal whose truncate always fails.
+    #[derive(Default)]
+    struct TruncateFailedWal {
+        num_truncates: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Wal for TruncateFailedWal {
+        async fn append(&self, _entry: &WalEntry) -> Result<()> {
+            Ok(())
+        }
+
+        async fn replay(&self) -> Result<Vec<WalEntry>> {
+            Ok(Vec::new())
+        }
+
+        async fn truncate(&self, _sequence: u64) -> Result<()> {
+            self.num_truncates.fetch_add(1, Ordering::Relaxed);
+            Err(anyhow::anyhow!("injected truncate failure").into())
+        }
+    }
+
+    #[test]
+    fn test_flusher_truncate_failed() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let path = root_dir.path().to_string_lossy().to_string();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let segment_duration = Duration::from_millis(10);
+        runtime.clone().block_on(async move {
+            let schema =
+                StorageSchema::try_new(build_batch(1).schema(), 1, None, UpdateMode::Overwrite)
+                    .unwrap();
+            let schema = SharedSchema::new(schema);
+            let manifest = Manifest::try_new(
+                path.clone(),
+                store.clone(),
+                runtime,
+                segment_duration,
+                ManifestConfig::default(),
+            )
+            .await
+            .unwrap();
+            let manifest = Arc::new(manifest);
+            let parquet_writer = Arc::new(ParquetWriter::new(
+                store,
+                schema.clone(),
+                Arc::new(SstPathGenerator::new(path)),
+                WriterProperties::default(),
+            ));
+            let wal = Arc::new(TruncateFailedWal::default());
+            let (memtables, flush_rx) = MemTables::new(segment_duration, MemTableConfig::default());
+            let memtables = Arc::new(memtables);
+            let flusher = Flusher::new(
+                memtables.clone(),
+                schema.clone(),
+                parquet_writer,
+                manifest.clone(),
+                Some(wal.clone()),
+            );
+            tokio::spawn(flusher.run(flush_rx));
+
+            for sequence in [1, 2] {
+                let batch = schema
+                    .load()
+                    .fill_builtin_columns(build_batch(2), sequence, RowKind::Put)
+                    .unwrap();
+                memtables.insert(vec![((1..2).into(), batch)], sequence, Vec::new());
+                tokio::time::timeout(Duration::from_secs(5), memtables.flush_all())
+                    .await
+                    .unwrap();
+            }
+            while wal.num_truncates.load(Ordering::Relaxed) < 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+            // Each memtable is flushed once, though truncate failed.
+            assert_eq!(2, manifest.all_ssts().await.len());
+        });
+    }
+}
@@ -15,24 +15,117 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{Arc, LazyLock, RwLock},
+};
 
 use anyhow::Context;
 use arrow::{
-    array::{Array, BinaryArray, RecordBatch},
+    array::{Array, ArrayRef, AsArray, BinaryArray, PrimitiveArray, RecordBatch},
     buffer::OffsetBuffer,
-    datatypes::DataType,
+    compute::{max, min, sum},
+    datatypes::{
+        ArrowNativeType, ArrowNativeTypeOp, ArrowPrimitiveType, DataType, Date32Type, Date64Type,
+        Decimal128Type, Decimal256Type, Float16Type, Float32Type, Float64Type, Int16Type,
+        Int32Type, Int64Type, Int8Type, TimeUnit, TimestampMicrosecondType,
+        TimestampMillisecondType, TimestampNanosecondType, TimestampSecondType, UInt16Type,
+        UInt32Type, UInt64Type, UInt8Type,
+    },
 };
 use tracing::debug;
 
-use crate::{ensure, Result};
+use crate::{ensure, Error, Result};
 
 pub trait MergeOperator: Send + Sync + Debug {
+    /// Check whether value columns of `data_type` can be merged, it's called
+    /// when schema is built, so columns of unsupported types are rejected.
+    fn check_type(&self, _data_type: &DataType) -> Result<()> {
+        Ok(())
+    }
+
     fn merge(&self, batch: RecordBatch) -> Result<RecordBatch>;
 }
 
 pub type MergeOperatorRef = Arc<dyn MergeOperator>;
 
+/// Build a [`MergeOperator`] for value columns at the given indexes, other
+/// columns of its output are ignored.
+pub type MergeOperatorFactory = Arc<dyn Fn(Vec<usize>) -> MergeOperatorRef + Send + Sync>;
+
+pub const LAST_VALUE_OPERATOR: &str = "last_value";
+pub const BYTES_MERGE_OPERATOR: &str = "bytes_merge";
+pub const SUM_OPERATOR: &str = "sum";
+pub const MIN_OPERATOR: &str = "min";
+pub const MAX_OPERATOR: &str = "max";
+pub const COUNTER_OPERATOR: &str = "counter";
+pub const HLL_OPERATOR: &str = "hll";
+
+static REGISTRY: LazyLock<RwLock<HashMap<String, MergeOperatorFactory>>> = LazyLock::new(|| {
+    let mut factories: HashMap<String, MergeOperatorFactory> = HashMap::new();
+    factories.insert(
+        LAST_VALUE_OPERATOR.to_string(),
+        Arc::new(|_| Arc::new(LastValueOperator)),
+    );
+    factories.insert(
+        BYTES_MERGE_OPERATOR.to_string(),
+        Arc::new(|value_idxes| Arc::new(BytesMergeOperator::new(value_idxes))),
+    );
+    for (name, func) in [
+        (SUM_OPERATOR, AggregateFunc::Sum),
+        (MIN_OPERATOR, AggregateFunc::Min),
+        (MAX_OPERATOR, AggregateFunc::Max),
+        (COUNTER_OPERATOR, AggregateFunc::Counter),
+        (HLL_OPERATOR, AggregateFunc::Hll),
+    ] {
+        factories.insert(
+            name.to_string(),
+            Arc::new(move |value_idxes| Arc::new(AggregateOperator::new(func, value_idxes))),
+        );
+    }
+    RwLock::new(factories)
+});
+
+/// Register a merge operator by `name`, so value columns can use it, see
+/// [`crate::config::StorageConfig::merge_operators`].
+///
+/// It should be registered before the storage using it is opened, and the
+/// same name can't be registered twice.
+pub fn register_merge_operator(name: &str, factory: MergeOperatorFactory) -> Result<()> {
+    let mut factories = REGISTRY.write().unwrap();
+    ensure!(
+        !factories.contains_key(name),
+        Error::InvalidRequest(format!("merge operator already registered, name:{name}"))
+    );
+    factories.insert(name.to_string(), factory);
+    Ok(())
+}
+
+pub fn find_merge_operator(name: &str) -> Option<MergeOperatorFactory> {
+    REGISTRY.read().unwrap().get(name).cloned()
+}
+
+/// Build the operator merging value columns, they are grouped by the name
+/// of their operator.
+pub fn build_merge_operator(operators: BTreeMap<&str, Vec<usize>>) -> Result<MergeOperatorRef> {
+    let mut operators = operators
+        .into_iter()
+        .map(|(name, value_idxes)| {
+            let factory = find_merge_operator(name).ok_or_else(|| {
+                Error::InvalidSchema(format!("merge operator not registered, name:{name}"))
+            })?;
+            Ok((value_idxes.clone(), factory(value_idxes)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(!operators.is_empty(), "no merge operator to build");
+    if operators.len() == 1 {
+        return Ok(operators.remove(0).1);
+    }
+
+    Ok(Arc::new(ColumnsMergeOperator::new(operators)))
+}
+
 #[derive(Debug)]
 pub struct LastValueOperator;
 
@@ -57,15 +150,21 @@ impl BytesMergeOperator {
 }
 
 impl MergeOperator for BytesMergeOperator {
+    fn check_type(&self, data_type: &DataType) -> Result<()> {
+        ensure!(
+            data_type == &DataType::Binary,
+            Error::InvalidSchema(format!(
+                "MergeOperator is only used for binary column, current:{data_type}"
+            ))
+        );
+        Ok(())
+    }
+
     fn merge(&self, batch: RecordBatch) -> Result<RecordBatch> {
         assert!(batch.num_rows() > 0);
 
         for idx in &self.value_idxes {
-            let data_type = batch.column(*idx).data_type();
-            ensure!(
-                data_type == &DataType::Binary,
-                "MergeOperator is only used for binary column, current:{data_type}"
-            );
+            self.check_type(batch.column(*idx).data_type())?;
         }
         debug!(batch = ?batch, "BytesMergeOperator merge");
 
@@ -110,6 +209,198 @@ impl MergeOperator for BytesMergeOperator {
     }
 }
 
+/// Function to combine values of a column, null values are ignored unless
+/// stated otherwise, and the result is null if all values are null.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    /// Sum of numeric values, it wraps around on overflow.
+    Sum,
+    /// Min of primitive values.
+    Min,
+    /// Max of primitive values.
+    Max,
+    /// Number of rows merged, integer values are counts of rows merged
+    /// before, and null counts as one, so raw rows can be written with null.
+    Counter,
+    /// Union of HyperLogLog sketches, they are binary values with one byte
+    /// per register, so registers are merged by max. Sketches should have
+    /// the same number of registers.
+    Hll,
+}
+
+/// Combine value columns of rows by [`AggregateFunc`], other columns are
+/// taken from the last row, so compaction keeps one row per primary keys
+/// for pre-aggregated data.
+#[derive(Debug)]
+pub struct AggregateOperator {
+    func: AggregateFunc,
+    value_idxes: Vec<usize>,
+}
+
+impl AggregateOperator {
+    pub fn new(func: AggregateFunc, value_idxes: Vec<usize>) -> Self {
+        Self { func, value_idxes }
+    }
+
+    fn merge_column(&self, column: &ArrayRef) -> Result<ArrayRef> {
+        if self.func == AggregateFunc::Hll {
+            return Self::merge_sketches(column.as_binary::<i32>());
+        }
+
+        let func = self.func;
+        let merged = match column.data_type() {
+            DataType::Int8 => Self::merge_primitive(column.as_primitive::<Int8Type>(), func),
+            DataType::Int16 => Self::merge_primitive(column.as_primitive::<Int16Type>(), func),
+            DataType::Int32 => Self::merge_primitive(column.as_primitive::<Int32Type>(), func),
+            DataType::Int64 => Self::merge_primitive(column.as_primitive::<Int64Type>(), func),
+            DataType::UInt8 => Self::merge_primitive(column.as_primitive::<UInt8Type>(), func),
+            DataType::UInt16 => Self::merge_primitive(column.as_primitive::<UInt16Type>(), func),
+            DataType::UInt32 => Self::merge_primitive(column.as_primitive::<UInt32Type>(), func),
+            DataType::UInt64 => Self::merge_primitive(column.as_primitive::<UInt64Type>(), func),
+            DataType::Float16 => Self::merge_primitive(column.as_primitive::<Float16Type>(), func),
+            DataType::Float32 => Self::merge_primitive(column.as_primitive::<Float32Type>(), func),
+            DataType::Float64 => Self::merge_primitive(column.as_primitive::<Float64Type>(), func),
+            DataType::Decimal128(_, _) => {
+                Self::merge_primitive(column.as_primitive::<Decimal128Type>(), func)
+            }
+            DataType::Decimal256(_, _) => {
+                Self::merge_primitive(column.as_primitive::<Decimal256Type>(), func)
+            }
+            DataType::Date32 => Self::merge_primitive(column.as_primitive::<Date32Type>(), func),
+            DataType::Date64 => Self::merge_primitive(column.as_primitive::<Date64Type>(), func),
+            DataType::Timestamp(TimeUnit::Second, _) => {
+                Self::merge_primitive(column.as_primitive::<TimestampSecondType>(), func)
+            }
+            DataType::Timestamp(TimeUnit::Millisecond, _) => {
+                Self::merge_primitive(column.as_primitive::<TimestampMillisecondType>(), func)
+            }
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                Self::merge_primitive(column.as_primitive::<TimestampMicrosecondType>(), func)
+            }
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                Self::merge_primitive(column.as_primitive::<TimestampNanosecondType>(), func)
+            }
+            t => unreachable!("type is checked before, type:{t}"),
+        };
+        Ok(merged)
+    }
+
+    fn merge_primitive<T: ArrowPrimitiveType>(
+        array: &PrimitiveArray<T>,
+        func: AggregateFunc,
+    ) -> ArrayRef {
+        let value = match func {
+            AggregateFunc::Sum => sum(array),
+            AggregateFunc::Min => min(array),
+            AggregateFunc::Max => max(array),
+            AggregateFunc::Counter => {
+                let nulls = T::Native::usize_as(array.null_count());
+                Some(sum(array).unwrap_or_default().add_wrapping(nulls))
+            }
+            AggregateFunc::Hll => unreachable!("sketches are not primitive"),
+        };
+        let merged =
+            PrimitiveArray::<T>::from_iter([value]).with_data_type(array.data_type().clone());
+        Arc::new(merged)
+    }
+
+    fn merge_sketches(array: &BinaryArray) -> Result<ArrayRef> {
+        let mut registers: Option<Vec<u8>> = None;
+        for sketch in array.iter().flatten() {
+            match &mut registers {
+                Some(registers) => {
+                    ensure!(
+                        registers.len() == sketch.len(),
+                        "hll sketches should have the same number of registers, expected:{}, actual:{}",
+                        registers.len(),
+                        sketch.len()
+                    );
+                    for (register, other) in registers.iter_mut().zip(sketch) {
+                        *register = (*register).max(*other);
+                    }
+                }
+                None => registers = Some(sketch.to_vec()),
+            }
+        }
+
+        Ok(Arc::new(BinaryArray::from(vec![registers.as_deref()])))
+    }
+}
+
+impl MergeOperator for AggregateOperator {
+    fn check_type(&self, data_type: &DataType) -> Result<()> {
+        let supported = match self.func {
+            AggregateFunc::Sum => data_type.is_numeric(),
+            AggregateFunc::Min | AggregateFunc::Max => {
+                data_type.is_numeric()
+                    || matches!(
+                        data_type,
+                        DataType::Timestamp(_, _) | DataType::Date32 | DataType::Date64
+                    )
+            }
+            AggregateFunc::Counter => data_type.is_integer(),
+            AggregateFunc::Hll => data_type == &DataType::Binary,
+        };
+        ensure!(
+            supported,
+            Error::InvalidSchema(format!(
+                "{:?} merge operator doesn't support type {data_type}",
+                self.func
+            ))
+        );
+        Ok(())
+    }
+
+    fn merge(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        assert!(batch.num_rows() > 0);
+
+        let mut columns = batch.slice(batch.num_rows() - 1, 1).columns().to_vec();
+        for idx in &self.value_idxes {
+            let column = batch.column(*idx);
+            self.check_type(column.data_type())?;
+            columns[*idx] = self.merge_column(column)?;
+        }
+
+        let merged_batch = RecordBatch::try_new(batch.schema(), columns)
+            .context("failed to construct RecordBatch in AggregateOperator.")?;
+
+        Ok(merged_batch)
+    }
+}
+
+/// Merge value columns by their own operators, other columns are taken from
+/// the last row.
+#[derive(Debug)]
+pub struct ColumnsMergeOperator {
+    /// Value column indexes and the operator merging them.
+    operators: Vec<(Vec<usize>, MergeOperatorRef)>,
+}
+
+impl ColumnsMergeOperator {
+    pub fn new(operators: Vec<(Vec<usize>, MergeOperatorRef)>) -> Self {
+        Self { operators }
+    }
+}
+
+impl MergeOperator for ColumnsMergeOperator {
+    fn merge(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        assert!(batch.num_rows() > 0);
+
+        let mut columns = batch.slice(batch.num_rows() - 1, 1).columns().to_vec();
+        for (value_idxes, operator) in &self.operators {
+            let merged = operator.merge(batch.clone())?;
+            for idx in value_idxes {
+                columns[*idx] = merged.column(*idx).clone();
+            }
+        }
+
+        let merged_batch = RecordBatch::try_new(batch.schema(), columns)
+            .context("failed to construct RecordBatch in ColumnsMergeOperator.")?;
+
+        Ok(merged_batch)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -157,4 +448,122 @@ mod tests {
 
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_columns_merge_operator() {
+        let operator = build_merge_operator(BTreeMap::from([
+            (LAST_VALUE_OPERATOR, vec![1]),
+            (BYTES_MERGE_OPERATOR, vec![2]),
+        ]))
+        .unwrap();
+
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 11, 11]),
+            ("v1", Int64, vec![2, 7, 4]),
+            ("v2", Binary, vec![b"one", b"two", b"three"])
+        )
+        .unwrap();
+
+        let actual = operator.merge(batch).unwrap();
+        let expected = record_batch!(
+            ("pk1", UInt8, vec![11]),
+            ("v1", Int64, vec![4]),
+            ("v2", Binary, vec![b"onetwothree"])
+        )
+        .unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_register_merge_operator() {
+        let name = "test_register_merge_operator";
+        assert!(find_merge_operator(name).is_none());
+        register_merge_operator(name, Arc::new(|_| Arc::new(LastValueOperator))).unwrap();
+        assert!(find_merge_operator(name).is_some());
+
+        // Names can't be registered twice.
+        assert!(matches!(
+            register_merge_operator(name, Arc::new(|_| Arc::new(LastValueOperator))),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            build_merge_operator(BTreeMap::from([("not_registered", vec![1])])),
+            Err(Error::InvalidSchema(_))
+        ));
+    }
+
+    #[test]
+    fn test_aggregate_operator() {
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 11, 11]),
+            ("v1", Int64, vec![Some(2), None, Some(4)]),
+            ("v2", Float64, vec![1.5, 0.5, 3.0]),
+            ("v3", UInt64, vec![Some(3), None, Some(1)]),
+            ("v4", Binary, vec![b"\x01\x05", b"\x03\x02", b"\x00\x04"])
+        )
+        .unwrap();
+        let testcases = [
+            (
+                AggregateFunc::Sum,
+                1,
+                record_batch!(("v1", Int64, vec![6])).unwrap(),
+            ),
+            (
+                AggregateFunc::Sum,
+                2,
+                record_batch!(("v2", Float64, vec![5.0])).unwrap(),
+            ),
+            (
+                AggregateFunc::Min,
+                1,
+                record_batch!(("v1", Int64, vec![2])).unwrap(),
+            ),
+            (
+                AggregateFunc::Max,
+                2,
+                record_batch!(("v2", Float64, vec![3.0])).unwrap(),
+            ),
+            // Null counts as one row.
+            (
+                AggregateFunc::Counter,
+                3,
+                record_batch!(("v3", UInt64, vec![5])).unwrap(),
+            ),
+            (
+                AggregateFunc::Hll,
+                4,
+                record_batch!(("v4", Binary, vec![b"\x03\x05"])).unwrap(),
+            ),
+        ];
+        for (func, idx, expected) in testcases {
+            let operator = AggregateOperator::new(func, vec![idx]);
+            let actual = operator.merge(batch.clone()).unwrap();
+            assert_eq!(1, actual.num_rows());
+            // Other columns are taken from the last row.
+            assert_eq!(&batch.column(0).slice(2, 1), actual.column(0));
+            assert_eq!(expected.column(0), actual.column(idx), "func:{func:?}");
+        }
+
+        // Unsupported types.
+        for (func, idx) in [
+            (AggregateFunc::Sum, 4),
+            (AggregateFunc::Counter, 2),
+            (AggregateFunc::Hll, 1),
+        ] {
+            let operator = AggregateOperator::new(func, vec![idx]);
+            assert!(matches!(
+                operator.merge(batch.clone()),
+                Err(Error::InvalidSchema(_))
+            ));
+        }
+
+        // Sketches of different sizes can't be merged.
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 11]),
+            ("v1", Binary, vec![b"\x01\x05", b"\x03"])
+        )
+        .unwrap();
+        let operator = AggregateOperator::new(AggregateFunc::Hll, vec![1]);
+        assert!(operator.merge(batch).is_err());
+    }
 }
@@ -0,0 +1,454 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! DataFusion table provider over [`CloudObjectStorage`].
+
+use std::{any::Any, fmt, sync::Arc};
+
+use arrow::datatypes::{Schema, SchemaRef};
+use async_trait::async_trait;
+use datafusion::{
+    catalog::Session,
+    common::{internal_err, ScalarValue},
+    datasource::{TableProvider, TableType},
+    error::{DataFusionError, Result as DfResult},
+    execution::{SendableRecordBatchStream, TaskContext},
+    logical_expr::{BinaryExpr, Operator, TableProviderFilterPushDown},
+    physical_expr::EquivalenceProperties,
+    physical_plan::{
+        stream::RecordBatchStreamAdapter, DisplayAs, DisplayFormatType, ExecutionMode,
+        ExecutionPlan, Partitioning, PlanProperties,
+    },
+    prelude::Expr,
+};
+use futures::{StreamExt, TryStreamExt};
+
+use crate::{
+    storage::{CloudObjectStorage, ScanRequest, TimeMergeStorage},
+    types::{StorageSchema, TimeRange, Timestamp},
+};
+
+/// Table of a [`CloudObjectStorage`], so it can be registered in a
+/// `SessionContext` and queried by SQL.
+///
+/// Builtin columns are hidden. Filters only referencing sort key columns are
+/// pushed down into scan, others are evaluated by DataFusion after rows are
+/// merged.
+pub struct StorageTableProvider {
+    storage: Arc<CloudObjectStorage>,
+}
+
+impl StorageTableProvider {
+    pub fn new(storage: Arc<CloudObjectStorage>) -> Self {
+        Self { storage }
+    }
+
+    /// Whether `expr` can be evaluated before merging, which requires it only
+    /// references sort key columns, whose values are shared by all versions of
+    /// a row. The timestamp column is a value column unless rows are sorted by
+    /// it, so its value may be changed by merging.
+    fn can_push_down(schema: &StorageSchema, expr: &Expr) -> bool {
+        let sort_key_idxes = schema.sort_key_idxes();
+        expr.column_refs().iter().all(|column| {
+            schema
+                .arrow_schema
+                .index_of(&column.name)
+                .is_ok_and(|idx| sort_key_idxes.contains(&idx))
+        })
+    }
+
+    /// Narrow time range by comparisons between timestamp column and
+    /// literals in `filters`.
+    fn time_range(schema: &StorageSchema, filters: &[Expr]) -> TimeRange {
+        let (mut range_start, mut range_end) = (Timestamp::MIN, Timestamp::MAX);
+        let Some(timestamp) = schema.timestamp_column() else {
+            return TimeRange::new(range_start, range_end);
+        };
+        for filter in filters {
+            let Expr::BinaryExpr(BinaryExpr { left, op, right }) = filter else {
+                continue;
+            };
+            let (Expr::Column(column), Expr::Literal(value)) = (left.as_ref(), right.as_ref())
+            else {
+                continue;
+            };
+            let value = match value {
+                ScalarValue::Int64(Some(v)) | ScalarValue::TimestampMillisecond(Some(v), _) => *v,
+                _ => continue,
+            };
+            if column.name != timestamp {
+                continue;
+            }
+            let (start, end) = match op {
+                Operator::Eq => (Some(value), value.checked_add(1)),
+                Operator::Gt => (value.checked_add(1), None),
+                Operator::GtEq => (Some(value), None),
+                Operator::Lt => (None, Some(value)),
+                Operator::LtEq => (None, value.checked_add(1)),
+                _ => continue,
+            };
+            if let Some(start) = start {
+                range_start = range_start.max(Timestamp(start));
+            }
+            if let Some(end) = end {
+                range_end = range_end.min(Timestamp(end));
+            }
+        }
+        TimeRange::new(range_start, range_end)
+    }
+}
+
+impl fmt::Debug for StorageTableProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageTableProvider")
+            .field("schema", &self.storage.schema())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl TableProvider for StorageTableProvider {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        let schema = self.storage.schema();
+        let fields = schema
+            .fields()
+            .iter()
+            .filter(|f| !StorageSchema::is_builtin_field(f))
+            .cloned()
+            .collect::<Vec<_>>();
+        Arc::new(Schema::new_with_metadata(fields, schema.metadata.clone()))
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> DfResult<Vec<TableProviderFilterPushDown>> {
+        let schema = self.storage.storage_schema();
+        Ok(filters
+            .iter()
+            .map(|expr| {
+                // Ssts overlapping with the time range are scanned entirely, so
+                // filters are still evaluated by DataFusion.
+                if Self::can_push_down(&schema, expr) {
+                    TableProviderFilterPushDown::Inexact
+                } else {
+                    TableProviderFilterPushDown::Unsupported
+                }
+            })
+            .collect())
+    }
+
+    async fn scan(
+        &self,
+        _state: &dyn Session,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        limit: Option<usize>,
+    ) -> DfResult<Arc<dyn ExecutionPlan>> {
+        let schema = self.schema();
+        let projected_schema = match projection {
+            Some(projection) => Arc::new(schema.project(projection)?),
+            None => schema.clone(),
+        };
+        let storage_schema = self.storage.storage_schema();
+        let predicate = filters
+            .iter()
+            .filter(|expr| Self::can_push_down(&storage_schema, expr))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(Arc::new(StorageScanExec::new(
+            self.storage.clone(),
+            projected_schema,
+            projection.cloned(),
+            Self::time_range(&storage_schema, &predicate),
+            predicate,
+            limit,
+        )))
+    }
+}
+
+/// Execution plan calling [`TimeMergeStorage::scan`] when executed.
+struct StorageScanExec {
+    storage: Arc<CloudObjectStorage>,
+    projection: Option<Vec<usize>>,
+    range: TimeRange,
+    predicate: Vec<Expr>,
+    limit: Option<usize>,
+    properties: PlanProperties,
+}
+
+impl StorageScanExec {
+    fn new(
+        storage: Arc<CloudObjectStorage>,
+        schema: SchemaRef,
+        projection: Option<Vec<usize>>,
+        range: TimeRange,
+        predicate: Vec<Expr>,
+        limit: Option<usize>,
+    ) -> Self {
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(schema),
+            Partitioning::UnknownPartitioning(1),
+            ExecutionMode::Bounded,
+        );
+        Self {
+            storage,
+            projection,
+            range,
+            predicate,
+            limit,
+            properties,
+        }
+    }
+}
+
+impl fmt::Debug for StorageScanExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageScanExec")
+            .field("projection", &self.projection)
+            .field("range", &self.range)
+            .field("predicate", &self.predicate)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl DisplayAs for StorageScanExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StorageScanExec: [range: {:?}, projection: {:?}, predicate: {:?}, limit: {:?}]",
+            self.range, self.projection, self.predicate, self.limit
+        )
+    }
+}
+
+impl ExecutionPlan for StorageScanExec {
+    fn name(&self) -> &str {
+        "StorageScanExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> DfResult<Arc<dyn ExecutionPlan>> {
+        if !children.is_empty() {
+            return internal_err!("StorageScanExec has no children");
+        }
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> DfResult<SendableRecordBatchStream> {
+        if 0 != partition {
+            return internal_err!("StorageScanExec invalid partition {partition}");
+        }
+
+        let storage = self.storage.clone();
+        let req = ScanRequest {
+            range: self.range.clone(),
+            predicate: self.predicate.clone(),
+            projections: self.projection.clone(),
+            limit: self.limit,
+            ..Default::default()
+        };
+        let schema = self.schema();
+        let output_schema = schema.clone();
+        let stream = futures::stream::once(async move {
+            storage
+                .scan(req)
+                .await
+                .map_err(|e| DataFusionError::External(Box::new(e)))
+        })
+        .try_flatten()
+        // Columns required by scan are appended to projection, they are
+        // removed here.
+        .map(move |batch| {
+            let batch = batch?;
+            let indices = output_schema
+                .fields()
+                .iter()
+                .map(|f| batch.schema().index_of(f.name()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(batch.project(&indices)?)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use datafusion::{
+        assert_batches_eq,
+        logical_expr::{col, lit},
+        prelude::SessionContext,
+    };
+    use object_store::local::LocalFileSystem;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::{
+        arrow_schema,
+        config::{StorageConfig, UpdateMode},
+        record_batch,
+        storage::{StorageRuntimes, WriteRequest},
+    };
+
+    #[test]
+    fn test_time_range() {
+        let schema = StorageSchema::try_new(
+            arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64)),
+            1,
+            Some("ts"),
+            UpdateMode::Overwrite,
+        )
+        .unwrap();
+        let filters = [
+            col("ts").gt_eq(lit(10_i64)),
+            col("ts").lt_eq(lit(20_i64)),
+            col("ts").lt(lit(30_i64)),
+            col("pk1").eq(lit(1_u8)),
+        ];
+        // Timestamp is a value column unless rows are sorted by it.
+        assert!(!StorageTableProvider::can_push_down(&schema, &filters[0]));
+        let schema = schema.with_sort_by_timestamp(true).unwrap();
+        assert!(StorageTableProvider::can_push_down(&schema, &filters[0]));
+        assert_eq!(
+            TimeRange::from(10..21),
+            StorageTableProvider::time_range(&schema, &filters)
+        );
+        assert!(StorageTableProvider::can_push_down(&schema, &filters[3]));
+        assert!(!StorageTableProvider::can_push_down(
+            &schema,
+            &col("value").eq(lit(1_i64))
+        ));
+    }
+
+    #[test]
+    fn test_sql_query() {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let rt = Arc::new(Runtime::new().unwrap());
+        let runtimes = StorageRuntimes::new(rt.clone(), rt.clone(), rt.clone());
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        rt.block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for batch in [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2, 3]),
+                    ("ts", Int64, vec![1, 2, 3]),
+                    ("value", Int64, vec![10, 20, 30])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![2]),
+                    ("ts", Int64, vec![2]),
+                    ("value", Int64, vec![25])
+                )
+                .unwrap(),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range: (1..4).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let ctx = SessionContext::new();
+            ctx.register_table(
+                "metrics",
+                Arc::new(StorageTableProvider::new(Arc::new(storage))),
+            )
+            .unwrap();
+            let query = |sql: &'static str| {
+                let ctx = ctx.clone();
+                async move { ctx.sql(sql).await.unwrap().collect().await.unwrap() }
+            };
+
+            // Filter on value column is evaluated after merging.
+            let batches =
+                query("SELECT pk1, value FROM metrics WHERE ts >= 2 AND value > 20 ORDER BY pk1")
+                    .await;
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 2   | 25    |",
+                    "| 3   | 30    |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+
+            let batches = query("SELECT count(*) AS num FROM metrics").await;
+            assert_batches_eq!(
+                ["+-----+", "| num |", "+-----+", "| 3   |", "+-----+",],
+                &batches
+            );
+
+            let batches = query("SELECT pk1 FROM metrics LIMIT 2").await;
+            assert_batches_eq!(
+                ["+-----+", "| pk1 |", "+-----+", "| 1   |", "| 2   |", "+-----+",],
+                &batches
+            );
+        });
+    }
+}
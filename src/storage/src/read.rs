@@ -15,16 +15,14 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{any::Any, pin::Pin, sync::Arc, task::Poll};
+use std::{any::Any, cmp::Ordering, collections::BTreeMap, pin::Pin, sync::Arc, task::Poll};
 
 use anyhow::Context;
 use arrow::{
-    array::{AsArray, RecordBatch},
-    compute::concat_batches,
-    datatypes::{
-        GenericBinaryType, Int32Type, Int64Type, Int8Type, Schema, SchemaRef, UInt32Type,
-        UInt64Type, UInt8Type,
-    },
+    array::{AsArray, RecordBatch, UInt64Array},
+    compute::{concat_batches, filter_record_batch, kernels::cmp::lt_eq},
+    datatypes::{Schema, SchemaRef, UInt64Type},
+    row::{RowConverter, Rows, SortField},
 };
 use datafusion::{
     common::{internal_err, DFSchema},
@@ -39,11 +37,13 @@ use datafusion::{
     },
     logical_expr::utils::conjunction,
     parquet::arrow::async_reader::AsyncFileReader,
-    physical_expr::{create_physical_expr, LexOrdering},
+    physical_expr::{
+        create_physical_expr, expressions::Column, EquivalenceProperties, LexOrdering, PhysicalExpr,
+    },
     physical_plan::{
-        filter::FilterExec, metrics::ExecutionPlanMetricsSet,
-        sorts::sort_preserving_merge::SortPreservingMergeExec, DisplayAs, Distribution,
-        ExecutionPlan, PlanProperties,
+        filter::FilterExec, memory::MemoryExec, metrics::ExecutionPlanMetricsSet,
+        projection::ProjectionExec, sorts::sort_preserving_merge::SortPreservingMergeExec,
+        union::UnionExec, DisplayAs, Distribution, ExecutionPlan, PlanProperties,
     },
     physical_planner::create_physical_sort_exprs,
     prelude::{ident, Expr},
@@ -53,12 +53,12 @@ use itertools::Itertools;
 use parquet::arrow::async_reader::ParquetObjectReader;
 
 use crate::{
-    compare_primitive_columns,
-    config::UpdateMode,
-    operator::{BytesMergeOperator, LastValueOperator, MergeOperator, MergeOperatorRef},
+    ensure,
+    operator::{self, MergeOperator, MergeOperatorRef},
     sst::{SstFile, SstPathGenerator},
     types::{
-        ObjectStoreRef, StorageSchema, BUILTIN_COLUMN_NUM, RESERVED_COLUMN_NAME, SEQ_COLUMN_NAME,
+        ObjectStoreRef, RowKind, SharedSchema, StorageSchema, BUILTIN_COLUMN_NUM,
+        RESERVED_COLUMN_NAME, SEQ_COLUMN_NAME,
     },
     Result,
 };
@@ -94,32 +94,54 @@ impl ParquetFileReaderFactory for DefaultParquetFileReaderFactory {
 
 /// Execution plan for merge RecordBatch values, like Merge Operator in RocksDB.
 ///
-/// Input record batches are sorted by the primary key columns and seq
-/// column.
+/// Input record batches are sorted by the sort key columns and seq column.
+/// Sort key is the primary keys, optionally followed by the timestamp column,
+/// see [`StorageSchema::sort_key_idxes`].
+///
+/// Rows older than a tombstone with the same primary keys are dropped, the
+/// tombstone itself is only kept when builtin columns are kept, since it may
+/// still need to delete rows not in the input, such as in compaction.
+///
+/// Rows with sequence larger than `max_sequence` are ignored, so the output is
+/// the merged state as of that sequence.
 #[derive(Debug)]
 pub(crate) struct MergeExec {
     /// Input plan
     input: Arc<dyn ExecutionPlan>,
-    /// (0..num_primary_keys) are primary key columns
-    num_primary_keys: usize,
-    /// Operator to merge values when primary keys are the same
+    /// Indexes of sort key columns
+    sort_key_idxes: Vec<usize>,
+    /// Operator to merge values when sort keys are the same
     value_operator: Arc<dyn MergeOperator>,
     /// Whether to keep the builtin columns in the output
     keep_builtin: bool,
+    /// Max sequence of rows to merge, `None` means all rows
+    max_sequence: Option<u64>,
+    /// Properties of the output, builtin columns are removed from the schema
+    /// unless they are kept
+    properties: PlanProperties,
 }
 
 impl MergeExec {
     pub fn new(
         input: Arc<dyn ExecutionPlan>,
-        num_primary_keys: usize,
+        sort_key_idxes: Vec<usize>,
         value_operator: Arc<dyn MergeOperator>,
         keep_builtin: bool,
+        max_sequence: Option<u64>,
     ) -> Self {
+        let input_props = input.properties();
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(MergeStream::output_schema(input.schema(), keep_builtin)),
+            input_props.output_partitioning().clone(),
+            input_props.execution_mode(),
+        );
         Self {
             input,
-            num_primary_keys,
+            sort_key_idxes,
             value_operator,
             keep_builtin,
+            max_sequence,
+            properties,
         }
     }
 }
@@ -131,9 +153,12 @@ impl DisplayAs for MergeExec {
     ) -> std::fmt::Result {
         write!(
             f,
-            "MergeExec: [primary_keys: {}, keep_builtin: {}]",
-            self.num_primary_keys, self.keep_builtin
+            "MergeExec: [sort_keys: {:?}, keep_builtin: {}]",
+            self.sort_key_idxes, self.keep_builtin
         )?;
+        if let Some(max_sequence) = self.max_sequence {
+            write!(f, ", max_sequence: {max_sequence}")?;
+        }
         Ok(())
     }
 }
@@ -148,7 +173,7 @@ impl ExecutionPlan for MergeExec {
     }
 
     fn properties(&self) -> &PlanProperties {
-        self.input.properties()
+        &self.properties
     }
 
     fn required_input_distribution(&self) -> Vec<Distribution> {
@@ -169,9 +194,10 @@ impl ExecutionPlan for MergeExec {
     ) -> DfResult<Arc<dyn ExecutionPlan>> {
         Ok(Arc::new(MergeExec::new(
             Arc::clone(&children[0]),
-            self.num_primary_keys,
+            self.sort_key_idxes.clone(),
             self.value_operator.clone(),
             self.keep_builtin,
+            self.max_sequence,
         )))
     }
 
@@ -184,34 +210,60 @@ impl ExecutionPlan for MergeExec {
             return internal_err!("MergeExec invalid partition {partition}");
         }
 
-        Ok(Box::pin(MergeStream::new(
+        let stream = MergeStream::try_new(
             self.input.execute(partition, context)?,
-            self.num_primary_keys,
+            self.sort_key_idxes.clone(),
             self.value_operator.clone(),
             self.keep_builtin,
-        )))
+            self.max_sequence,
+        )
+        .map_err(|e| DataFusionError::External(Box::new(e)))?;
+        Ok(Box::pin(stream))
     }
 }
 
 struct MergeStream {
     stream: SendableRecordBatchStream,
-    num_primary_keys: usize,
+    sort_key_idxes: Vec<usize>,
     value_operator: MergeOperatorRef,
     keep_builtin: bool,
+    max_sequence: Option<u64>,
+    /// Converts sort key columns to rows, which are compared as bytes.
+    converter: RowConverter,
 
     pending_batch: Option<RecordBatch>,
     arrow_schema: SchemaRef,
 }
 
 impl MergeStream {
-    fn new(
+    fn try_new(
         stream: SendableRecordBatchStream,
-        num_primary_keys: usize,
+        sort_key_idxes: Vec<usize>,
         value_operator: MergeOperatorRef,
         keep_builtin: bool,
-    ) -> Self {
-        let arrow_schema = if keep_builtin {
-            let schema = stream.schema();
+        max_sequence: Option<u64>,
+    ) -> Result<Self> {
+        let input_schema = stream.schema();
+        let sort_fields = sort_key_idxes
+            .iter()
+            .map(|idx| SortField::new(input_schema.field(*idx).data_type().clone()))
+            .collect();
+        let converter = RowConverter::new(sort_fields).context("create row converter")?;
+        let arrow_schema = Self::output_schema(input_schema, keep_builtin);
+        Ok(Self {
+            stream,
+            sort_key_idxes,
+            value_operator,
+            keep_builtin,
+            max_sequence,
+            converter,
+            pending_batch: None,
+            arrow_schema,
+        })
+    }
+
+    fn output_schema(schema: SchemaRef, keep_builtin: bool) -> SchemaRef {
+        if keep_builtin {
             let found_seq = schema.fields().iter().any(|f| f.name() == SEQ_COLUMN_NAME);
             assert!(found_seq, "Sequence column not found");
             let found_reserved = schema
@@ -221,8 +273,7 @@ impl MergeStream {
             assert!(found_reserved, "Reserved column not found");
             schema
         } else {
-            let fields = stream
-                .schema()
+            let fields = schema
                 .fields()
                 .into_iter()
                 .filter_map(|f| {
@@ -233,18 +284,7 @@ impl MergeStream {
                     }
                 })
                 .collect_vec();
-            Arc::new(Schema::new_with_metadata(
-                fields,
-                stream.schema().metadata.clone(),
-            ))
-        };
-        Self {
-            stream,
-            num_primary_keys,
-            value_operator,
-            keep_builtin,
-            pending_batch: None,
-            arrow_schema,
+            Arc::new(Schema::new_with_metadata(fields, schema.metadata.clone()))
         }
     }
 
@@ -259,46 +299,74 @@ impl MergeStream {
         }
     }
 
-    fn primary_key_eq(
-        &self,
-        lhs: &RecordBatch,
-        lhs_idx: usize,
-        rhs: &RecordBatch,
-        rhs_idx: usize,
-    ) -> bool {
-        for k in 0..self.num_primary_keys {
-            let lhs_col = lhs.column(k);
-            let rhs_col = rhs.column(k);
-
-            compare_primitive_columns!(
-                lhs_col, rhs_col, lhs_idx, rhs_idx, // TODO: Add more types here
-                UInt8Type, Int8Type, UInt32Type, Int32Type, UInt64Type, Int64Type
-            );
+    /// Convert sort key columns of `batch` to rows, rows are equal iff their
+    /// sort keys are equal.
+    fn sort_keys(&self, batch: &RecordBatch) -> Result<Rows> {
+        let columns = self
+            .sort_key_idxes
+            .iter()
+            .map(|idx| batch.column(*idx).clone())
+            .collect::<Vec<_>>();
+        let rows = self
+            .converter
+            .convert_columns(&columns)
+            .context("convert sort key columns")?;
+        Ok(rows)
+    }
 
-            if let Some(lhs_col) = lhs_col.as_bytes_opt::<GenericBinaryType<i32>>() {
-                let rhs_col = rhs_col.as_bytes::<GenericBinaryType<i32>>();
-                if !rhs_col.value(rhs_idx).eq(lhs_col.value(lhs_idx)) {
-                    return false;
-                }
-            }
+    /// Merge rows with the same primary keys, and apply tombstones in them.
+    fn merge_rows(&self, rows: RecordBatch) -> Result<Vec<RecordBatch>> {
+        // Reserved column is always the last one.
+        let reserved = rows
+            .column(rows.num_columns() - 1)
+            .as_primitive::<UInt64Type>();
+        let Some(tombstone_idx) = (0..rows.num_rows())
+            .rev()
+            .find(|idx| RowKind::from_reserved(reserved, *idx) == RowKind::Delete)
+        else {
+            return Ok(vec![self.value_operator.merge(rows)?]);
+        };
+
+        let mut merged = Vec::with_capacity(2);
+        if self.keep_builtin {
+            merged.push(rows.slice(tombstone_idx, 1));
+        }
+        let num_puts = rows.num_rows() - tombstone_idx - 1;
+        if num_puts > 0 {
+            let puts = rows.slice(tombstone_idx + 1, num_puts);
+            merged.push(self.value_operator.merge(puts)?);
         }
 
-        true
+        Ok(merged)
+    }
+
+    /// Remove rows with sequence larger than `max_sequence`.
+    fn filter_by_sequence(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        let Some(max_sequence) = self.max_sequence else {
+            return Ok(batch);
+        };
+        let sequences = batch
+            .column_by_name(SEQ_COLUMN_NAME)
+            .context("sequence column not found")?;
+        let visible =
+            lt_eq(sequences, &UInt64Array::new_scalar(max_sequence)).context("compare sequence")?;
+        let batch = filter_record_batch(&batch, &visible).context("filter by sequence")?;
+        Ok(batch)
     }
 
     fn merge_batch(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+        let batch = self.filter_by_sequence(batch)?;
         if batch.num_rows() == 0 {
             return Ok(None);
         }
 
         // Group rows with the same primary keys
+        let keys = self.sort_keys(&batch)?;
         let mut groupby_pk_batches = Vec::new();
         let mut start_idx = 0;
         while start_idx < batch.num_rows() {
             let mut end_idx = start_idx + 1;
-            while end_idx < batch.num_rows()
-                && self.primary_key_eq(&batch, start_idx, &batch, end_idx)
-            {
+            while end_idx < batch.num_rows() && keys.row(start_idx) == keys.row(end_idx) {
                 end_idx += 1;
             }
             groupby_pk_batches.push(batch.slice(start_idx, end_idx - start_idx));
@@ -308,19 +376,15 @@ impl MergeStream {
         let rows_with_same_primary_keys = &groupby_pk_batches[0];
         let mut output_batches = Vec::new();
         if let Some(pending) = self.pending_batch.take() {
-            if self.primary_key_eq(
-                &pending,
-                pending.num_rows() - 1,
-                rows_with_same_primary_keys,
-                0,
-            ) {
+            let pending_keys = self.sort_keys(&pending.slice(pending.num_rows() - 1, 1))?;
+            if pending_keys.row(0) == keys.row(0) {
                 groupby_pk_batches[0] = concat_batches(
                     &self.stream.schema(),
                     [&pending, rows_with_same_primary_keys],
                 )
                 .context("concat batch")?;
             } else {
-                output_batches.push(self.value_operator.merge(pending)?);
+                output_batches.extend(self.merge_rows(pending)?);
             }
         }
 
@@ -329,7 +393,7 @@ impl MergeStream {
         self.pending_batch = groupby_pk_batches.pop();
 
         for batch in groupby_pk_batches {
-            output_batches.push(self.value_operator.merge(batch)?);
+            output_batches.extend(self.merge_rows(batch)?);
         }
         if output_batches.is_empty() {
             return Ok(None);
@@ -354,17 +418,25 @@ impl Stream for MergeStream {
             match self.stream.poll_next_unpin(ctx) {
                 Poll::Pending => return Poll::Pending,
                 Poll::Ready(None) => {
-                    let value = if let Some(mut pending) = self.pending_batch.take() {
-                        self.maybe_remove_builtin_columns(&mut pending);
-                        let res = self
-                            .value_operator
-                            .merge(pending)
-                            .map_err(|e| DataFusionError::External(Box::new(e)));
-                        Some(res)
-                    } else {
-                        None
+                    let Some(pending) = self.pending_batch.take() else {
+                        return Poll::Ready(None);
+                    };
+                    let merged = match self.merge_rows(pending) {
+                        Ok(merged) => merged,
+                        Err(e) => {
+                            return Poll::Ready(Some(Err(DataFusionError::External(Box::new(e)))))
+                        }
                     };
-                    return Poll::Ready(value);
+                    if merged.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    let res = concat_batches(&self.stream.schema(), merged.iter())
+                        .map(|mut batch| {
+                            self.maybe_remove_builtin_columns(&mut batch);
+                            batch
+                        })
+                        .map_err(|e| DataFusionError::ArrowError(e, None));
+                    return Poll::Ready(Some(res));
                 }
                 Poll::Ready(Some(v)) => match v {
                     Ok(v) => match self.merge_batch(v) {
@@ -392,14 +464,14 @@ impl RecordBatchStream for MergeStream {
 
 pub struct ParquetReader {
     store: ObjectStoreRef,
-    schema: StorageSchema,
+    schema: SharedSchema,
     sst_path_gen: Arc<SstPathGenerator>,
 }
 
 impl ParquetReader {
     pub fn new(
         store: ObjectStoreRef,
-        schema: StorageSchema,
+        schema: SharedSchema,
         sst_path_gen: Arc<SstPathGenerator>,
     ) -> Self {
         Self {
@@ -409,10 +481,87 @@ impl ParquetReader {
         }
     }
 
-    fn build_sort_exprs(&self, df_schema: &DFSchema, sort_seq: bool) -> Result<LexOrdering> {
-        let mut sort_exprs = (0..self.schema.num_primary_keys)
+    /// Returns the order to read `ssts` by concatenation without merging, it's
+    /// only possible when rows of each sst are deduplicated, and primary key
+    /// ranges of ssts don't overlap.
+    fn concat_order(schema: &StorageSchema, ssts: &[SstFile]) -> Option<Vec<usize>> {
+        if ssts.is_empty() || !ssts.iter().all(|f| f.meta().deduplicated) {
+            return None;
+        }
+        if ssts.len() == 1 {
+            return Some(vec![0]);
+        }
+
+        let mut bounds = ssts
+            .iter()
+            .enumerate()
+            .map(|(idx, f)| {
+                let (min, max) = f.meta().primary_key_stats.as_ref()?.bounds(schema)?;
+                Some((idx, min, max))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        bounds.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        // Values of different types are not comparable, which are regarded as
+        // overlapping.
+        let disjoint = bounds.windows(2).all(|w| w[0].2 < w[1].1);
+        disjoint.then(|| bounds.into_iter().map(|(idx, _, _)| idx).collect())
+    }
+
+    /// Build operator to merge value columns in `projected_schema` by their
+    /// own operators.
+    fn build_merge_operator(
+        schema: &StorageSchema,
+        projected_schema: &SchemaRef,
+    ) -> Result<MergeOperatorRef> {
+        let mut operators = BTreeMap::new();
+        for idx in &schema.value_idxes {
+            let name = schema.merge_operator(*idx);
+            let value_idxes: &mut Vec<_> = operators.entry(name).or_default();
+            if let Ok(projected_idx) =
+                projected_schema.index_of(schema.arrow_schema.field(*idx).name())
+            {
+                value_idxes.push(projected_idx);
+            }
+        }
+
+        operator::build_merge_operator(operators)
+    }
+
+    /// Remove builtin columns unless they are kept, like [`MergeExec`] does.
+    fn remove_builtin_columns(
+        input: Arc<dyn ExecutionPlan>,
+        keep_builtin: bool,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if keep_builtin {
+            return Ok(input);
+        }
+
+        let exprs = input
+            .schema()
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !StorageSchema::is_builtin_field(f))
+            .map(|(idx, f)| {
+                let expr: Arc<dyn PhysicalExpr> = Arc::new(Column::new(f.name(), idx));
+                (expr, f.name().clone())
+            })
+            .collect();
+        let projection_exec =
+            ProjectionExec::try_new(exprs, input).context("create projection exec")?;
+        Ok(Arc::new(projection_exec))
+    }
+
+    fn build_sort_exprs(
+        schema: &StorageSchema,
+        df_schema: &DFSchema,
+        sort_seq: bool,
+    ) -> Result<LexOrdering> {
+        let mut sort_exprs = schema
+            .sort_key_idxes()
+            .into_iter()
             .map(|i| {
-                ident(self.schema.arrow_schema.field(i).name())
+                ident(schema.arrow_schema.field(i).name())
                     .sort(true /* asc */, true /* nulls_first */)
             })
             .collect::<Vec<_>>();
@@ -426,86 +575,198 @@ impl ParquetReader {
         Ok(sort_exprs)
     }
 
+    /// Build plan to read and merge `ssts` and `mem_batches` of one segment.
+    ///
+    /// `mem_batches` come from memtables, each of them should be sorted and
+    /// contain builtin columns.
+    ///
+    /// Only rows with sequence not larger than `max_sequence` are read when
+    /// it's set.
     pub fn build_df_plan(
         &self,
         ssts: Vec<SstFile>,
+        mem_batches: Vec<RecordBatch>,
         projection: Option<Vec<usize>>,
         predicates: Vec<Expr>,
         keep_builtin: bool,
+        max_sequence: Option<u64>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
+        // Ssts of older schema versions are projected onto the latest one by
+        // ParquetExec, and memtable batches are projected here.
+        let schema = self.schema.load();
         // we won't use url for selecting object_store.
         let dummy_url = ObjectStoreUrl::parse("empty://").unwrap();
         let df_schema =
-            DFSchema::try_from(self.schema.arrow_schema.clone()).context("build DFSchema")?;
-        let sort_exprs = self.build_sort_exprs(&df_schema, true /* sort_seq */)?;
+            DFSchema::try_from(schema.arrow_schema.clone()).context("build DFSchema")?;
+        let sort_exprs = Self::build_sort_exprs(&schema, &df_schema, true /* sort_seq */)?;
+        // Plans above inputs are built against the projected schema, columns
+        // required by merging should be projected.
+        let projected_schema = match &projection {
+            Some(projection) => Arc::new(
+                schema
+                    .arrow_schema
+                    .project(projection)
+                    .context("project schema")?,
+            ),
+            None => schema.arrow_schema.clone(),
+        };
+        let projected_df_schema =
+            DFSchema::try_from(projected_schema.clone()).context("build projected DFSchema")?;
+        let merge_sort_exprs =
+            Self::build_sort_exprs(&schema, &projected_df_schema, true /* sort_seq */)?;
+        let projected_idxes = |idxes: Vec<usize>| {
+            idxes
+                .into_iter()
+                .filter_map(|i| {
+                    projected_schema
+                        .index_of(schema.arrow_schema.field(i).name())
+                        .ok()
+                })
+                .collect::<Vec<_>>()
+        };
 
-        let file_groups = ssts
-            .into_iter()
-            .map(|f| {
-                vec![PartitionedFile::new(
-                    self.sst_path_gen.generate(f.id()),
-                    f.meta().size as u64,
-                )]
-            })
-            .collect::<Vec<_>>();
-        let scan_config = FileScanConfig::new(dummy_url, self.schema.arrow_schema.clone())
-            .with_output_ordering(vec![sort_exprs.clone(); file_groups.len()])
-            .with_file_groups(file_groups)
-            .with_projection(projection);
+        // Memtable batches may contain duplicated rows, so they always need
+        // merging, and so do ssts when rows newer than `max_sequence` should be
+        // ignored by merging.
+        let concat_order = if mem_batches.is_empty() && max_sequence.is_none() {
+            Self::concat_order(&schema, &ssts)
+        } else {
+            None
+        };
 
-        let mut builder = ParquetExec::builder(scan_config).with_parquet_file_reader_factory(
-            Arc::new(DefaultParquetFileReaderFactory::new(self.store.clone())),
-        );
-        let base_plan: Arc<dyn ExecutionPlan> = match conjunction(predicates) {
-            Some(expr) => {
-                let filters = create_physical_expr(&expr, &df_schema, &ExecutionProps::new())
-                    .context("create physical expr")?;
+        let mut inputs: Vec<Arc<dyn ExecutionPlan>> = Vec::new();
+        // All versions of a row, including tombstones, share the same sort
+        // keys, so predicates on them can filter rows before merging. Others
+        // are evaluated on merged rows, otherwise an older version could
+        // match while the latest one doesn't, or the other way around.
+        let (predicates, merged_predicates): (Vec<_>, Vec<_>) = if concat_order.is_some() {
+            (predicates, Vec::new())
+        } else {
+            let sort_keys = schema
+                .sort_key_idxes()
+                .into_iter()
+                .map(|idx| schema.arrow_schema.field(idx).name())
+                .collect::<Vec<_>>();
+            predicates.into_iter().partition(|expr| {
+                expr.column_refs()
+                    .iter()
+                    .all(|column| sort_keys.contains(&&column.name))
+            })
+        };
+        let predicate = conjunction(predicates);
+        // Predicate of ParquetExec is used to prune by statistics of the full
+        // schema, while the filter is applied on projected rows.
+        let (predicate, filter) = match predicate {
+            Some(expr) => (
+                Some(
+                    create_physical_expr(&expr, &df_schema, &ExecutionProps::new())
+                        .context("create physical expr")?,
+                ),
+                Some(
+                    create_physical_expr(&expr, &projected_df_schema, &ExecutionProps::new())
+                        .context("create physical filter expr")?,
+                ),
+            ),
+            None => (None, None),
+        };
+        if !ssts.is_empty() {
+            let partitioned_file = |f: &SstFile| {
+                PartitionedFile::new(self.sst_path_gen.generate(f.id()), f.meta().size as u64)
+            };
+            let mut scan_config = FileScanConfig::new(dummy_url, schema.arrow_schema.clone())
+                .with_projection(projection.clone());
+            scan_config = match &concat_order {
+                // Files in one group are read one by one. The ordering isn't
+                // set since ParquetExec can't prove it without statistics.
+                Some(order) => scan_config.with_file_groups(vec![order
+                    .iter()
+                    .map(|idx| partitioned_file(&ssts[*idx]))
+                    .collect()]),
+                None => scan_config
+                    .with_output_ordering(vec![sort_exprs.clone(); ssts.len()])
+                    .with_file_groups(ssts.iter().map(|f| vec![partitioned_file(f)]).collect()),
+            };
 
-                builder = builder.with_predicate(filters.clone());
-                let parquet_exec = builder.build();
+            let mut builder = ParquetExec::builder(scan_config).with_parquet_file_reader_factory(
+                Arc::new(DefaultParquetFileReaderFactory::new(self.store.clone())),
+            );
+            if let Some(predicate) = &predicate {
+                builder = builder.with_predicate(predicate.clone());
+            }
+            inputs.push(Arc::new(builder.build()));
+        }
+        if !mem_batches.is_empty() {
+            let partitions = mem_batches
+                .into_iter()
+                .map(|batch| Ok(vec![schema.project_batch(batch)?]))
+                .collect::<Result<Vec<_>>>()?;
+            let memory_exec =
+                MemoryExec::try_new(&partitions, schema.arrow_schema.clone(), projection)
+                    .context("create memory exec")?
+                    .try_with_sort_information(vec![sort_exprs.clone()])
+                    .context("set memory exec ordering")?;
+            inputs.push(Arc::new(memory_exec));
+        }
 
-                let filter_exec = FilterExec::try_new(filters, Arc::new(parquet_exec))
-                    .context("create filter exec")?;
+        ensure!(!inputs.is_empty(), "no ssts or memtables to read");
+        let input = if inputs.len() == 1 {
+            inputs.remove(0)
+        } else {
+            Arc::new(UnionExec::new(inputs))
+        };
+        let base_plan: Arc<dyn ExecutionPlan> = match filter {
+            Some(filter) => {
+                let filter_exec =
+                    FilterExec::try_new(filter, input).context("create filter exec")?;
                 Arc::new(filter_exec)
             }
-            None => {
-                let parquet_exec = builder.build();
-                Arc::new(parquet_exec)
-            }
+            None => input,
         };
+        if concat_order.is_some() {
+            return Self::remove_builtin_columns(base_plan, keep_builtin);
+        }
 
-        // TODO: fetch using multiple threads since read from parquet will incur CPU
-        // when convert between arrow and parquet.
-        let sort_exec =
-            SortPreservingMergeExec::new(sort_exprs, base_plan).with_round_robin_repartition(true);
+        // Each sst is read in its own task spawned by the merge, so they are
+        // decoded concurrently in the runtime executing the plan.
+        let sort_exec = SortPreservingMergeExec::new(merge_sort_exprs, base_plan)
+            .with_round_robin_repartition(true);
 
         let merge_exec = MergeExec::new(
             Arc::new(sort_exec),
-            self.schema.num_primary_keys,
-            match self.schema.update_mode {
-                UpdateMode::Overwrite => Arc::new(LastValueOperator),
-                UpdateMode::Append => {
-                    Arc::new(BytesMergeOperator::new(self.schema.value_idxes.clone()))
-                }
-            },
+            projected_idxes(schema.sort_key_idxes()),
+            Self::build_merge_operator(&schema, &projected_schema)?,
             keep_builtin,
+            max_sequence,
         );
-        Ok(Arc::new(merge_exec))
+        let Some(expr) = conjunction(merged_predicates) else {
+            return Ok(Arc::new(merge_exec));
+        };
+        let merged_df_schema =
+            DFSchema::try_from(merge_exec.schema()).context("build merged DFSchema")?;
+        let filter = create_physical_expr(&expr, &merged_df_schema, &ExecutionProps::new())
+            .context("create merged filter expr")?;
+        let filter_exec =
+            FilterExec::try_new(filter, Arc::new(merge_exec)).context("create filter exec")?;
+        Ok(Arc::new(filter_exec))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use datafusion::logical_expr::{col, lit};
+    use datafusion::{
+        common::ScalarValue,
+        logical_expr::{col, lit},
+    };
     use object_store::local::LocalFileSystem;
     use test_log::test;
 
     use super::*;
     use crate::{
         arrow_schema,
+        config::UpdateMode,
         operator::{BytesMergeOperator, LastValueOperator, MergeOperatorRef},
         record_batch,
-        sst::FileMeta,
+        sst::{FileMeta, PrimaryKeyStats},
         test_util::{check_stream, make_sendable_record_batches},
     };
 
@@ -545,30 +806,155 @@ mod tests {
                 ("pk1", UInt8, vec![11, 11, 12, 12, 13]),
                 ("value", Binary, vec![b"1", b"2", b"3", b"4", b"5"]),
                 (SEQ_COLUMN_NAME, UInt8, vec![1, 2, 3, 4, 5]),
-                (RESERVED_COLUMN_NAME, UInt8, vec![None; 5])
+                (RESERVED_COLUMN_NAME, UInt64, vec![None; 5])
             )
             .unwrap(),
             record_batch!(
                 ("pk1", UInt8, vec![13, 13]),
                 ("value", Binary, vec![b"6", b"7"]),
                 (SEQ_COLUMN_NAME, UInt8, vec![6, 7]),
-                (RESERVED_COLUMN_NAME, UInt8, vec![None; 2])
+                (RESERVED_COLUMN_NAME, UInt64, vec![None; 2])
             )
             .unwrap(),
             record_batch!(
                 ("pk1", UInt8, vec![13, 14]),
                 ("value", Binary, vec![b"8", b"9"]),
                 (SEQ_COLUMN_NAME, UInt8, vec![8, 9]),
-                (RESERVED_COLUMN_NAME, UInt8, vec![None; 2])
+                (RESERVED_COLUMN_NAME, UInt64, vec![None; 2])
             )
             .unwrap(),
         ]);
 
-        let stream = MergeStream::new(
-            stream, 1,        // num_primary_keys
+        let stream = MergeStream::try_new(
+            stream,
+            vec![0],  // sort_key_idxes
             merge_op, // merge_operator
             false,    // keep_builtin
-        );
+            None,     // max_sequence
+        )
+        .unwrap();
+        check_stream(Box::pin(stream), expected).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_merge_stream_with_tombstone() {
+        let build_stream = || {
+            make_sendable_record_batches([record_batch!(
+                ("pk1", UInt8, vec![11, 11, 12, 12, 12, 13]),
+                ("value", Binary, vec![b"1", b"2", b"3", b"4", b"5", b"6"]),
+                (SEQ_COLUMN_NAME, UInt64, vec![1, 2, 3, 4, 5, 6]),
+                (
+                    RESERVED_COLUMN_NAME,
+                    UInt64,
+                    vec![None, Some(1), None, Some(1), None, Some(1)]
+                )
+            )
+            .unwrap()])
+        };
+
+        // Rows before tombstones are deleted.
+        let stream = MergeStream::try_new(
+            build_stream(),
+            vec![0],
+            Arc::new(LastValueOperator),
+            false,
+            None,
+        )
+        .unwrap();
+        let expected =
+            [record_batch!(("pk1", UInt8, vec![12]), ("value", Binary, vec![b"5"])).unwrap()];
+        check_stream(Box::pin(stream), expected).await;
+
+        // Tombstones are kept along with builtin columns.
+        let stream = MergeStream::try_new(
+            build_stream(),
+            vec![0],
+            Arc::new(LastValueOperator),
+            true,
+            None,
+        )
+        .unwrap();
+        let expected = [
+            record_batch!(
+                ("pk1", UInt8, vec![11, 12, 12]),
+                ("value", Binary, vec![b"2", b"4", b"5"]),
+                (SEQ_COLUMN_NAME, UInt64, vec![2, 4, 5]),
+                (RESERVED_COLUMN_NAME, UInt64, vec![Some(1), Some(1), None])
+            )
+            .unwrap(),
+            record_batch!(
+                ("pk1", UInt8, vec![13]),
+                ("value", Binary, vec![b"6"]),
+                (SEQ_COLUMN_NAME, UInt64, vec![6]),
+                (RESERVED_COLUMN_NAME, UInt64, vec![Some(1)])
+            )
+            .unwrap(),
+        ];
+        check_stream(Box::pin(stream), expected).await;
+
+        // Rows and tombstones newer than max sequence are ignored.
+        let stream = MergeStream::try_new(
+            build_stream(),
+            vec![0],
+            Arc::new(LastValueOperator),
+            false,
+            Some(3),
+        )
+        .unwrap();
+        let expected =
+            [record_batch!(("pk1", UInt8, vec![12]), ("value", Binary, vec![b"3"])).unwrap()];
+        check_stream(Box::pin(stream), expected).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_merge_stream_with_string_keys() {
+        let stream = make_sendable_record_batches([
+            record_batch!(
+                ("pk1", Utf8, vec!["a", "a", "b"]),
+                ("pk2", Int16, vec![1, 1, 1]),
+                ("value", Binary, vec![b"1", b"2", b"3"]),
+                (SEQ_COLUMN_NAME, UInt64, vec![1, 2, 3]),
+                (RESERVED_COLUMN_NAME, UInt64, vec![None; 3])
+            )
+            .unwrap(),
+            record_batch!(
+                ("pk1", Utf8, vec!["b", "b"]),
+                ("pk2", Int16, vec![1, 2]),
+                ("value", Binary, vec![b"4", b"5"]),
+                (SEQ_COLUMN_NAME, UInt64, vec![4, 5]),
+                (RESERVED_COLUMN_NAME, UInt64, vec![None; 2])
+            )
+            .unwrap(),
+        ]);
+
+        let stream = MergeStream::try_new(
+            stream,
+            vec![0, 1], // sort_key_idxes
+            Arc::new(LastValueOperator),
+            false, // keep_builtin
+            None,  // max_sequence
+        )
+        .unwrap();
+        let expected = [
+            record_batch!(
+                ("pk1", Utf8, vec!["a"]),
+                ("pk2", Int16, vec![1]),
+                ("value", Binary, vec![b"2"])
+            )
+            .unwrap(),
+            record_batch!(
+                ("pk1", Utf8, vec!["b"]),
+                ("pk2", Int16, vec![1]),
+                ("value", Binary, vec![b"4"])
+            )
+            .unwrap(),
+            record_batch!(
+                ("pk1", Utf8, vec!["b"]),
+                ("pk2", Int16, vec![2]),
+                ("value", Binary, vec![b"5"])
+            )
+            .unwrap(),
+        ];
         check_stream(Box::pin(stream), expected).await;
     }
 
@@ -578,7 +964,9 @@ mod tests {
         let store = Arc::new(LocalFileSystem::new());
         let reader = ParquetReader::new(
             store,
-            StorageSchema::try_new(schema, 1, UpdateMode::Overwrite).unwrap(),
+            SharedSchema::new(
+                StorageSchema::try_new(schema, 1, None, UpdateMode::Overwrite).unwrap(),
+            ),
             Arc::new(SstPathGenerator::new("mock".to_string())),
         );
 
@@ -594,20 +982,25 @@ mod tests {
                                 num_rows: 1,
                                 size: 1,
                                 time_range: (1..10).into(),
+                                primary_key_stats: None,
+                                deduplicated: false,
+                                merged: false,
                             },
                         )
                     })
                     .collect(),
+                Vec::new(), // mem_batches
                 None,
                 vec![expr],
                 false, // keep_builtin
+                None,  // max_sequence
             )
             .unwrap();
         let display_plan =
             datafusion::physical_plan::display::DisplayableExecutionPlan::new(plan.as_ref())
                 .indent(true);
         assert_eq!(
-            r#"MergeExec: [primary_keys: 1, keep_builtin: false]
+            r#"MergeExec: [sort_keys: [0], keep_builtin: false]
   SortPreservingMergeExec: [pk1@0 ASC, __seq__@2 ASC]
     FilterExec: pk1@0 = 0
       ParquetExec: file_groups={3 groups: [[mock/data/100.sst], [mock/data/101.sst], [mock/data/102.sst]]}, projection=[pk1, value, __seq__, __reserved__], output_orderings=[[pk1@0 ASC, __seq__@2 ASC], [pk1@0 ASC, __seq__@2 ASC], [pk1@0 ASC, __seq__@2 ASC]], predicate=pk1@0 = 0, pruning_predicate=CASE WHEN pk1_null_count@2 = pk1_row_count@3 THEN false ELSE pk1_min@0 <= 0 AND 0 <= pk1_max@1 END, required_guarantees=[pk1 in (0)]
@@ -615,4 +1008,67 @@ mod tests {
             format!("{display_plan}")
         );
     }
+
+    #[test(tokio::test)]
+    async fn test_build_scan_plan_without_merge() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", UInt8));
+        let store = Arc::new(LocalFileSystem::new());
+        let reader = ParquetReader::new(
+            store,
+            SharedSchema::new(
+                StorageSchema::try_new(schema, 1, None, UpdateMode::Overwrite).unwrap(),
+            ),
+            Arc::new(SstPathGenerator::new("mock".to_string())),
+        );
+        let sst = |id, min: u8, max: u8, deduplicated| {
+            SstFile::new(
+                id,
+                FileMeta {
+                    max_sequence: id,
+                    num_rows: 1,
+                    size: 1,
+                    time_range: (1..10).into(),
+                    primary_key_stats: PrimaryKeyStats::new(
+                        vec![ScalarValue::UInt8(Some(min))],
+                        vec![ScalarValue::UInt8(Some(max))],
+                    ),
+                    deduplicated,
+                    merged: deduplicated,
+                },
+            )
+        };
+        let display_plan = |ssts| {
+            let plan = reader
+                .build_df_plan(
+                    ssts,
+                    Vec::new(), // mem_batches
+                    None,
+                    Vec::new(),
+                    false, // keep_builtin
+                    None,  // max_sequence
+                )
+                .unwrap();
+            format!(
+                "{}",
+                datafusion::physical_plan::display::DisplayableExecutionPlan::new(plan.as_ref())
+                    .indent(true)
+            )
+        };
+
+        // Disjoint ssts are read in order of primary keys.
+        assert_eq!(
+            r#"ProjectionExec: expr=[pk1@0 as pk1, value@1 as value]
+  ParquetExec: file_groups={1 group: [[mock/data/101.sst, mock/data/100.sst]]}, projection=[pk1, value, __seq__, __reserved__]
+"#,
+            display_plan(vec![sst(100, 10, 20, true), sst(101, 1, 9, true)])
+        );
+
+        // Overlapping or not deduplicated ssts are merged.
+        for ssts in [
+            vec![sst(100, 10, 20, true), sst(101, 1, 10, true)],
+            vec![sst(100, 10, 20, true), sst(101, 1, 9, false)],
+        ] {
+            assert!(display_plan(ssts).starts_with("MergeExec"));
+        }
+    }
 }
@@ -19,14 +19,23 @@ use std::{
     fmt,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc, LazyLock,
+        Arc, LazyLock, OnceLock,
     },
     time::SystemTime,
 };
 
+use anyhow::Context;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::{
+    common::ScalarValue,
+    logical_expr::{BinaryExpr, Operator},
+    prelude::Expr,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
 use crate::{
     ensure,
-    types::{TimeRange, Timestamp},
+    types::{StorageSchema, TimeRange, Timestamp},
     Error,
 };
 
@@ -47,6 +56,10 @@ static NEXT_ID: LazyLock<AtomicU64> = LazyLock::new(|| {
 
 pub type FileId = u64;
 
+/// Key of parquet metadata, it's set when rows of the sst are merged, so
+/// there are no duplicate sort keys except tombstones.
+pub const MERGED_METADATA_KEY: &str = "merged";
+
 #[derive(Clone)]
 pub struct SstFile {
     inner: Arc<Inner>,
@@ -68,6 +81,9 @@ struct Inner {
     meta: FileMeta,
 
     in_compaction: AtomicBool,
+    /// Set when the sst is removed from manifest, id is sent to it once the
+    /// sst is dropped, so that it can be deleted.
+    purge_tx: OnceLock<UnboundedSender<FileId>>,
 }
 
 impl Inner {
@@ -76,6 +92,17 @@ impl Inner {
             id,
             meta,
             in_compaction: AtomicBool::new(false),
+            purge_tx: OnceLock::new(),
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(purge_tx) = self.purge_tx.take() {
+            // Receiver may be closed when the storage is closed, the file is
+            // deleted when the storage is opened next time then.
+            let _ = purge_tx.send(self.id);
         }
     }
 }
@@ -106,6 +133,13 @@ impl SstFile {
         self.inner.in_compaction.load(Ordering::Relaxed)
     }
 
+    /// Mark the sst is removed from manifest, its id is sent to `purge_tx`
+    /// after all clones of it are dropped, including those held by versions
+    /// of manifest pinned by readers.
+    pub fn mark_obsolete(&self, purge_tx: UnboundedSender<FileId>) {
+        let _ = self.inner.purge_tx.set(purge_tx);
+    }
+
     pub fn is_expired(&self, expire_time: Option<Timestamp>) -> bool {
         match expire_time {
             Some(expire_time) => self.meta().time_range.end < expire_time,
@@ -126,7 +160,10 @@ impl TryFrom<pb_types::SstFile> for SstFile {
     type Error = Error;
 
     fn try_from(value: pb_types::SstFile) -> Result<Self, Self::Error> {
-        ensure!(value.meta.is_some(), "file meta is missing");
+        ensure!(
+            value.meta.is_some(),
+            Error::Corruption("file meta is missing".to_string())
+        );
         let meta = value.meta.unwrap();
         let meta = meta.try_into()?;
 
@@ -157,27 +194,59 @@ pub struct FileMeta {
     pub num_rows: u32,
     pub size: u32,
     pub time_range: TimeRange,
+    /// `None` means stats are unknown, such as ssts written before stats are
+    /// added, or with primary keys of unsupported types.
+    pub primary_key_stats: Option<PrimaryKeyStats>,
+    /// Rows are unique by sort key and there are no tombstones, such as
+    /// outputs of compaction, so they can be read without merging.
+    pub deduplicated: bool,
+    /// Rows with the same sort key are merged into one, such as outputs of
+    /// compaction, so states before `max_sequence` can't be read from it.
+    pub merged: bool,
 }
 
 impl TryFrom<pb_types::SstMeta> for FileMeta {
     type Error = Error;
 
     fn try_from(value: pb_types::SstMeta) -> Result<Self, Self::Error> {
-        ensure!(value.time_range.is_some(), "time range is missing");
+        ensure!(
+            value.time_range.is_some(),
+            Error::Corruption("time range is missing".to_string())
+        );
         let time_range = value.time_range.unwrap();
 
+        let primary_key_stats = if value.min_primary_keys.is_empty() {
+            None
+        } else {
+            Some(PrimaryKeyStats::try_new(
+                &value.min_primary_keys,
+                &value.max_primary_keys,
+            )?)
+        };
+
         Ok(Self {
             max_sequence: value.max_sequence,
             num_rows: value.num_rows,
             size: value.size,
             time_range: TimeRange::new(time_range.start.into(), time_range.end.into()),
+            primary_key_stats,
+            deduplicated: value.deduplicated,
+            merged: value.merged,
         })
     }
 }
 
 impl From<FileMeta> for pb_types::SstMeta {
     fn from(value: FileMeta) -> Self {
+        let (min_primary_keys, max_primary_keys) = value
+            .primary_key_stats
+            .map(|stats| stats.encode())
+            .unwrap_or_default();
         pb_types::SstMeta {
+            min_primary_keys,
+            max_primary_keys,
+            deduplicated: value.deduplicated,
+            merged: value.merged,
             max_sequence: value.max_sequence,
             num_rows: value.num_rows,
             size: value.size,
@@ -189,6 +258,215 @@ impl From<FileMeta> for pb_types::SstMeta {
     }
 }
 
+/// Min and max values of each primary key column in a sst, so the sst can be
+/// pruned by predicates on primary keys without being read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrimaryKeyStats {
+    pub min: Vec<ScalarValue>,
+    pub max: Vec<ScalarValue>,
+}
+
+impl PrimaryKeyStats {
+    const TAG_BINARY: u8 = 3;
+    const TAG_INT: u8 = 1;
+    const TAG_NULL: u8 = 0;
+    const TAG_UINT: u8 = 2;
+    const TAG_UTF8: u8 = 4;
+
+    /// `None` is returned when any value can't be encoded.
+    pub fn new(min: Vec<ScalarValue>, max: Vec<ScalarValue>) -> Option<Self> {
+        let stats = Self { min, max };
+        let encodable = stats
+            .min
+            .iter()
+            .chain(stats.max.iter())
+            .all(|v| Self::encode_value(v).is_some());
+        encodable.then_some(stats)
+    }
+
+    pub fn try_new(min: &[Vec<u8>], max: &[Vec<u8>]) -> Result<Self, Error> {
+        ensure!(
+            min.len() == max.len(),
+            Error::Corruption(format!(
+                "primary key stats length mismatch, min:{}, max:{}",
+                min.len(),
+                max.len()
+            ))
+        );
+        let decode = |values: &[Vec<u8>]| {
+            values
+                .iter()
+                .map(|v| Self::decode_value(v))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(Self {
+            min: decode(min)?,
+            max: decode(max)?,
+        })
+    }
+
+    /// Encode min and max values.
+    pub fn encode(&self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        // Values are checked to be encodable when created.
+        let encode = |values: &[ScalarValue]| {
+            values
+                .iter()
+                .map(|v| Self::encode_value(v).unwrap())
+                .collect::<Vec<_>>()
+        };
+        (encode(&self.min), encode(&self.max))
+    }
+
+    /// Integers are encoded as 64 bits, they are casted to the column type
+    /// when compared.
+    fn encode_value(value: &ScalarValue) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        match value {
+            v if v.is_null() => buf.push(Self::TAG_NULL),
+            ScalarValue::Int8(Some(v)) => Self::encode_int(&mut buf, *v as i64),
+            ScalarValue::Int16(Some(v)) => Self::encode_int(&mut buf, *v as i64),
+            ScalarValue::Int32(Some(v)) => Self::encode_int(&mut buf, *v as i64),
+            ScalarValue::Int64(Some(v)) | ScalarValue::TimestampMillisecond(Some(v), _) => {
+                Self::encode_int(&mut buf, *v)
+            }
+            ScalarValue::UInt8(Some(v)) => Self::encode_uint(&mut buf, *v as u64),
+            ScalarValue::UInt16(Some(v)) => Self::encode_uint(&mut buf, *v as u64),
+            ScalarValue::UInt32(Some(v)) => Self::encode_uint(&mut buf, *v as u64),
+            ScalarValue::UInt64(Some(v)) => Self::encode_uint(&mut buf, *v),
+            ScalarValue::Binary(Some(v)) => {
+                buf.push(Self::TAG_BINARY);
+                buf.extend_from_slice(v);
+            }
+            ScalarValue::Utf8(Some(v)) => {
+                buf.push(Self::TAG_UTF8);
+                buf.extend_from_slice(v.as_bytes());
+            }
+            _ => return None,
+        }
+        Some(buf)
+    }
+
+    fn encode_int(buf: &mut Vec<u8>, v: i64) {
+        buf.push(Self::TAG_INT);
+        buf.write_i64::<LittleEndian>(v).unwrap();
+    }
+
+    fn encode_uint(buf: &mut Vec<u8>, v: u64) {
+        buf.push(Self::TAG_UINT);
+        buf.write_u64::<LittleEndian>(v).unwrap();
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<ScalarValue, Error> {
+        let Some((tag, mut payload)) = bytes.split_first() else {
+            return Err(Error::Corruption("primary key stats is empty".to_string()));
+        };
+        let value = match *tag {
+            Self::TAG_NULL => ScalarValue::Null,
+            Self::TAG_INT => ScalarValue::Int64(Some(
+                payload.read_i64::<LittleEndian>().context("read int")?,
+            )),
+            Self::TAG_UINT => ScalarValue::UInt64(Some(
+                payload.read_u64::<LittleEndian>().context("read uint")?,
+            )),
+            Self::TAG_BINARY => ScalarValue::Binary(Some(payload.to_vec())),
+            Self::TAG_UTF8 => ScalarValue::Utf8(Some(
+                String::from_utf8(payload.to_vec()).context("read utf8")?,
+            )),
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "unknown primary key stats tag:{tag}"
+                )))
+            }
+        };
+        Ok(value)
+    }
+
+    /// Min and max of the primary keys as tuples, casted to types of the
+    /// columns. Every row is in the range of them when compared
+    /// lexicographically.
+    ///
+    /// `None` is returned when the bounds are unknown.
+    pub fn bounds(&self, schema: &StorageSchema) -> Option<(Vec<ScalarValue>, Vec<ScalarValue>)> {
+        (0..schema.num_primary_keys)
+            .map(|idx| self.column_bounds(schema, idx))
+            .collect::<Option<Vec<_>>>()
+            .map(|bounds| bounds.into_iter().unzip())
+    }
+
+    fn column_bounds(
+        &self,
+        schema: &StorageSchema,
+        idx: usize,
+    ) -> Option<(ScalarValue, ScalarValue)> {
+        if idx >= schema.num_primary_keys || idx >= self.min.len() {
+            return None;
+        }
+
+        let data_type = schema.arrow_schema.field(idx).data_type();
+        let min = self.min[idx].cast_to(data_type).ok()?;
+        let max = self.max[idx].cast_to(data_type).ok()?;
+        (!min.is_null() && !max.is_null()).then_some((min, max))
+    }
+
+    /// Whether rows of the sst may match `expr`, `false` only when it's sure
+    /// that no rows match.
+    ///
+    /// Only comparisons and in lists between a primary key and literals are
+    /// considered.
+    pub fn may_match(&self, schema: &StorageSchema, expr: &Expr) -> bool {
+        let (column, values, op) = match expr {
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(column), Expr::Literal(value)) => (column, vec![value], *op),
+                    (Expr::Literal(value), Expr::Column(column)) => match op.swap() {
+                        Some(op) => (column, vec![value], op),
+                        None => return true,
+                    },
+                    _ => return true,
+                }
+            }
+            Expr::InList(in_list) if !in_list.negated => {
+                let Expr::Column(column) = in_list.expr.as_ref() else {
+                    return true;
+                };
+                let mut values = Vec::with_capacity(in_list.list.len());
+                for expr in &in_list.list {
+                    let Expr::Literal(value) = expr else {
+                        return true;
+                    };
+                    values.push(value);
+                }
+                (column, values, Operator::Eq)
+            }
+            _ => return true,
+        };
+        let Ok(idx) = schema.arrow_schema.index_of(&column.name) else {
+            return true;
+        };
+        let Some((min, max)) = self.column_bounds(schema, idx) else {
+            return true;
+        };
+        let data_type = schema.arrow_schema.field(idx).data_type();
+        values.into_iter().any(|value| {
+            let Ok(value) = value.cast_to(data_type) else {
+                return true;
+            };
+            if value.is_null() {
+                return true;
+            }
+            match op {
+                Operator::Eq => min <= value && value <= max,
+                Operator::Lt => min < value,
+                Operator::LtEq => min <= value,
+                Operator::Gt => max > value,
+                Operator::GtEq => max >= value,
+                _ => true,
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SstPathGenerator {
     prefix: String,
@@ -200,6 +478,73 @@ impl SstPathGenerator {
     }
 
     pub fn generate(&self, id: FileId) -> String {
-        format!("{}/{}/{}.sst", self.prefix, PREFIX_PATH, id)
+        format!("{}/{}.sst", self.dir(), id)
+    }
+
+    /// Directory of all ssts.
+    pub fn dir(&self) -> String {
+        format!("{}/{}", self.prefix, PREFIX_PATH)
+    }
+
+    /// Returns the id of sst named `filename`, `None` if it's not an sst.
+    pub fn parse_id(filename: &str) -> Option<FileId> {
+        filename.strip_suffix(".sst")?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::logical_expr::{col, lit};
+
+    use super::*;
+    use crate::config::UpdateMode;
+
+    #[test]
+    fn test_primary_key_stats_may_match() {
+        let arrow_schema = Arc::new(Schema::new(vec![
+            Field::new("pk1", DataType::UInt8, false),
+            Field::new("pk2", DataType::Utf8, false),
+            Field::new("value", DataType::Int64, true),
+        ]));
+        let schema = StorageSchema::try_new(arrow_schema, 2, None, UpdateMode::Overwrite).unwrap();
+        let stats = PrimaryKeyStats::new(
+            vec![ScalarValue::UInt8(Some(10)), ScalarValue::from("b")],
+            vec![ScalarValue::UInt8(Some(20)), ScalarValue::from("d")],
+        )
+        .unwrap();
+        // Stats are persisted in a type erased way.
+        let (mins, maxs) = stats.encode();
+        let stats = PrimaryKeyStats::try_new(&mins, &maxs).unwrap();
+
+        for (expr, expected) in [
+            (col("pk1").eq(lit(15)), true),
+            (col("pk1").eq(lit(21)), false),
+            (col("pk1").lt(lit(10)), false),
+            (col("pk1").lt_eq(lit(10)), true),
+            (col("pk1").gt(lit(20)), false),
+            (lit(20).lt(col("pk1")), false),
+            (lit(20).gt_eq(col("pk1")), true),
+            (col("pk1").in_list(vec![lit(1), lit(30)], false), false),
+            (col("pk1").in_list(vec![lit(1), lit(30)], true), true),
+            (col("pk2").eq(lit("c")), true),
+            (col("pk2").gt(lit("d")), false),
+            // Not primary keys
+            (col("value").eq(lit(100)), true),
+            // Out of range of column type, can't be casted.
+            (col("pk1").eq(lit(1000)), true),
+            (col("pk1").eq(lit(15)).or(col("pk1").eq(lit(1))), true),
+        ] {
+            assert_eq!(expected, stats.may_match(&schema, &expr), "{expr}");
+        }
+    }
+
+    #[test]
+    fn test_primary_key_stats_unsupported_type() {
+        assert!(PrimaryKeyStats::new(
+            vec![ScalarValue::Float64(Some(1.0))],
+            vec![ScalarValue::Float64(Some(2.0))],
+        )
+        .is_none());
     }
 }
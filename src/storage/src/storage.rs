@@ -15,44 +15,70 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{sync::Arc, time::Duration, vec};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+    vec,
+};
 
 use anyhow::Context;
-use arrow::{array::RecordBatch, datatypes::SchemaRef};
+use arrow::{
+    array::{RecordBatch, UInt32Array},
+    compute::{concat_batches, take_record_batch},
+    datatypes::{DataType, FieldRef, SchemaRef},
+};
 use async_trait::async_trait;
+use common::{now, StoreContext};
 use datafusion::{
     self,
     common::DFSchema,
-    execution::{context::ExecutionProps, SendableRecordBatchStream},
-    logical_expr::Expr,
+    error::{DataFusionError, Result as DfResult},
+    execution::{context::ExecutionProps, SendableRecordBatchStream, TaskContext},
+    logical_expr::{cast, lit, Expr},
     physical_expr::LexOrdering,
     physical_plan::{
-        execute_stream, memory::MemoryExec, sorts::sort::SortExec, union::UnionExec,
-        EmptyRecordBatchStream,
+        execute_stream,
+        limit::LimitStream,
+        metrics::{BaselineMetrics, ExecutionPlanMetricsSet},
+        sorts::{sort::SortExec, sort_preserving_merge::SortPreservingMergeExec},
+        stream::{RecordBatchReceiverStream, RecordBatchStreamAdapter},
+        union::UnionExec,
+        EmptyRecordBatchStream, ExecutionPlan,
     },
     physical_planner::create_physical_sort_exprs,
     prelude::{ident, SessionContext},
 };
-use futures::StreamExt;
-use itertools::Itertools;
+use futures::{StreamExt, TryStreamExt};
 use object_store::path::Path;
 use parquet::{
-    arrow::{async_writer::ParquetObjectWriter, AsyncArrowWriter},
+    arrow::async_reader::{ParquetObjectReader, ParquetRecordBatchStreamBuilder},
     file::properties::WriterProperties,
     format::SortingColumn,
     schema::types::ColumnPath,
 };
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, sync::Mutex};
+use tracing::info;
 
 use crate::{
+    aggregate::{AggregateRequest, StatsAggregator},
     compaction::CompactionScheduler,
-    config::{StorageConfig, WriteConfig},
+    config::{ScanConfig, StorageConfig, WriteConfig},
     ensure,
-    manifest::{Manifest, ManifestRef},
+    manifest::{
+        schema::{SchemaStore, TableDescriptor},
+        Manifest, ManifestRef, ManifestUpdate, VersionRef, WriteKey,
+    },
+    memtable::{self, Flusher, MemTables},
     read::ParquetReader,
-    sst::{FileMeta, SstFile, SstPathGenerator},
-    types::{ObjectStoreRef, StorageSchema, TimeRange, WriteResult, SEQ_COLUMN_NAME},
-    Result,
+    sst::{FileId, FileMeta, SstFile, SstPathGenerator},
+    types::{
+        ObjectStoreRef, RowKind, SharedSchema, StorageSchema, TimeRange, Timestamp, WriteResult,
+    },
+    wal::{self, WalEntry, WalRef},
+    write::{ParquetWriter, SstWriter},
+    write_controller::WriteController,
+    Error, Result,
 };
 
 pub struct WriteRequest {
@@ -60,6 +86,32 @@ pub struct WriteRequest {
     pub time_range: TimeRange,
     // Check data is valid if it's true.
     pub enable_check: bool,
+    /// Retries of a write with the same key are ignored within
+    /// `idempotency_window` of
+    /// [`ManifestConfig`](crate::config::ManifestConfig).
+    pub idempotency_key: Option<String>,
+}
+
+/// Write rows of a stream, which may be too large to fit in memory.
+pub struct WriteStreamRequest {
+    pub stream: SendableRecordBatchStream,
+    /// Time range of all rows, it's used as sst time range when there is no
+    /// timestamp column.
+    pub time_range: TimeRange,
+    // Check data is valid if it's true.
+    pub enable_check: bool,
+}
+
+/// Order of rows returned by scan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanOrder {
+    /// Sorted by primary keys, rows with the same primary keys are sorted by
+    /// timestamp column if it exists.
+    #[default]
+    PrimaryKey,
+    /// Sorted by timestamp column globally, rows with the same timestamp are
+    /// sorted by primary keys. It requires a timestamp column.
+    Time,
 }
 
 pub struct ScanRequest {
@@ -67,23 +119,112 @@ pub struct ScanRequest {
     pub predicate: Vec<Expr>,
     /// `None` means all columns.
     pub projections: Option<Vec<usize>>,
+    pub order: ScanOrder,
+    /// Rows are returned in descending order when it's true, newest segments
+    /// are read first when sorted by time.
+    pub reverse: bool,
+    /// Number of rows to skip.
+    pub offset: usize,
+    /// Max number of rows to return after `offset`, `None` means no limit.
+    pub limit: Option<usize>,
+    /// Aggregate rows when set, `order` is ignored then, and `reverse`,
+    /// `offset`, `limit` apply to the aggregated rows.
+    pub aggregate: Option<AggregateRequest>,
+    /// Read the state as of this sequence, rows written with larger sequence
+    /// are ignored, `None` means the latest state.
+    ///
+    /// Rows with the same primary keys are merged by compaction, so states
+    /// older than the inputs of compaction can't be read any more, and the
+    /// request is rejected then.
+    pub as_of_sequence: Option<u64>,
+}
+
+impl Default for ScanRequest {
+    /// Scan all rows with all columns, sorted by primary keys.
+    fn default() -> Self {
+        Self {
+            range: TimeRange::new(Timestamp::MIN, Timestamp::MAX),
+            predicate: Vec::new(),
+            projections: None,
+            order: ScanOrder::default(),
+            reverse: false,
+            offset: 0,
+            limit: None,
+            aggregate: None,
+            as_of_sequence: None,
+        }
+    }
+}
+
+/// Delete rows within `range` which match all `predicate`.
+///
+/// When there is no timestamp column, rows are selected by the time range of
+/// ssts, so all rows of ssts overlapping with `range` are candidates.
+pub struct DeleteRequest {
+    pub range: TimeRange,
+    pub predicate: Vec<Expr>,
+}
+
+/// Import parquet files as ssts.
+pub struct IngestRequest {
+    /// Paths of parquet files in the object store of storage.
+    pub files: Vec<String>,
+    /// Time range of all rows, it's used as sst time range when there is no
+    /// timestamp column.
+    pub time_range: TimeRange,
 }
 
 #[derive(Default)]
 pub struct CompactRequest {}
 
+pub struct AlterSchemaRequest {
+    /// Value columns to add, they must be nullable, and are appended to the
+    /// end of schema.
+    pub add_columns: Vec<FieldRef>,
+    /// Value columns to drop.
+    pub drop_columns: Vec<String>,
+}
+
 /// Time-aware merge storage interface.
 #[async_trait]
 pub trait TimeMergeStorage {
-    fn schema(&self) -> &SchemaRef;
+    fn schema(&self) -> SchemaRef;
 
+    /// Writes may be delayed when background jobs can't catch up, and
+    /// [`Error::WriteStalled`](crate::Error::WriteStalled) is returned when
+    /// they are stopped for too long.
     async fn write(&self, req: WriteRequest) -> Result<()>;
 
-    /// Implementation shoule ensure that the returned stream is sorted by time,
-    /// from old to latest.
+    /// Write all requests atomically, readers observe either all or none of
+    /// them, even when they belong to different segments.
+    ///
+    /// Idempotency keys of requests should be unique, and the write is
+    /// ignored as a retry only when all of them are written before.
+    async fn write_atomic(&self, reqs: Vec<WriteRequest>) -> Result<()>;
+
+    /// Rows are sorted with bounded memory, spilling to disk when needed,
+    /// then written to new ssts directly, bypassing wal and memtable.
+    ///
+    /// Rows are visible atomically once this returns.
+    async fn write_stream(&self, req: WriteStreamRequest) -> Result<()>;
+
+    /// Implementation should ensure that the returned stream is sorted as
+    /// [`ScanRequest::order`] requires.
     async fn scan(&self, req: ScanRequest) -> Result<SendableRecordBatchStream>;
 
+    /// Delete rows by writing tombstones for them, rows are hidden from scan
+    /// once this returns, and dropped physically by later compactions.
+    ///
+    /// Rows written concurrently with the same primary keys may be deleted
+    /// as well.
+    async fn delete(&self, req: DeleteRequest) -> Result<()>;
+
     async fn compact(&self, req: CompactRequest) -> Result<()>;
+
+    /// Add or drop value columns, the new schema takes effect for all data
+    /// once this returns, rows written before are filled with nulls for added
+    /// columns.
+    async fn alter_schema(&self, req: AlterSchemaRequest) -> Result<()>;
 }
 
 pub type TimeMergeStorageRef = Arc<(dyn TimeMergeStorage + Send + Sync)>;
@@ -92,17 +233,31 @@ pub type TimeMergeStorageRef = Arc<(dyn TimeMergeStorage + Send + Sync)>;
 pub struct StorageRuntimes {
     manifest_compact_runtime: Arc<Runtime>,
     sst_compact_runtime: Arc<Runtime>,
+    /// Scans are executed in it, including decoding ssts, which is CPU
+    /// intensive.
+    read_runtime: Arc<Runtime>,
 }
 
 impl StorageRuntimes {
-    pub fn new(manifest_compact_runtime: Arc<Runtime>, sst_compact_runtime: Arc<Runtime>) -> Self {
+    pub fn new(
+        manifest_compact_runtime: Arc<Runtime>,
+        sst_compact_runtime: Arc<Runtime>,
+        read_runtime: Arc<Runtime>,
+    ) -> Self {
         Self {
             manifest_compact_runtime,
             sst_compact_runtime,
+            read_runtime,
         }
     }
 }
 
+/// Ssts and memtable batches of one segment.
+struct SegmentInputs {
+    ssts: Vec<SstFile>,
+    mem_batches: Vec<RecordBatch>,
+}
+
 /// `TimeMergeStorage` implementation using cloud object storage, it will split
 /// data into different segments(aka `segment_duration`) based time range.
 ///
@@ -110,16 +265,31 @@ impl StorageRuntimes {
 /// will make it easy to support expiration.
 #[allow(dead_code)]
 pub struct CloudObjectStorage {
-    segment_duration: Duration,
+    pub(crate) segment_duration: Duration,
     path: String,
-    store: ObjectStoreRef,
-    schema: StorageSchema,
+    pub(crate) store: ObjectStoreRef,
+    schema: SharedSchema,
+    schema_store: SchemaStore,
     manifest: ManifestRef,
     runtimes: StorageRuntimes,
     parquet_reader: Arc<ParquetReader>,
+    parquet_writer: Arc<ParquetWriter>,
     write_props: WriterProperties,
-    sst_path_gen: Arc<SstPathGenerator>,
+    /// Max size of each sst written by `write_stream`.
+    new_sst_max_size: usize,
+    /// Memory limit to sort rows of `write_stream`.
+    sort_memory_limit: usize,
+    scan_config: ScanConfig,
+    pub(crate) sst_path_gen: Arc<SstPathGenerator>,
     compact_scheduler: CompactionScheduler,
+    pub(crate) write_controller: WriteController,
+    wal: Option<WalRef>,
+    /// `None` means writes are persisted to sst directly.
+    memtables: Option<Arc<MemTables>>,
+    /// Writes are serialized when wal is enabled, so they are persisted in
+    /// the order of sequence, which is required by wal replay. Schema
+    /// altering also holds it to be serialized.
+    write_lock: Mutex<()>,
 }
 
 /// It will organize the data in the following way:
@@ -128,11 +298,17 @@ pub struct CloudObjectStorage {
 /// {root_path}/manifest/timestamp1
 /// {root_path}/manifest/timestamp2
 /// {root_path}/manifest/...
+/// {root_path}/manifest/schema/version1
+/// {root_path}/manifest/schema/version2
+/// {root_path}/manifest/schema/...
 /// {root_path}/data/timestamp_a.sst
 /// {root_path}/data/timestamp_b.sst
 /// {root_path}/data/...
+/// {root_path}/wal/...
 /// ```
 /// `root_path` is composed of `path` and `segment_duration`.
+///
+/// The wal dir only exists when wal is stored in object store.
 impl CloudObjectStorage {
     pub async fn try_new(
         path: String,
@@ -143,115 +319,1036 @@ impl CloudObjectStorage {
         storage_opts: StorageConfig,
         runtimes: StorageRuntimes,
     ) -> Result<Self> {
-        let schema =
-            StorageSchema::try_new(arrow_schema, num_primary_keys, storage_opts.update_mode)?;
+        let schema = StorageSchema::try_new(
+            arrow_schema,
+            num_primary_keys,
+            storage_opts.timestamp_column.as_deref(),
+            storage_opts.update_mode,
+        )?
+        .with_sort_by_timestamp(storage_opts.sort_by_timestamp)?
+        .with_merge_operators(storage_opts.merge_operators.clone())?;
+        let descriptor = TableDescriptor {
+            schema,
+            segment_duration,
+        };
+        // Options can't be changed after the storage is created, and the schema
+        // given should be the latest altered one.
+        let schema_store = SchemaStore::new(&path, store.clone());
+        let schema = match schema_store.load().await? {
+            Some(persisted) => {
+                persisted.check(&descriptor)?;
+                info!(version = persisted.schema.version, "Load persisted schema");
+                persisted.schema
+            }
+            None => {
+                schema_store.persist(&descriptor).await?;
+                descriptor.schema
+            }
+        };
+        let schema = SharedSchema::new(schema);
         let manifest = Manifest::try_new(
             path.clone(),
             store.clone(),
             runtimes.manifest_compact_runtime.clone(),
-            storage_opts.manifest,
+            segment_duration,
+            storage_opts.manifest.clone(),
         )
         .await?;
         let manifest = Arc::new(manifest);
-        let write_props = Self::build_write_props(storage_opts.write, num_primary_keys);
         let sst_path_gen = Arc::new(SstPathGenerator::new(path.clone()));
+        Self::delete_orphan_ssts(&store, &sst_path_gen, &manifest).await?;
+        let wal = wal::open_wal(&storage_opts.wal, &path, store.clone()).await?;
+        let new_sst_max_size = storage_opts.scheduler.new_sst_max_size.as_byte() as usize;
+        let sort_memory_limit = storage_opts.write.sort_memory_limit.as_byte() as usize;
+        let write_props = Self::build_write_props(storage_opts.write, num_primary_keys);
         let parquet_reader = Arc::new(ParquetReader::new(
             store.clone(),
             schema.clone(),
             sst_path_gen.clone(),
         ));
+        let parquet_writer = Arc::new(ParquetWriter::new(
+            store.clone(),
+            schema.clone(),
+            sst_path_gen.clone(),
+            write_props.clone(),
+        ));
+        let memtables = if storage_opts.memtable.enable {
+            let check_interval = storage_opts.memtable.check_interval.0;
+            let (memtables, flush_rx) = MemTables::new(segment_duration, storage_opts.memtable);
+            let memtables = Arc::new(memtables);
+            let flusher = Flusher::new(
+                memtables.clone(),
+                schema.clone(),
+                parquet_writer.clone(),
+                manifest.clone(),
+                wal.clone(),
+            );
+            // Flush is a background job just like compaction, so they share
+            // the same runtime.
+            let runtime = &runtimes.sst_compact_runtime;
+            runtime.spawn(flusher.run(flush_rx));
+            runtime.spawn(memtable::freeze_expired_loop(
+                memtables.clone(),
+                check_interval,
+            ));
+            Some(memtables)
+        } else {
+            None
+        };
         let compact_scheduler = CompactionScheduler::new(
             runtimes.sst_compact_runtime.clone(),
             manifest.clone(),
             store.clone(),
-            schema.clone(),
             segment_duration,
             sst_path_gen.clone(),
             parquet_reader.clone(),
+            parquet_writer.clone(),
             storage_opts.scheduler,
-            write_props.clone(),
         );
-        Ok(Self {
+        let write_controller = WriteController::new(
+            storage_opts.write_stall,
+            &storage_opts.manifest,
+            manifest.clone(),
+            compact_scheduler.inused_memory(),
+            compact_scheduler.memory_limit(),
+        );
+        let storage = Self {
             path,
             schema,
+            schema_store,
             segment_duration,
             store,
             manifest,
             parquet_reader,
+            parquet_writer,
             runtimes,
             write_props,
+            new_sst_max_size,
+            sort_memory_limit,
+            scan_config: storage_opts.scan,
             sst_path_gen,
             compact_scheduler,
-        })
-    }
+            write_controller,
+            wal,
+            memtables,
+            write_lock: Mutex::new(()),
+        };
+        storage.replay_wal().await?;
 
-    async fn write_batch(&self, batch: RecordBatch) -> Result<WriteResult> {
-        let file_id = SstFile::allocate_id();
-        let file_path = self.sst_path_gen.generate(file_id);
-        let file_path = Path::from(file_path);
-        let object_store_writer = ParquetObjectWriter::new(self.store.clone(), file_path.clone());
-        let mut writer = AsyncArrowWriter::try_new(
-            object_store_writer,
-            self.schema().clone(),
-            Some(self.write_props.clone()),
-        )
-        .context("create arrow writer")?;
+        Ok(storage)
+    }
 
-        // sort record batch
-        let mut batches = self.sort_batch(batch).await?;
-        while let Some(batch) = batches.next().await {
-            let batch = batch.context("get sorted batch")?;
-            // Since file_id is increasing order, we can use it as sequence.
-            let sequence = file_id;
-            let batch_with_seq = self.schema.fill_builtin_columns(batch, sequence)?;
-            writer
-                .write(&batch_with_seq)
+    /// Delete ssts not in manifest, such as compacted ones still pinned by
+    /// readers when the storage is closed, or those written before a crash
+    /// but not added to manifest.
+    ///
+    /// It must be called before any job writing ssts is started.
+    async fn delete_orphan_ssts(
+        store: &ObjectStoreRef,
+        sst_path_gen: &SstPathGenerator,
+        manifest: &Manifest,
+    ) -> Result<()> {
+        let version = manifest.current_version().await;
+        let live_ids = version
+            .ssts()
+            .iter()
+            .map(|f| f.id())
+            .collect::<HashSet<_>>();
+        let dir = Path::from(sst_path_gen.dir());
+        let paths = store
+            .list(Some(&dir))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await
+            .with_store_context(|| format!("list ssts, dir:{dir}"))?;
+        for path in paths {
+            let Some(id) = path.filename().and_then(SstPathGenerator::parse_id) else {
+                continue;
+            };
+            if live_ids.contains(&id) {
+                continue;
+            }
+            store
+                .delete(&path)
                 .await
-                .context("write arrow batch")?;
+                .with_store_context(|| format!("delete orphan sst, path:{path}"))?;
+            info!(id, "Delete orphan sst");
         }
-        writer.close().await.context("close arrow writer")?;
-        let object_meta = self
+
+        Ok(())
+    }
+
+    pub(crate) async fn open_parquet_file(
+        &self,
+        path: &Path,
+    ) -> Result<ParquetRecordBatchStreamBuilder<ParquetObjectReader>> {
+        let meta = self
             .store
-            .head(&file_path)
+            .head(path)
+            .await
+            .with_store_context(|| format!("get external file meta, path:{path}"))?;
+        let reader = ParquetObjectReader::new(self.store.clone(), meta);
+        let builder = ParquetRecordBatchStreamBuilder::new(reader)
+            .await
+            .with_context(|| format!("open external file, path:{path}"))?;
+
+        Ok(builder)
+    }
+
+    /// Sst file id and sequence share the same allocator, so sequence is
+    /// increasing even after restart.
+    pub(crate) fn allocate_sequence() -> u64 {
+        SstFile::allocate_id()
+    }
+
+    pub(crate) fn storage_schema(&self) -> Arc<StorageSchema> {
+        self.schema.load()
+    }
+
+    pub(crate) fn segment_of(&self, time_range: &TimeRange) -> Timestamp {
+        time_range.start.truncate_by(self.segment_duration)
+    }
+
+    /// Recover writes in wal which are not flushed to manifest yet.
+    async fn replay_wal(&self) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+
+        // Ssts within a segment are flushed in the order of sequence, so
+        // entries with smaller sequence than the max one are already flushed.
+        let mut flushed_sequences = HashMap::new();
+        for sst in self.manifest.all_ssts().await {
+            let sequence = flushed_sequences
+                .entry(self.segment_of(&sst.meta().time_range))
+                .or_insert(0);
+            *sequence = sst.meta().max_sequence.max(*sequence);
+        }
+        let entries = wal.replay().await?;
+        let Some(last_sequence) = entries.last().map(|entry| entry.sequence) else {
+            return Ok(());
+        };
+        let mut num_replayed = 0;
+        for entry in entries {
+            let sequence = entry.sequence;
+            let batches = match &self.memtables {
+                Some(_) => {
+                    let schema = self.schema.load();
+                    let mut batches = Vec::with_capacity(entry.batches.len());
+                    for (time_range, batch) in entry.batches {
+                        let batch = self.sort_batch(batch).await?;
+                        let batch = schema.fill_builtin_columns(batch, sequence, entry.kind)?;
+                        batches.push((time_range, batch));
+                    }
+                    batches
+                }
+                None => entry.batches,
+            };
+            let parts = self
+                .split_by_segment(batches)?
+                .into_iter()
+                .filter(|(time_range, _)| {
+                    flushed_sequences
+                        .get(&self.segment_of(time_range))
+                        .is_none_or(|flushed| sequence > *flushed)
+                })
+                .collect::<Vec<_>>();
+            if parts.is_empty() {
+                continue;
+            }
+            match &self.memtables {
+                Some(memtables) => memtables.insert(parts, sequence, entry.write_keys),
+                None => {
+                    self.persist(sequence, entry.kind, parts, entry.write_keys)
+                        .await?
+                }
+            }
+            num_replayed += 1;
+        }
+        info!(last_sequence, num_replayed, "Replay wal finished");
+
+        // When memtable is enabled, wal will be truncated after flush.
+        if self.memtables.is_none() {
+            wal.truncate(last_sequence).await?;
+        }
+        Ok(())
+    }
+
+    /// Split batches of one write into parts by segment, see
+    /// `split_batch_by_segment`.
+    fn split_by_segment(
+        &self,
+        batches: Vec<(TimeRange, RecordBatch)>,
+    ) -> Result<Vec<(TimeRange, RecordBatch)>> {
+        let mut parts = Vec::with_capacity(batches.len());
+        for (time_range, batch) in batches {
+            parts.extend(self.split_batch_by_segment(batch, time_range)?);
+        }
+
+        Ok(parts)
+    }
+
+    /// Split batch into parts by segment, time range of each part is computed
+    /// from the timestamp column.
+    ///
+    /// When there is no timestamp column, batch is not split and `time_range`
+    /// is used for it.
+    fn split_batch_by_segment(
+        &self,
+        batch: RecordBatch,
+        time_range: TimeRange,
+    ) -> Result<Vec<(TimeRange, RecordBatch)>> {
+        let Some(timestamps) = self.schema.load().timestamps(&batch)? else {
+            return Ok(vec![(time_range, batch)]);
+        };
+
+        let mut rows_by_segment: BTreeMap<Timestamp, (TimeRange, Vec<u32>)> = BTreeMap::new();
+        for (row, ts) in timestamps.values().iter().enumerate() {
+            let ts = Timestamp(*ts);
+            let row_range = TimeRange::new(ts, ts + 1);
+            let (time_range, rows) = rows_by_segment
+                .entry(ts.truncate_by(self.segment_duration))
+                .or_insert_with(|| (row_range.clone(), Vec::new()));
+            time_range.merge(&row_range);
+            rows.push(row as u32);
+        }
+
+        if rows_by_segment.len() == 1 {
+            let (time_range, _) = rows_by_segment.into_values().next().unwrap();
+            return Ok(vec![(time_range, batch)]);
+        }
+        rows_by_segment
+            .into_values()
+            .map(|(time_range, rows)| {
+                let part = take_record_batch(&batch, &UInt32Array::from(rows))
+                    .context("take rows of segment")?;
+                Ok((time_range, part))
+            })
+            .collect()
+    }
+
+    /// Write parts of each segment to a new sst, then add them to manifest
+    /// together along with `write_keys`.
+    async fn persist(
+        &self,
+        sequence: u64,
+        kind: RowKind,
+        parts: Vec<(TimeRange, RecordBatch)>,
+        write_keys: Vec<WriteKey>,
+    ) -> Result<()> {
+        let mut parts_by_segment: BTreeMap<Timestamp, (TimeRange, Vec<RecordBatch>)> =
+            BTreeMap::new();
+        for (time_range, part) in parts {
+            match parts_by_segment.entry(self.segment_of(&time_range)) {
+                Entry::Occupied(mut entry) => {
+                    let (segment_range, segment_parts) = entry.get_mut();
+                    segment_range.merge(&time_range);
+                    segment_parts.push(part);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((time_range, vec![part]));
+                }
+            }
+        }
+
+        let mut to_adds = Vec::with_capacity(parts_by_segment.len());
+        for (time_range, parts) in parts_by_segment.into_values() {
+            let batch = concat_batches(&parts[0].schema(), &parts).context("concat parts")?;
+            let num_rows = batch.num_rows();
+            let WriteResult {
+                id: file_id,
+                seq,
+                size: file_size,
+                time_range: data_range,
+                primary_key_stats,
+            } = self
+                .write_sst(SstFile::allocate_id(), sequence, kind, batch)
+                .await?;
+            let file_meta = FileMeta {
+                max_sequence: seq,
+                num_rows: num_rows as u32,
+                size: file_size as u32,
+                time_range: data_range.unwrap_or(time_range),
+                primary_key_stats,
+                deduplicated: false,
+                merged: false,
+            };
+            to_adds.push(SstFile::new(file_id, file_meta));
+        }
+
+        self.manifest
+            .update(ManifestUpdate::new(to_adds, Vec::new()).with_write_keys(write_keys))
             .await
-            .context("get object meta")?;
+    }
+
+    async fn write_sst(
+        &self,
+        file_id: FileId,
+        sequence: u64,
+        kind: RowKind,
+        batch: RecordBatch,
+    ) -> Result<WriteResult> {
+        let batches = self
+            .parquet_writer
+            .sort_batch(batch, false /* sort_seq */)
+            .await?
+            .map(|batch| {
+                let batch = batch.context("get sorted batch")?;
+                self.schema
+                    .load()
+                    .fill_builtin_columns(batch, sequence, kind)
+            });
+        let summary = self.parquet_writer.write(file_id, batches).await?;
 
         Ok(WriteResult {
             id: file_id,
-            seq: file_id,
-            size: object_meta.size,
+            seq: sequence,
+            size: summary.size,
+            time_range: summary.time_range,
+            primary_key_stats: summary.primary_key_stats,
         })
     }
 
-    fn build_sort_exprs(&self, df_schema: &DFSchema, sort_seq: bool) -> Result<LexOrdering> {
-        let mut sort_exprs = (0..self.schema.num_primary_keys)
-            .map(|i| {
-                ident(self.schema().field(i).name())
-                    .sort(true /* asc */, true /* nulls_first */)
+    /// Sort batch by primary keys, and concat results into one batch.
+    async fn sort_batch(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        let schema = batch.schema();
+        let batches = self
+            .parquet_writer
+            .sort_batch(batch, false /* sort_seq */)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await
+            .context("get sorted batch")?;
+        let batch = concat_batches(&schema, &batches).context("concat sorted batches")?;
+
+        Ok(batch)
+    }
+
+    /// Find ssts of current version and memtable batches overlapping with
+    /// `range`, grouped by segment.
+    ///
+    /// Ssts are pruned by primary key stats if no rows of them could match
+    /// `predicate`, which is safe since rows are merged by primary keys.
+    ///
+    /// The returned version should be held until inputs are read.
+    async fn find_segment_inputs(
+        &self,
+        range: &TimeRange,
+        predicate: &[Expr],
+    ) -> (VersionRef, BTreeMap<Timestamp, SegmentInputs>) {
+        let (version, mut mem_batches_by_segment) = match &self.memtables {
+            Some(memtables) => {
+                // Otherwise rows flushed between reading the version and
+                // memtables are missed.
+                let _guard = memtables.pause_flush().await;
+                let version = self.manifest.current_version().await;
+                (version, memtables.find_memtables(range))
+            }
+            None => (self.manifest.current_version().await, BTreeMap::new()),
+        };
+        let schema = self.schema.load();
+        let total_ssts = version.find_ssts(range).into_iter().filter(|file| {
+            let Some(stats) = &file.meta().primary_key_stats else {
+                return true;
+            };
+            predicate.iter().all(|expr| stats.may_match(&schema, expr))
+        });
+
+        let mut ssts_by_segment: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for file in total_ssts {
+            ssts_by_segment
+                .entry(self.segment_of(&file.meta().time_range))
+                .or_default()
+                .push(file);
+        }
+        let segments = ssts_by_segment
+            .keys()
+            .chain(mem_batches_by_segment.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let inputs = segments
+            .into_iter()
+            .map(|segment| {
+                let inputs = SegmentInputs {
+                    ssts: ssts_by_segment.remove(&segment).unwrap_or_default(),
+                    mem_batches: mem_batches_by_segment.remove(&segment).unwrap_or_default(),
+                };
+                (segment, inputs)
+            })
+            .collect();
+        (version, inputs)
+    }
+
+    fn plan_segments(
+        &self,
+        inputs: BTreeMap<Timestamp, SegmentInputs>,
+        projections: Option<Vec<usize>>,
+        predicate: Vec<Expr>,
+        max_sequence: Option<u64>,
+    ) -> Result<Vec<(Timestamp, Arc<dyn ExecutionPlan>)>> {
+        let mut plans = Vec::with_capacity(inputs.len());
+        for (segment, inputs) in inputs {
+            let plan = self.parquet_reader.build_df_plan(
+                inputs.ssts,
+                inputs.mem_batches,
+                projections.clone(),
+                predicate.clone(),
+                false, // keep_builtin
+                max_sequence,
+            )?;
+            plans.push((segment, plan));
+        }
+
+        Ok(plans)
+    }
+
+    /// Compute aggregates from statistics of ssts whose rows won't be merged
+    /// with others, those ssts are removed from `inputs`.
+    async fn aggregate_by_stats(
+        &self,
+        schema: &StorageSchema,
+        inputs: &mut BTreeMap<Timestamp, SegmentInputs>,
+        aggregator: &mut StatsAggregator,
+    ) -> Result<()> {
+        for inputs in inputs.values_mut() {
+            let mem_ranges = inputs
+                .mem_batches
+                .iter()
+                .map(|batch| schema.time_range(batch))
+                .collect::<Result<Vec<_>>>()?;
+            let ssts = std::mem::take(&mut inputs.ssts);
+            for (i, sst) in ssts.iter().enumerate() {
+                // Rows with the same primary keys can only be merged within
+                // overlapping time ranges when timestamp is in the sort key.
+                let isolated = (ssts.len() == 1 && inputs.mem_batches.is_empty())
+                    || (schema.sort_by_timestamp
+                        && ssts
+                            .iter()
+                            .enumerate()
+                            .filter(|(j, _)| *j != i)
+                            .map(|(_, other)| Some(&other.meta().time_range))
+                            .chain(mem_ranges.iter().map(|r| r.as_ref()))
+                            .all(|range| {
+                                range.is_some_and(|r| !r.overlaps(&sst.meta().time_range))
+                            }));
+                let path = Path::from(self.sst_path_gen.generate(sst.id()));
+                if isolated {
+                    let builder = self.open_parquet_file(&path).await?;
+                    if aggregator.try_update(builder.metadata(), builder.schema())? {
+                        continue;
+                    }
+                }
+                inputs.ssts.push(sst.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `write_keys` are written before, they are recorded in memtable
+    /// before flushed, and in manifest after.
+    ///
+    /// Keys of one write are recorded atomically, so a retry has either all
+    /// or none of its keys written, and it's invalid otherwise.
+    fn is_duplicated(&self, write_keys: &[WriteKey]) -> Result<bool> {
+        let num_written = write_keys
+            .iter()
+            .filter(|write_key| {
+                self.manifest.contains_write_key(&write_key.key)
+                    || self
+                        .memtables
+                        .as_ref()
+                        .is_some_and(|memtables| memtables.contains_write_key(&write_key.key))
+            })
+            .count();
+        ensure!(
+            num_written == 0 || num_written == write_keys.len(),
+            Error::InvalidRequest(format!(
+                "only some of write keys are written before, write_keys:{write_keys:?}"
+            ))
+        );
+
+        Ok(num_written > 0)
+    }
+
+    /// Write to wal if enabled, then persist to new ssts.
+    async fn write_to_sst(
+        &self,
+        batches: Vec<(TimeRange, RecordBatch)>,
+        kind: RowKind,
+        write_keys: Vec<WriteKey>,
+    ) -> Result<()> {
+        // Writes with keys are serialized as well, so retries can't pass the
+        // duplication check concurrently.
+        let _guard = if self.wal.is_some() || !write_keys.is_empty() {
+            Some(self.write_lock.lock().await)
+        } else {
+            None
+        };
+        if self.is_duplicated(&write_keys)? {
+            info!(write_keys = ?write_keys, "Ignore duplicated write");
+            return Ok(());
+        }
+
+        let sequence = Self::allocate_sequence();
+        let Some(wal) = &self.wal else {
+            let parts = self.split_by_segment(batches)?;
+            return self.persist(sequence, kind, parts, write_keys).await;
+        };
+
+        let entry = WalEntry {
+            sequence,
+            kind,
+            batches,
+            write_keys,
+        };
+        wal.append(&entry).await?;
+        let parts = self.split_by_segment(entry.batches)?;
+        self.persist(entry.sequence, kind, parts, entry.write_keys)
+            .await?;
+        wal.truncate(entry.sequence).await
+    }
+
+    /// Write to wal if enabled, then insert into memtable.
+    async fn write_to_memtable(
+        &self,
+        memtables: &MemTables,
+        batches: Vec<(TimeRange, RecordBatch)>,
+        kind: RowKind,
+        write_keys: Vec<WriteKey>,
+    ) -> Result<()> {
+        let mut sorted_batches = Vec::with_capacity(batches.len());
+        for (time_range, batch) in batches {
+            sorted_batches.push((time_range, self.sort_batch(batch).await?));
+        }
+
+        let _guard = self.write_lock.lock().await;
+        if self.is_duplicated(&write_keys)? {
+            info!(write_keys = ?write_keys, "Ignore duplicated write");
+            return Ok(());
+        }
+
+        let sequence = Self::allocate_sequence();
+        if let Some(wal) = &self.wal {
+            let entry = WalEntry {
+                sequence,
+                kind,
+                batches: sorted_batches.clone(),
+                write_keys: write_keys.clone(),
+            };
+            wal.append(&entry).await?;
+        }
+        let schema = self.schema.load();
+        let batches = sorted_batches
+            .into_iter()
+            .map(|(time_range, batch)| {
+                let batch = schema.fill_builtin_columns(batch, sequence, kind)?;
+                Ok((time_range, batch))
             })
+            .collect::<Result<Vec<_>>>()?;
+        memtables.insert(self.split_by_segment(batches)?, sequence, write_keys);
+
+        Ok(())
+    }
+
+    /// Write batches of one write atomically, it's ignored when all of
+    /// `write_keys` are written before.
+    async fn write_batches(
+        &self,
+        batches: Vec<(TimeRange, RecordBatch)>,
+        kind: RowKind,
+        write_keys: Vec<WriteKey>,
+    ) -> Result<()> {
+        let batches = batches
+            .into_iter()
+            .filter(|(_, batch)| batch.num_rows() > 0)
             .collect::<Vec<_>>();
-        if sort_seq {
-            sort_exprs.push(ident(SEQ_COLUMN_NAME).sort(true, true));
+        if batches.is_empty() {
+            return Ok(());
         }
-        let sort_exprs =
-            create_physical_sort_exprs(&sort_exprs, df_schema, &ExecutionProps::default())
-                .context("create physical sort exprs")?;
 
-        Ok(sort_exprs)
+        match &self.memtables {
+            Some(memtables) => {
+                self.write_to_memtable(memtables, batches, kind, write_keys)
+                    .await
+            }
+            None => self.write_to_sst(batches, kind, write_keys).await,
+        }
+    }
+
+    /// Check batch to write, and project it onto `schema` since it may be
+    /// written with older version of schema.
+    pub(crate) fn check_batch(
+        schema: &StorageSchema,
+        segment_duration: Duration,
+        batch: RecordBatch,
+        time_range: &TimeRange,
+        enable_check: bool,
+    ) -> Result<RecordBatch> {
+        for field in batch.schema().fields() {
+            ensure!(
+                schema.arrow_schema.column_with_name(field.name()).is_some()
+                    && !StorageSchema::is_builtin_field(field),
+                Error::InvalidSchema(format!("unknown column in batch, name:{}", field.name()))
+            );
+        }
+        let batch = schema.project_batch(batch)?;
+        if !enable_check {
+            return Ok(batch);
+        }
+
+        if schema.timestamp_idx.is_some() {
+            // Batch will be split by segment, and sst time range is
+            // derived from data, just make sure the given range is right.
+            if let Some(data_range) = schema.time_range(&batch)? {
+                ensure!(
+                    time_range.contains(&data_range),
+                    Error::InvalidRequest(format!(
+                        "time range doesn't cover data, value:{:?}, data:{:?}",
+                        time_range, &data_range
+                    ))
+                );
+            }
+        } else {
+            let segment_duration = segment_duration.as_millis() as i64;
+            ensure!(
+                time_range.start.0 / segment_duration == (time_range.end.0 - 1) / segment_duration,
+                Error::InvalidRequest(format!(
+                    "time range can't cross segment, value:{:?}",
+                    time_range
+                ))
+            );
+        }
+
+        Ok(batch)
+    }
+
+    /// Split batch sorted by segment into parts of each segment.
+    ///
+    /// When there is no timestamp column, batch belongs to the segment of
+    /// `time_range`.
+    fn split_sorted_by_segment(
+        &self,
+        batch: RecordBatch,
+        time_range: &TimeRange,
+    ) -> Result<Vec<(Timestamp, RecordBatch)>> {
+        let Some(timestamps) = self.schema.load().timestamps(&batch)? else {
+            return Ok(vec![(self.segment_of(time_range), batch)]);
+        };
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let segments = timestamps
+            .values()
+            .iter()
+            .map(|ts| Timestamp(*ts).truncate_by(self.segment_duration))
+            .collect::<Vec<_>>();
+        for (idx, segment) in segments.iter().enumerate() {
+            if idx + 1 == segments.len() || segments[idx + 1] != *segment {
+                parts.push((*segment, batch.slice(start, idx + 1 - start)));
+                start = idx + 1;
+            }
+        }
+
+        Ok(parts)
+    }
+
+    /// Sort rows by segment and primary keys, then write them to new ssts,
+    /// each of which only contains rows of one segment.
+    ///
+    /// Stream should be checked by `check_batch` before.
+    pub(crate) async fn write_unsorted_ssts(
+        &self,
+        schema: &StorageSchema,
+        stream: SendableRecordBatchStream,
+        sequence: u64,
+        time_range: &TimeRange,
+    ) -> Result<Vec<SstFile>> {
+        // Sort by segment first, so ssts of one segment are written one by
+        // one.
+        let segment_expr = schema.timestamp_column().map(|name| {
+            cast(ident(name), DataType::Int64) / lit(self.segment_duration.as_millis() as i64)
+        });
+        let mut sorted_stream =
+            self.parquet_writer
+                .sort_stream(stream, segment_expr, self.sort_memory_limit)?;
+
+        let mut ssts = Vec::new();
+        let mut current: Option<(Timestamp, FileId, SstWriter)> = None;
+        while let Some(batch) = sorted_stream.next().await {
+            let batch = batch.context("get sorted batch")?;
+            for (segment, part) in self.split_sorted_by_segment(batch, time_range)? {
+                if let Some((current_segment, _, writer)) = &current {
+                    if *current_segment != segment
+                        || writer.estimated_size() >= self.new_sst_max_size
+                    {
+                        let (_, file_id, writer) = current.take().unwrap();
+                        ssts.push(Self::finish_sst(writer, file_id, sequence, time_range).await?);
+                    }
+                }
+                let (_, _, writer) = match current.as_mut() {
+                    Some(current) => current,
+                    None => {
+                        let file_id = SstFile::allocate_id();
+                        let writer = self.parquet_writer.open(file_id)?;
+                        current.insert((segment, file_id, writer))
+                    }
+                };
+                let part = schema.fill_builtin_columns(part, sequence, RowKind::Put)?;
+                writer.write(part).await?;
+            }
+        }
+        if let Some((_, file_id, writer)) = current {
+            ssts.push(Self::finish_sst(writer, file_id, sequence, time_range).await?);
+        }
+
+        Ok(ssts)
+    }
+
+    /// Add ssts written outside of wal and memtable to manifest.
+    pub(crate) async fn commit_ssts(&self, ssts: Vec<SstFile>) -> Result<()> {
+        if ssts.is_empty() {
+            return Ok(());
+        }
+
+        // Writes with larger sequence may be added to the same segment before
+        // new ssts, which is fine. But writes with smaller sequence must be
+        // flushed first, otherwise they will be skipped by wal replay.
+        let _guard = self.write_lock.lock().await;
+        if let Some(memtables) = &self.memtables {
+            memtables.flush_all().await;
+        }
+        self.manifest
+            .update(ManifestUpdate::new(ssts, Vec::new()))
+            .await
+    }
+
+    async fn finish_sst(
+        writer: SstWriter,
+        file_id: FileId,
+        sequence: u64,
+        time_range: &TimeRange,
+    ) -> Result<SstFile> {
+        let summary = writer.close().await?;
+        let file_meta = FileMeta {
+            max_sequence: sequence,
+            num_rows: summary.num_rows as u32,
+            size: summary.size as u32,
+            time_range: summary.time_range.unwrap_or_else(|| time_range.clone()),
+            primary_key_stats: summary.primary_key_stats,
+            deduplicated: false,
+            merged: false,
+        };
+        Ok(SstFile::new(file_id, file_meta))
     }
 
-    async fn sort_batch(&self, batch: RecordBatch) -> Result<SendableRecordBatchStream> {
+    /// Scan rows of segment `inputs` in the order `req` requires, at most
+    /// `offset + limit` rows are returned, and `offset` is not skipped.
+    ///
+    /// Projections of `req` should contain required columns.
+    fn scan_segments(
+        &self,
+        schema: &StorageSchema,
+        inputs: BTreeMap<Timestamp, SegmentInputs>,
+        req: ScanRequest,
+    ) -> Result<SendableRecordBatchStream> {
+        let mut plan_for_all_segments = self
+            .plan_segments(inputs, req.projections, req.predicate, req.as_of_sequence)?
+            .into_iter()
+            .map(|(_, plan)| plan)
+            .collect::<Vec<_>>();
+        if plan_for_all_segments.is_empty() || req.limit == Some(0) {
+            return Ok(Box::pin(EmptyRecordBatchStream::new(
+                schema.arrow_schema.clone(),
+            )));
+        }
+        if req.reverse {
+            plan_for_all_segments.reverse();
+        }
+
+        // All segment plans share the same output schema.
+        let plan_schema = plan_for_all_segments[0].schema();
+        let sort_exprs = Self::build_scan_sort_exprs(schema, &plan_schema, req.order, req.reverse)?;
+        // Rows required from each segment, `offset` is skipped after merging.
+        let fetch = req.limit.map(|limit| limit + req.offset);
+        if req.order == ScanOrder::Time || req.reverse {
+            // Segment plans are sorted by sort key in ascending order, so they
+            // need to be resorted.
+            plan_for_all_segments = plan_for_all_segments
+                .into_iter()
+                .map(|plan| {
+                    Arc::new(SortExec::new(sort_exprs.clone(), plan).with_fetch(fetch))
+                        as Arc<dyn ExecutionPlan>
+                })
+                .collect();
+        }
+
         let ctx = SessionContext::default();
-        let schema = batch.schema();
-        let df_schema = DFSchema::try_from(self.schema().clone()).context("build DFSchema")?;
-        let sort_exprs = self.build_sort_exprs(&df_schema, false /* sort_seq */)?;
-        let batch_plan =
-            MemoryExec::try_new(&[vec![batch]], schema, None).context("build batch plan")?;
-        let physical_plan = Arc::new(SortExec::new(sort_exprs, Arc::new(batch_plan)));
+        let read_runtime = self.runtimes.read_runtime.clone();
+        let buffer_batches = self.scan_config.buffer_batches.max(1);
+        let stream: SendableRecordBatchStream = if req.order == ScanOrder::Time {
+            // Segments don't overlap in time, so they are returned one by one,
+            // with following segments read ahead concurrently, and remaining
+            // segments are skipped once the limit is satisfied.
+            let task_ctx = ctx.task_ctx();
+            let streams = futures::stream::iter(plan_for_all_segments)
+                .map(move |plan| {
+                    let stream = Self::spawn_plan(&read_runtime, plan, &task_ctx, buffer_batches);
+                    futures::future::ready(stream)
+                })
+                .buffered(self.scan_config.max_concurrent_segments.max(1))
+                .try_flatten();
+            Box::pin(RecordBatchStreamAdapter::new(plan_schema, streams))
+        } else if plan_for_all_segments.len() == 1 {
+            Self::spawn_plan(
+                &read_runtime,
+                plan_for_all_segments.remove(0),
+                &ctx.task_ctx(),
+                buffer_batches,
+            )
+            .context("execute stream")?
+        } else {
+            let union_exec = Arc::new(UnionExec::new(plan_for_all_segments));
+            let merge_exec =
+                Arc::new(SortPreservingMergeExec::new(sort_exprs, union_exec).with_fetch(fetch));
+            Self::spawn_plan(&read_runtime, merge_exec, &ctx.task_ctx(), buffer_batches)
+                .context("execute stream")?
+        };
+        Ok(stream)
+    }
+
+    /// Keep `version` alive until `stream` is dropped.
+    fn pin_version(
+        stream: SendableRecordBatchStream,
+        version: VersionRef,
+    ) -> SendableRecordBatchStream {
+        let schema = stream.schema();
+        let stream = stream.map(move |batch| {
+            let _pinned = &version;
+            batch
+        });
+        Box::pin(RecordBatchStreamAdapter::new(schema, stream))
+    }
+
+    /// Execute `plan` in `runtime`, results are sent back through a channel
+    /// buffering at most `buffer` batches.
+    ///
+    /// Tasks spawned when the plan is executed, such as those reading ssts of
+    /// a segment concurrently, are also run in `runtime`.
+    fn spawn_plan(
+        runtime: &Runtime,
+        plan: Arc<dyn ExecutionPlan>,
+        task_ctx: &Arc<TaskContext>,
+        buffer: usize,
+    ) -> DfResult<SendableRecordBatchStream> {
+        let _guard = runtime.enter();
+        let mut stream = execute_stream(plan, task_ctx.clone())?;
+        let mut builder = RecordBatchReceiverStream::builder(stream.schema(), buffer);
+        let tx = builder.tx();
+        builder.spawn(async move {
+            while let Some(batch) = stream.next().await {
+                if tx.send(batch).await.is_err() {
+                    // Receiver is dropped, such as the limit is satisfied.
+                    break;
+                }
+            }
+            Ok(())
+        });
+        Ok(builder.build())
+    }
 
-        let res =
-            execute_stream(physical_plan, ctx.task_ctx()).context("execute sort physical plan")?;
-        Ok(res)
+    /// Aggregate merged rows, statistics of ssts are used when possible.
+    async fn scan_aggregate(
+        &self,
+        schema: &StorageSchema,
+        mut inputs: BTreeMap<Timestamp, SegmentInputs>,
+        req: ScanRequest,
+        aggregate: AggregateRequest,
+    ) -> Result<SendableRecordBatchStream> {
+        aggregate.check(schema)?;
+        // Statistics cover all rows of ssts, including those newer than the
+        // sequence to read.
+        let stats = if req.predicate.is_empty()
+            && req.as_of_sequence.is_none()
+            && aggregate.support_stats(schema)
+        {
+            let mut aggregator = StatsAggregator::new(aggregate.aggregates.clone());
+            self.aggregate_by_stats(schema, &mut inputs, &mut aggregator)
+                .await?;
+            Some(aggregator)
+        } else {
+            None
+        };
+
+        let mut projections = aggregate.projections(schema)?;
+        // Columns of predicate are required to filter rows.
+        for expr in &req.predicate {
+            for column in expr.column_refs() {
+                let idx = schema.arrow_schema.index_of(&column.name).map_err(|_| {
+                    Error::InvalidRequest(format!("column not found, name:{}", column.name))
+                })?;
+                if !projections.contains(&idx) {
+                    projections.push(idx);
+                }
+            }
+        }
+        let mut projections = Some(projections);
+        schema.fill_required_projections(&mut projections);
+        let rows = self.scan_segments(
+            schema,
+            inputs,
+            ScanRequest {
+                range: req.range,
+                predicate: req.predicate,
+                projections,
+                order: ScanOrder::PrimaryKey,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                aggregate: None,
+                as_of_sequence: req.as_of_sequence,
+            },
+        )?;
+        aggregate.execute(schema, rows, req.reverse, stats).await
+    }
+
+    /// Build sort exprs for scan output of `plan_schema`, columns are
+    /// resolved by name since projections may reorder them.
+    ///
+    /// Timestamp column should exist when sorted by time.
+    fn build_scan_sort_exprs(
+        schema: &StorageSchema,
+        plan_schema: &SchemaRef,
+        order: ScanOrder,
+        reverse: bool,
+    ) -> Result<LexOrdering> {
+        let primary_keys = schema.arrow_schema.fields()[..schema.num_primary_keys]
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        let timestamp = schema.timestamp_column();
+        let columns: Vec<_> = match order {
+            ScanOrder::PrimaryKey => primary_keys.into_iter().chain(timestamp).collect(),
+            ScanOrder::Time => timestamp
+                .into_iter()
+                .chain(
+                    primary_keys
+                        .into_iter()
+                        .filter(|name| Some(*name) != timestamp),
+                )
+                .collect(),
+        };
+        let sort_exprs = columns
+            .into_iter()
+            .map(|name| ident(name).sort(!reverse /* asc */, !reverse /* nulls_first */))
+            .collect::<Vec<_>>();
+        let df_schema = DFSchema::try_from(plan_schema.clone()).context("build DFSchema")?;
+        let sort_exprs =
+            create_physical_sort_exprs(&sort_exprs, &df_schema, &ExecutionProps::default())
+                .context("create physical sort exprs")?;
+
+        Ok(sort_exprs)
     }
 
     fn build_write_props(write_options: WriteConfig, num_primary_key: usize) -> WriterProperties {
@@ -299,92 +1396,286 @@ impl CloudObjectStorage {
 
 #[async_trait]
 impl TimeMergeStorage for CloudObjectStorage {
-    fn schema(&self) -> &SchemaRef {
-        &self.schema.arrow_schema
+    fn schema(&self) -> SchemaRef {
+        self.schema.load().arrow_schema.clone()
     }
 
     async fn write(&self, req: WriteRequest) -> Result<()> {
-        if req.enable_check {
-            let segment_duration = self.segment_duration.as_millis() as i64;
-            ensure!(
-                req.time_range.start.0 / segment_duration
-                    == (req.time_range.end.0 - 1) / segment_duration,
-                "time range can't cross segment, value:{:?}",
-                &req.time_range
-            );
-        }
+        self.write_atomic(vec![req]).await
+    }
 
-        let num_rows = req.batch.num_rows();
-        let WriteResult {
-            id: file_id,
-            seq,
-            size: file_size,
-        } = self.write_batch(req.batch).await?;
-        let file_meta = FileMeta {
-            max_sequence: seq,
-            num_rows: num_rows as u32,
-            size: file_size as u32,
-            time_range: req.time_range,
+    async fn write_atomic(&self, reqs: Vec<WriteRequest>) -> Result<()> {
+        let time_ranges = reqs
+            .iter()
+            .map(|req| req.time_range.clone())
+            .collect::<Vec<_>>();
+        self.write_controller.admit(&time_ranges).await?;
+        // Batches of one write share the same schema.
+        let schema = self.schema.load();
+        let timestamp = Timestamp(now());
+        let write_keys = reqs
+            .iter()
+            .filter_map(|req| req.idempotency_key.clone())
+            .map(|key| WriteKey { key, timestamp })
+            .collect::<Vec<_>>();
+        ensure!(
+            write_keys
+                .iter()
+                .map(|k| &k.key)
+                .collect::<HashSet<_>>()
+                .len()
+                == write_keys.len(),
+            Error::InvalidRequest(format!(
+                "idempotency keys of one write should be unique, write_keys:{write_keys:?}"
+            ))
+        );
+        let batches = reqs
+            .into_iter()
+            .map(|req| {
+                let batch = Self::check_batch(
+                    &schema,
+                    self.segment_duration,
+                    req.batch,
+                    &req.time_range,
+                    req.enable_check,
+                )?;
+                Ok((req.time_range, batch))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.write_batches(batches, RowKind::Put, write_keys).await
+    }
+
+    async fn write_stream(&self, req: WriteStreamRequest) -> Result<()> {
+        self.write_controller
+            .admit(std::slice::from_ref(&req.time_range))
+            .await?;
+        let WriteStreamRequest {
+            stream,
+            time_range,
+            enable_check,
+        } = req;
+        let schema = self.schema.load();
+        let checked_stream = {
+            let schema = schema.clone();
+            let segment_duration = self.segment_duration;
+            let time_range = time_range.clone();
+            stream.map(move |batch| {
+                Self::check_batch(&schema, segment_duration, batch?, &time_range, enable_check)
+                    .map_err(|e| DataFusionError::External(Box::new(e)))
+            })
         };
-        self.manifest.add_file(file_id, file_meta).await?;
+        let checked_stream = Box::pin(RecordBatchStreamAdapter::new(
+            schema.user_schema(),
+            checked_stream,
+        ));
+        let sequence = Self::allocate_sequence();
+        let to_adds = self
+            .write_unsorted_ssts(&schema, checked_stream, sequence, &time_range)
+            .await?;
+        let num_ssts = to_adds.len();
+        self.commit_ssts(to_adds).await?;
+        info!(sequence, num_ssts, "Write stream finished");
 
         Ok(())
     }
 
     async fn scan(&self, mut req: ScanRequest) -> Result<SendableRecordBatchStream> {
-        let total_ssts = self.manifest.find_ssts(&req.range).await;
-        if total_ssts.is_empty() {
-            return Ok(Box::pin(EmptyRecordBatchStream::new(
-                self.schema.arrow_schema.clone(),
-            )));
+        let schema = self.schema.load();
+        ensure!(
+            req.order != ScanOrder::Time || schema.timestamp_idx.is_some(),
+            Error::InvalidRequest("scan by time requires timestamp column".to_string())
+        );
+        let (offset, limit) = (req.offset, req.limit);
+        // Ssts of the version are read by the stream, they are kept until the
+        // stream is dropped, even if they are compacted meanwhile.
+        let (version, inputs) = self.find_segment_inputs(&req.range, &req.predicate).await;
+        if let Some(sequence) = req.as_of_sequence {
+            for segment in inputs.keys() {
+                let min_sequence = version.min_readable_sequence(*segment);
+                ensure!(
+                    sequence >= min_sequence,
+                    Error::InvalidRequest(format!(
+                        "sequence is older than compacted rows, sequence:{sequence}, min_sequence:{min_sequence}, segment:{}",
+                        segment.0
+                    ))
+                );
+            }
+        }
+        let stream = match req.aggregate.take() {
+            Some(aggregate) => self.scan_aggregate(&schema, inputs, req, aggregate).await?,
+            None => {
+                schema.fill_required_projections(&mut req.projections);
+                self.scan_segments(&schema, inputs, req)?
+            }
+        };
+        let stream = Self::pin_version(stream, version);
+        if offset == 0 && limit.is_none() {
+            return Ok(stream);
         }
 
-        let ssts_by_segment = total_ssts.into_iter().group_by(|file| {
-            file.meta().time_range.start.0 / self.segment_duration.as_millis() as i64
-        });
-
-        let mut plan_for_all_segments = Vec::new();
-        self.schema.fill_required_projections(&mut req.projections);
-        for (_, ssts) in ssts_by_segment.sorted_by(|a, b| a.0.cmp(&b.0)) {
-            let plan = self.parquet_reader.build_df_plan(
-                ssts,
-                req.projections.clone(),
-                req.predicate.clone(),
-                false, // keep_builtin
-            )?;
+        let metrics = BaselineMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        Ok(Box::pin(LimitStream::new(stream, offset, limit, metrics)))
+    }
 
-            plan_for_all_segments.push(plan);
+    async fn delete(&self, req: DeleteRequest) -> Result<()> {
+        self.write_controller
+            .admit(std::slice::from_ref(&req.range))
+            .await?;
+        let mut predicate = req.predicate;
+        let schema = self.schema.load();
+        if let Some(idx) = schema.timestamp_idx {
+            let ts = cast(
+                ident(schema.arrow_schema.field(idx).name()),
+                DataType::Int64,
+            );
+            predicate.push(ts.clone().gt_eq(lit(req.range.start.0)));
+            predicate.push(ts.lt(lit(req.range.end.0)));
         }
 
         let ctx = SessionContext::default();
-        if plan_for_all_segments.len() == 1 {
-            let res = execute_stream(plan_for_all_segments.remove(0), ctx.task_ctx())
-                .context("execute stream")?;
-            return Ok(res);
+        let (_version, inputs) = self.find_segment_inputs(&req.range, &predicate).await;
+        let plans = self.plan_segments(inputs, None, predicate, None)?;
+        // Tombstones only take effect within their own segment, so rows of
+        // each segment are collected separately, then deleted together.
+        let mut batches = Vec::with_capacity(plans.len());
+        for (segment, plan) in plans {
+            let stream = execute_stream(plan, ctx.task_ctx()).context("execute stream")?;
+            let schema = stream.schema();
+            let segment_batches = stream
+                .try_collect::<Vec<_>>()
+                .await
+                .context("collect rows to delete")?;
+            let batch =
+                concat_batches(&schema, &segment_batches).context("concat rows to delete")?;
+            if batch.num_rows() == 0 {
+                continue;
+            }
+
+            let segment_end = segment + self.segment_duration.as_millis() as i64;
+            let time_range =
+                TimeRange::new(segment.max(req.range.start), segment_end.min(req.range.end));
+            info!(
+                segment = segment.0,
+                num_rows = batch.num_rows(),
+                "Delete rows"
+            );
+            batches.push((time_range, batch));
         }
 
-        let union_exec = Arc::new(UnionExec::new(plan_for_all_segments));
-        let res = execute_stream(union_exec, ctx.task_ctx()).context("execute stream")?;
-        return Ok(res);
+        self.write_batches(batches, RowKind::Delete, Vec::new())
+            .await
     }
 
     async fn compact(&self, _req: CompactRequest) -> Result<()> {
         self.compact_scheduler.trigger_compaction()
     }
+
+    async fn alter_schema(&self, req: AlterSchemaRequest) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let schema = self
+            .schema
+            .load()
+            .alter(&req.add_columns, &req.drop_columns)?;
+        let descriptor = TableDescriptor {
+            schema,
+            segment_duration: self.segment_duration,
+        };
+        self.schema_store.persist(&descriptor).await?;
+        let schema = descriptor.schema;
+        info!(
+            version = schema.version,
+            add_columns = ?req.add_columns,
+            drop_columns = ?req.drop_columns,
+            "Alter schema"
+        );
+        self.schema.store(schema);
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use datafusion::logical_expr::{col, lit};
-    use object_store::local::LocalFileSystem;
+    use std::{future::Future, ops::Range};
+
+    use arrow::{array::AsArray, datatypes::Field};
+    use common::ReadableSize;
+    use datafusion::{
+        assert_batches_eq,
+        logical_expr::{col, lit},
+    };
+    use object_store::{local::LocalFileSystem, ObjectStore, PutPayload};
     use test_log::test;
 
     use super::*;
-    use crate::{arrow_schema, record_batch, test_util::check_stream, types::Timestamp};
+    use crate::{
+        aggregate::{Aggregate, AggregateFunc},
+        arrow_schema,
+        config::{MemTableConfig, ScanConfig, SchedulerConfig, UpdateMode, WalConfig},
+        operator::{
+            register_merge_operator, MergeOperator, COUNTER_OPERATOR, MAX_OPERATOR, MIN_OPERATOR,
+            SUM_OPERATOR,
+        },
+        record_batch,
+        test_util::check_stream,
+        types::Timestamp,
+        wal::{ObjectStoreWal, Wal},
+    };
 
     fn build_runtimes() -> StorageRuntimes {
         let rt = Arc::new(Runtime::new().unwrap());
-        StorageRuntimes::new(rt.clone(), rt)
+        StorageRuntimes::new(rt.clone(), rt.clone(), rt)
+    }
+
+    /// Temp dir, store and runtimes to open storage in, the dir is removed
+    /// when it's dropped.
+    struct TestEnv {
+        root_dir: temp_dir::TempDir,
+        store: ObjectStoreRef,
+        runtimes: StorageRuntimes,
+    }
+
+    impl TestEnv {
+        fn new() -> Self {
+            Self {
+                root_dir: temp_dir::TempDir::new().unwrap(),
+                store: Arc::new(LocalFileSystem::new()),
+                runtimes: build_runtimes(),
+            }
+        }
+
+        fn block_on<F: Future>(&self, future: F) -> F::Output {
+            self.runtimes.sst_compact_runtime.block_on(future)
+        }
+
+        async fn open_storage(
+            &self,
+            segment_duration: Duration,
+            schema: SchemaRef,
+            num_primary_keys: usize,
+            config: StorageConfig,
+        ) -> Result<CloudObjectStorage> {
+            CloudObjectStorage::try_new(
+                self.root_dir.path().to_string_lossy().to_string(),
+                segment_duration,
+                self.store.clone(),
+                schema,
+                num_primary_keys,
+                config,
+                self.runtimes.clone(),
+            )
+            .await
+        }
+    }
+
+    /// Runs `test` with memtable disabled and then enabled in `config`.
+    fn run_with_and_without_memtable(config: StorageConfig, test: impl Fn(StorageConfig)) {
+        for enable in [false, true] {
+            let mut config = config.clone();
+            config.memtable.enable = enable;
+            test(config);
+        }
     }
 
     #[test(test)]
@@ -417,6 +1708,7 @@ mod tests {
                     batch,
                     time_range: (1..10).into(),
                     enable_check: true,
+                    idempotency_key: None,
                 })
                 .await
                 .unwrap();
@@ -432,6 +1724,7 @@ mod tests {
                     batch,
                     time_range: (10..20).into(),
                     enable_check: true,
+                    idempotency_key: None,
                 })
                 .await
                 .unwrap();
@@ -441,6 +1734,7 @@ mod tests {
                     range: TimeRange::new(Timestamp(0), Timestamp::MAX),
                     predicate: vec![],
                     projections: None,
+                    ..Default::default()
                 })
                 .await
                 .unwrap();
@@ -468,6 +1762,7 @@ mod tests {
                     range: TimeRange::new(Timestamp(0), Timestamp::MAX),
                     predicate: vec![expr],
                     projections: None,
+                    ..Default::default()
                 })
                 .await
                 .unwrap();
@@ -486,36 +1781,2155 @@ mod tests {
                 .unwrap(),
             ];
             check_stream(result_stream, expected_batch).await;
+
+            // Scan by time requires timestamp column.
+            let res = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    order: ScanOrder::Time,
+                    ..Default::default()
+                })
+                .await;
+            assert!(matches!(res, Err(Error::InvalidRequest(_))));
+        });
+    }
+
+    #[test(test)]
+    fn test_storage_replay_wal() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let path = root_dir.path().to_string_lossy().to_string();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            wal: WalConfig::ObjectStore,
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                path.clone(),
+                Duration::from_hours(2),
+                store.clone(),
+                schema.clone(),
+                1, // num_primary_keys
+                config.clone(),
+                runtimes.clone(),
+            )
+            .await
+            .unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![1, 2]))
+                        .unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+            drop(storage);
+
+            // Simulate a crash after the entry is appended to wal.
+            let wal = ObjectStoreWal::new(&path, store.clone());
+            assert!(wal.replay().await.unwrap().is_empty());
+            wal.append(&WalEntry {
+                sequence: SstFile::allocate_id(),
+                kind: RowKind::Put,
+                batches: vec![(
+                    (1..10).into(),
+                    record_batch!(("pk1", UInt8, vec![2, 3]), ("value", Int64, vec![20, 30]))
+                        .unwrap(),
+                )],
+                write_keys: Vec::new(),
+            })
+            .await
+            .unwrap();
+
+            let storage = CloudObjectStorage::try_new(
+                path,
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            assert!(wal.replay().await.unwrap().is_empty());
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![1, 20])).unwrap(),
+                record_batch!(("pk1", UInt8, vec![3]), ("value", Int64, vec![30])).unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
         });
     }
 
     #[test]
-    fn test_storage_sort_batch() {
-        let schema = arrow_schema!(("a", UInt8), ("b", UInt8), ("c", UInt8), ("c", UInt8));
+    fn test_storage_write_atomic() {
+        let config = StorageConfig {
+            wal: WalConfig::ObjectStore,
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_write_atomic_inner);
+    }
+
+    fn test_storage_write_atomic_inner(config: StorageConfig) {
+        let enable_memtable = config.memtable.enable;
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let open = || {
+                env.open_storage(
+                    Duration::from_millis(100),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    config.clone(),
+                )
+            };
+            let storage = open().await.unwrap();
+            let build_req = |pks: Vec<u8>, values: Vec<i64>, time_range: Range<i64>| WriteRequest {
+                batch: record_batch!(("pk1", UInt8, pks), ("value", Int64, values)).unwrap(),
+                time_range: time_range.into(),
+                enable_check: true,
+                idempotency_key: None,
+            };
+
+            // Nothing is written when any request is invalid.
+            assert!(storage
+                .write_atomic(vec![
+                    build_req(vec![1], vec![1], 0..10),
+                    build_req(vec![2], vec![2], 90..110),
+                ])
+                .await
+                .is_err_and(|e| matches!(e, Error::InvalidRequest(_))));
+            assert!(storage.manifest.all_ssts().await.is_empty());
+
+            storage
+                .write_atomic(vec![
+                    build_req(vec![3, 1], vec![3, 1], 0..10),
+                    build_req(vec![2], vec![2], 100..110),
+                    build_req(vec![2], vec![20], 20..30),
+                ])
+                .await
+                .unwrap();
+            if !enable_memtable {
+                // One sst for each segment, added in one manifest update.
+                let ssts = storage.manifest.all_ssts().await;
+                assert_eq!(2, ssts.len());
+                assert_eq!(ssts[0].meta().max_sequence, ssts[1].meta().max_sequence);
+            }
+
+            // Rows in memtable are recovered from wal after restart.
+            drop(storage);
+            let storage = open().await.unwrap();
+            let expected = [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2, 3]),
+                    ("value", Int64, vec![1, 20, 3])
+                )
+                .unwrap(),
+                record_batch!(("pk1", UInt8, vec![2]), ("value", Int64, vec![2])).unwrap(),
+            ];
+            for (segment, expected) in [0, 100].into_iter().zip(expected) {
+                let result_stream = storage
+                    .scan(ScanRequest {
+                        range: (segment..segment + 100).into(),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                let batches = result_stream.try_collect::<Vec<_>>().await.unwrap();
+                let batch = concat_batches(&schema, &batches).unwrap();
+                assert_eq!(expected, batch);
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_idempotent_write() {
+        // Duplicated writes are visible in append mode.
+        let config = StorageConfig {
+            update_mode: UpdateMode::Append,
+            wal: WalConfig::ObjectStore,
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_idempotent_write_inner);
+    }
+
+    fn test_storage_idempotent_write_inner(config: StorageConfig) {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Binary));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let open = || {
+                env.open_storage(
+                    Duration::from_hours(2),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    config.clone(),
+                )
+            };
+            let build_req = |value: &'static [u8], key: Option<&str>| WriteRequest {
+                batch: record_batch!(("pk1", UInt8, vec![1]), ("value", Binary, vec![value]))
+                    .unwrap(),
+                time_range: (1..10).into(),
+                enable_check: true,
+                idempotency_key: key.map(|k| k.to_string()),
+            };
+            let check_value = |storage: CloudObjectStorage, expected: &'static [u8]| async move {
+                let result_stream = storage
+                    .scan(ScanRequest {
+                        range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                let expected_batch =
+                    record_batch!(("pk1", UInt8, vec![1]), ("value", Binary, vec![expected]))
+                        .unwrap();
+                check_stream(result_stream, vec![expected_batch]).await;
+                storage
+            };
+
+            let storage = open().await.unwrap();
+            storage.write(build_req(b"a", Some("req-1"))).await.unwrap();
+            // Retry is ignored.
+            storage.write(build_req(b"a", Some("req-1"))).await.unwrap();
+            let storage = check_value(storage, b"a").await;
+
+            // Keys are recovered from wal or manifest after restart.
+            drop(storage);
+            let storage = open().await.unwrap();
+            // Writes mixing new and written keys are rejected as a whole.
+            assert!(matches!(
+                storage
+                    .write_atomic(vec![
+                        build_req(b"b", Some("req-2")),
+                        build_req(b"a", Some("req-1")),
+                    ])
+                    .await,
+                Err(Error::InvalidRequest(_))
+            ));
+            assert!(matches!(
+                storage
+                    .write_atomic(vec![
+                        build_req(b"b", Some("req-2")),
+                        build_req(b"b", Some("req-2")),
+                    ])
+                    .await,
+                Err(Error::InvalidRequest(_))
+            ));
+            let storage = check_value(storage, b"a").await;
+
+            let atomic_reqs = || {
+                vec![
+                    build_req(b"b", Some("req-2")),
+                    build_req(b"c", Some("req-3")),
+                ]
+            };
+            storage.write_atomic(atomic_reqs()).await.unwrap();
+            storage.write_atomic(atomic_reqs()).await.unwrap();
+            storage.write(build_req(b"d", None)).await.unwrap();
+            storage.write(build_req(b"d", None)).await.unwrap();
+            check_value(storage, b"abcdd").await;
+        });
+    }
+
+    #[test(test)]
+    fn test_storage_write_with_memtable() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
         let root_dir = temp_dir::TempDir::new().unwrap();
         let store = Arc::new(LocalFileSystem::new());
         let runtimes = build_runtimes();
+        let config = StorageConfig {
+            memtable: MemTableConfig {
+                enable: true,
+                max_rows: 6,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
         runtimes.sst_compact_runtime.clone().block_on(async move {
             let storage = CloudObjectStorage::try_new(
                 root_dir.path().to_string_lossy().to_string(),
                 Duration::from_hours(2),
                 store,
-                schema.clone(),
-                1,
-                StorageConfig::default(),
+                schema,
+                1, // num_primary_keys
+                config,
                 runtimes,
             )
             .await
             .unwrap();
-            let batch = record_batch!(
-                ("a", UInt8, vec![2, 1, 3, 4, 8, 6, 5, 7]),
-                ("b", UInt8, vec![1, 3, 4, 8, 2, 6, 5, 7]),
-                ("c", UInt8, vec![8, 6, 2, 4, 3, 1, 5, 7]),
-                ("d", UInt8, vec![2, 7, 4, 6, 1, 3, 5, 8])
+            let scan_all = || async {
+                storage
+                    .scan(ScanRequest {
+                        range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap()
+            };
+
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![3, 1, 2, 4, 5]),
+                        ("value", Int64, vec![3, 1, 2, 4, 5])
+                    )
+                    .unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+            // Writes in memtable are visible to scan.
+            assert!(storage.manifest.all_ssts().await.is_empty());
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2, 3, 4]),
+                    ("value", Int64, vec![1, 2, 3, 4])
+                )
+                .unwrap(),
+                record_batch!(("pk1", UInt8, vec![5]), ("value", Int64, vec![5])).unwrap(),
+            ];
+            check_stream(scan_all().await, expected_batch).await;
+
+            // Exceed max rows, memtable should be flushed into one sst.
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(("pk1", UInt8, vec![5, 6]), ("value", Int64, vec![50, 6]))
+                        .unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+            while storage.manifest.all_ssts().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let ssts = storage.manifest.all_ssts().await;
+            assert_eq!(1, ssts.len());
+            assert_eq!(7, ssts[0].meta().num_rows);
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2, 3, 4, 5]),
+                    ("value", Int64, vec![1, 2, 3, 4, 50])
+                )
+                .unwrap(),
+                record_batch!(("pk1", UInt8, vec![6]), ("value", Int64, vec![6])).unwrap(),
+            ];
+            check_stream(scan_all().await, expected_batch).await;
+        });
+    }
+
+    #[test(test)]
+    fn test_storage_write_split_by_segment() {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_millis(10),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
             )
+            .await
             .unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![1, 2, 3, 4, 5]),
+                        ("ts", Int64, vec![1, 15, 3, 12, 25]),
+                        ("value", Int64, vec![1, 2, 3, 4, 5])
+                    )
+                    .unwrap(),
+                    time_range: (0..30).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            let mut ssts = storage.manifest.all_ssts().await;
+            ssts.sort_by_key(|f| f.meta().time_range.start);
+            let ranges = ssts
+                .iter()
+                .map(|f| (f.meta().time_range.clone(), f.meta().num_rows))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                vec![
+                    (TimeRange::from(1..4), 2),
+                    (TimeRange::from(12..16), 2),
+                    (TimeRange::from(25..26), 1)
+                ],
+                ranges
+            );
+
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: (10..20).into(),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![2]),
+                    ("ts", Int64, vec![15]),
+                    ("value", Int64, vec![2])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![4]),
+                    ("ts", Int64, vec![12]),
+                    ("value", Int64, vec![4])
+                )
+                .unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_order() {
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            sort_by_timestamp: true,
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_scan_order_inner);
+    }
+
+    fn test_storage_scan_order_inner(config: StorageConfig) {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let storage = env
+                .open_storage(
+                    Duration::from_millis(10),
+                    schema,
+                    1, // num_primary_keys
+                    config,
+                )
+                .await
+                .unwrap();
+            for (pk1, ts, value) in [
+                (vec![1, 2, 1, 2], vec![15, 3, 5, 12], vec![1, 2, 3, 4]),
+                (vec![1], vec![5], vec![30]),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(
+                            ("pk1", UInt8, pk1),
+                            ("ts", Int64, ts),
+                            ("value", Int64, value)
+                        )
+                        .unwrap(),
+                        time_range: (0..20).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            // Rows with the same primary keys but different timestamps are all
+            // kept, and sorted across segments.
+            let scan_req = |order, reverse, offset, limit| ScanRequest {
+                range: (0..20).into(),
+                predicate: vec![],
+                projections: None,
+                order,
+                reverse,
+                offset,
+                limit,
+                ..Default::default()
+            };
+            let result_stream = storage
+                .scan(scan_req(ScanOrder::PrimaryKey, false, 0, None))
+                .await
+                .unwrap();
+            let expected_batch = [record_batch!(
+                ("pk1", UInt8, vec![1, 1, 2, 2]),
+                ("ts", Int64, vec![5, 15, 3, 12]),
+                ("value", Int64, vec![30, 1, 2, 4])
+            )
+            .unwrap()];
+            check_stream(result_stream, expected_batch).await;
+
+            let result_stream = storage
+                .scan(scan_req(ScanOrder::Time, false, 0, None))
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![2, 1]),
+                    ("ts", Int64, vec![3, 5]),
+                    ("value", Int64, vec![2, 30])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![2, 1]),
+                    ("ts", Int64, vec![12, 15]),
+                    ("value", Int64, vec![4, 1])
+                )
+                .unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+
+            // Latest rows are returned first.
+            let result_stream = storage
+                .scan(scan_req(ScanOrder::Time, true, 1, Some(2)))
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![2]),
+                    ("ts", Int64, vec![12]),
+                    ("value", Int64, vec![4])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![1]),
+                    ("ts", Int64, vec![5]),
+                    ("value", Int64, vec![30])
+                )
+                .unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+
+            let result_stream = storage
+                .scan(scan_req(ScanOrder::PrimaryKey, true, 0, Some(3)))
+                .await
+                .unwrap();
+            let expected_batch = [record_batch!(
+                ("pk1", UInt8, vec![2, 2, 1]),
+                ("ts", Int64, vec![12, 3, 15]),
+                ("value", Int64, vec![4, 2, 1])
+            )
+            .unwrap()];
+            check_stream(result_stream, expected_batch).await;
 
-            let mut sorted_batches = storage.sort_batch(batch).await.unwrap();
+            let result_stream = storage
+                .scan(scan_req(ScanOrder::PrimaryKey, false, 3, Some(5)))
+                .await
+                .unwrap();
+            let expected_batch = [record_batch!(
+                ("pk1", UInt8, vec![2]),
+                ("ts", Int64, vec![12]),
+                ("value", Int64, vec![4])
+            )
+            .unwrap()];
+            check_stream(result_stream, expected_batch).await;
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_concurrent_segments() {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let rt = Arc::new(Runtime::new().unwrap());
+        // Held outside, since runtime can't be dropped in async context.
+        let read_rt = Arc::new(Runtime::new().unwrap());
+        let runtimes = StorageRuntimes::new(rt.clone(), rt.clone(), read_rt.clone());
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            scan: ScanConfig {
+                max_concurrent_segments: 2,
+                buffer_batches: 1,
+            },
+            ..Default::default()
+        };
+        rt.block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_millis(10),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            // One sst for each segment.
+            for segment in 0..5 {
+                let ts = segment * 10;
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(
+                            ("pk1", UInt8, vec![2, 1]),
+                            ("ts", Int64, vec![ts, ts + 1]),
+                            ("value", Int64, vec![ts, ts + 1])
+                        )
+                        .unwrap(),
+                        time_range: (ts..ts + 2).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let scan_req = |order, reverse, limit| ScanRequest {
+                range: (0..50).into(),
+                predicate: vec![],
+                projections: Some(vec![2]),
+                order,
+                reverse,
+                limit,
+                ..Default::default()
+            };
+            let values = |req| {
+                let storage = &storage;
+                async move {
+                    let batches = storage
+                        .scan(req)
+                        .await
+                        .unwrap()
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .unwrap();
+                    batches
+                        .iter()
+                        .flat_map(|b| {
+                            let idx = b.schema().index_of("value").unwrap();
+                            b.column(idx)
+                                .as_primitive::<arrow::datatypes::Int64Type>()
+                                .values()
+                                .to_vec()
+                        })
+                        .collect::<Vec<_>>()
+                }
+            };
+
+            // Segments are returned in time order though read concurrently.
+            assert_eq!(
+                vec![0, 1, 10, 11, 20, 21, 30, 31, 40, 41],
+                values(scan_req(ScanOrder::Time, false, None)).await
+            );
+            assert_eq!(
+                vec![41, 40, 31],
+                values(scan_req(ScanOrder::Time, true, Some(3))).await
+            );
+            assert_eq!(
+                vec![1, 11, 21, 31, 41, 0, 10, 20, 30, 40],
+                values(scan_req(ScanOrder::PrimaryKey, false, None)).await
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_aggregate() {
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_scan_aggregate_inner);
+    }
+
+    fn test_storage_scan_aggregate_inner(config: StorageConfig) {
+        let schema = arrow_schema!(
+            ("pk1", UInt8),
+            ("pk2", UInt8),
+            ("ts", Int64),
+            ("value", Int64)
+        );
+        let env = TestEnv::new();
+        env.block_on(async {
+            let storage = env
+                .open_storage(
+                    Duration::from_millis(10),
+                    schema,
+                    2, // num_primary_keys
+                    config,
+                )
+                .await
+                .unwrap();
+            for batch in [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 1, 1, 2]),
+                    ("pk2", UInt8, vec![1, 2, 1, 1]),
+                    ("ts", Int64, vec![1, 3, 12, 15]),
+                    ("value", Int64, vec![10, 20, 30, 40])
+                )
+                .unwrap(),
+                // Overwrite the first row.
+                record_batch!(
+                    ("pk1", UInt8, vec![1]),
+                    ("pk2", UInt8, vec![1]),
+                    ("ts", Int64, vec![2]),
+                    ("value", Int64, vec![5])
+                )
+                .unwrap(),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range: (0..20).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let scan_req = |aggregate| ScanRequest {
+                range: (0..20).into(),
+                predicate: vec![],
+                projections: None,
+                aggregate: Some(aggregate),
+                ..Default::default()
+            };
+            let aggregate = AggregateRequest {
+                group_by_primary_keys: 1,
+                time_bucket: Some(Duration::from_millis(10)),
+                aggregates: vec![
+                    Aggregate::new(AggregateFunc::Count, "value"),
+                    Aggregate::new(AggregateFunc::Sum, "value"),
+                    Aggregate::new(AggregateFunc::Last, "value"),
+                ],
+            };
+            let batches = storage
+                .scan(scan_req(aggregate))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+----+--------------+------------+-------------+",
+                    "| pk1 | ts | count(value) | sum(value) | last(value) |",
+                    "+-----+----+--------------+------------+-------------+",
+                    "| 1   | 0  | 2            | 25         | 20          |",
+                    "| 1   | 10 | 1            | 30         | 30          |",
+                    "| 2   | 10 | 1            | 40         | 40          |",
+                    "+-----+----+--------------+------------+-------------+",
+                ],
+                &batches
+            );
+
+            let aggregate = AggregateRequest {
+                aggregates: vec![
+                    Aggregate::new(AggregateFunc::Count, "value"),
+                    Aggregate::new(AggregateFunc::Min, "ts"),
+                    Aggregate::new(AggregateFunc::Max, "value"),
+                ],
+                ..Default::default()
+            };
+            let batches = storage
+                .scan(scan_req(aggregate))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_batches_eq!(
+                [
+                    "+--------------+---------+------------+",
+                    "| count(value) | min(ts) | max(value) |",
+                    "+--------------+---------+------------+",
+                    "| 4            | 2       | 40         |",
+                    "+--------------+---------+------------+",
+                ],
+                &batches
+            );
+
+            let aggregate = AggregateRequest {
+                group_by_primary_keys: 3,
+                aggregates: vec![Aggregate::new(AggregateFunc::Count, "value")],
+                ..Default::default()
+            };
+            let res = storage.scan(scan_req(aggregate)).await;
+            assert!(matches!(res, Err(Error::InvalidRequest(_))));
+        });
+    }
+
+    #[test]
+    fn test_storage_delete() {
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_delete_inner);
+    }
+
+    fn test_storage_delete_inner(config: StorageConfig) {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let storage = env
+                .open_storage(
+                    Duration::from_hours(2),
+                    schema,
+                    1, // num_primary_keys
+                    config,
+                )
+                .await
+                .unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![1, 2, 3, 4, 5]),
+                        ("ts", Int64, vec![10, 20, 30, 40, 50]),
+                        ("value", Int64, vec![1, 2, 3, 4, 5])
+                    )
+                    .unwrap(),
+                    time_range: (0..100).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            // Delete by predicate.
+            storage
+                .delete(DeleteRequest {
+                    range: (0..100).into(),
+                    predicate: vec![col("value").gt(lit(3_i64))],
+                })
+                .await
+                .unwrap();
+            // Delete by time range.
+            storage
+                .delete(DeleteRequest {
+                    range: (0..25).into(),
+                    predicate: vec![],
+                })
+                .await
+                .unwrap();
+            // Rows written after tombstones are visible.
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![5]),
+                        ("ts", Int64, vec![60]),
+                        ("value", Int64, vec![50])
+                    )
+                    .unwrap(),
+                    time_range: (0..100).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: (0..100).into(),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![3]),
+                    ("ts", Int64, vec![30]),
+                    ("value", Int64, vec![3])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![5]),
+                    ("ts", Int64, vec![60]),
+                    ("value", Int64, vec![50])
+                )
+                .unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+        });
+    }
+
+    #[test]
+    fn test_storage_delete_by_value_predicate() {
+        run_with_and_without_memtable(
+            StorageConfig::default(),
+            test_storage_delete_by_value_predicate_inner,
+        );
+    }
+
+    fn test_storage_delete_by_value_predicate_inner(config: StorageConfig) {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let storage = env
+                .open_storage(
+                    Duration::from_hours(2),
+                    schema,
+                    1, // num_primary_keys
+                    config,
+                )
+                .await
+                .unwrap();
+            for (pk1, value) in [(vec![1, 2, 3], vec![1, 1, 1]), (vec![1, 3], vec![5, 7])] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(("pk1", UInt8, pk1), ("value", Int64, value)).unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            let scan = |predicate: Vec<Expr>| {
+                let storage = &storage;
+                async move {
+                    let stream = storage
+                        .scan(ScanRequest {
+                            range: (0..10).into(),
+                            predicate,
+                            projections: None,
+                            ..Default::default()
+                        })
+                        .await
+                        .unwrap();
+                    let schema = stream.schema();
+                    let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+                    concat_batches(&schema, &batches).unwrap()
+                }
+            };
+
+            // Only the latest value of pk1 2 matches, older values of other
+            // rows don't.
+            storage
+                .delete(DeleteRequest {
+                    range: (0..10).into(),
+                    predicate: vec![col("value").eq(lit(1_i64))],
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                record_batch!(("pk1", UInt8, vec![1, 3]), ("value", Int64, vec![5, 7])).unwrap(),
+                scan(vec![]).await
+            );
+
+            // Tombstone of pk1 3 hides its older value matching the predicate.
+            storage
+                .delete(DeleteRequest {
+                    range: (0..10).into(),
+                    predicate: vec![col("value").eq(lit(7_i64))],
+                })
+                .await
+                .unwrap();
+            assert_eq!(0, scan(vec![col("value").eq(lit(1_i64))]).await.num_rows());
+            assert_eq!(
+                record_batch!(("pk1", UInt8, vec![1]), ("value", Int64, vec![5])).unwrap(),
+                scan(vec![col("value").gt(lit(0_i64))]).await
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_alter_schema() {
+        run_with_and_without_memtable(StorageConfig::default(), test_storage_alter_schema_inner);
+    }
+
+    fn test_storage_alter_schema_inner(config: StorageConfig) {
+        let enable_memtable = config.memtable.enable;
+        let schema = arrow_schema!(("pk1", UInt8), ("v1", Int64), ("v2", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let open = |schema: SchemaRef, segment_duration| {
+                env.open_storage(
+                    segment_duration,
+                    schema,
+                    1, // num_primary_keys
+                    config.clone(),
+                )
+            };
+            let storage = open(schema.clone(), Duration::from_hours(2)).await.unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![1, 2]),
+                        ("v1", Int64, vec![1, 2]),
+                        ("v2", Int64, vec![10, 20])
+                    )
+                    .unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            storage
+                .alter_schema(AlterSchemaRequest {
+                    add_columns: vec![Arc::new(Field::new("v3", DataType::Utf8, true))],
+                    drop_columns: vec!["v1".to_string()],
+                })
+                .await
+                .unwrap();
+            // Dropped columns are rejected.
+            assert!(storage
+                .write(WriteRequest {
+                    batch: record_batch!(("pk1", UInt8, vec![3]), ("v1", Int64, vec![3])).unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .is_err());
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![2, 3]),
+                        ("v2", Int64, vec![200, 30]),
+                        ("v3", Utf8, vec!["b", "c"])
+                    )
+                    .unwrap(),
+                    time_range: (1..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2]),
+                    ("v2", Int64, vec![10, 200]),
+                    ("v3", Utf8, vec![None, Some("b")])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![3]),
+                    ("v2", Int64, vec![30]),
+                    ("v3", Utf8, vec!["c"])
+                )
+                .unwrap(),
+            ];
+            let scan_req = || ScanRequest {
+                range: (1..10).into(),
+                predicate: vec![],
+                projections: None,
+                ..Default::default()
+            };
+            let result_stream = storage.scan(scan_req()).await.unwrap();
+            check_stream(result_stream, expected_batch.clone()).await;
+
+            // Reopen should use the latest schema and same options.
+            drop(storage);
+            let new_schema = arrow_schema!(("pk1", UInt8), ("v2", Int64), ("v3", Utf8));
+            assert!(matches!(
+                open(schema.clone(), Duration::from_hours(2)).await,
+                Err(Error::InvalidSchema(_))
+            ));
+            assert!(matches!(
+                open(new_schema.clone(), Duration::from_hours(1)).await,
+                Err(Error::InvalidSchema(_))
+            ));
+            let storage = open(new_schema.clone(), Duration::from_hours(2))
+                .await
+                .unwrap();
+            assert_eq!(2, storage.schema.load().version);
+            assert_eq!(new_schema, storage.schema.load().user_schema());
+            if !enable_memtable {
+                let result_stream = storage.scan(scan_req()).await.unwrap();
+                check_stream(result_stream, expected_batch).await;
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_time_range_from_data() {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let batch = record_batch!(
+                ("pk1", UInt8, vec![1, 2]),
+                ("ts", Int64, vec![100, 50]),
+                ("value", Int64, vec![1, 2])
+            )
+            .unwrap();
+
+            // Given range doesn't cover data.
+            let res = storage
+                .write(WriteRequest {
+                    batch: batch.clone(),
+                    time_range: (0..100).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await;
+            assert!(res.is_err());
+            assert!(storage.manifest.all_ssts().await.is_empty());
+
+            // Given range is ignored when check is disabled.
+            storage
+                .write(WriteRequest {
+                    batch,
+                    time_range: (0..1).into(),
+                    enable_check: false,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+            let ranges = storage
+                .manifest
+                .all_ssts()
+                .await
+                .iter()
+                .map(|f| f.meta().time_range.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(vec![TimeRange::from(50..101)], ranges);
+        });
+    }
+
+    #[test]
+    fn test_storage_read_compacted_sst() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            scheduler: SchedulerConfig {
+                input_sst_min_num: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for (pk1, value) in [(vec![1, 2, 3], vec![1, 2, 3]), (vec![2, 3], vec![20, 30])] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(("pk1", UInt8, pk1), ("value", Int64, value)).unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            assert!(storage
+                .manifest
+                .all_ssts()
+                .await
+                .iter()
+                .all(|f| !f.meta().deduplicated));
+
+            storage.compact(CompactRequest {}).await.unwrap();
+            let mut ssts = storage.manifest.all_ssts().await;
+            while ssts.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ssts = storage.manifest.all_ssts().await;
+            }
+            // Output of compaction is read without merging.
+            assert!(ssts[0].meta().deduplicated);
+
+            let batches = storage
+                .scan(ScanRequest {
+                    range: (0..10).into(),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 1     |",
+                    "| 2   | 20    |",
+                    "| 3   | 30    |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+        });
+    }
+
+    /// Keep the first row, used to test custom merge operators.
+    #[derive(Debug)]
+    struct FirstValueOperator;
+
+    impl MergeOperator for FirstValueOperator {
+        fn merge(&self, batch: RecordBatch) -> Result<RecordBatch> {
+            Ok(batch.slice(0, 1))
+        }
+    }
+
+    #[test]
+    fn test_storage_merge_operators() {
+        register_merge_operator(
+            "test_first_value",
+            Arc::new(|_| Arc::new(FirstValueOperator)),
+        )
+        .unwrap();
+        let schema = arrow_schema!(("pk1", UInt8), ("v1", Int64), ("v2", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let open = |merge_operators: &[(&str, &str)]| {
+            let config = StorageConfig {
+                scheduler: SchedulerConfig {
+                    input_sst_min_num: 2,
+                    ..Default::default()
+                },
+                merge_operators: merge_operators
+                    .iter()
+                    .map(|(column, name)| (column.to_string(), name.to_string()))
+                    .collect(),
+                ..Default::default()
+            };
+            CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store.clone(),
+                schema.clone(),
+                1, // num_primary_keys
+                config,
+                runtimes.clone(),
+            )
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = open(&[("v1", "test_first_value")]).await.unwrap();
+            for (v1, v2) in [(vec![1, 2], vec![10, 20]), (vec![3, 4], vec![30, 40])] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(
+                            ("pk1", UInt8, vec![1, 2]),
+                            ("v1", Int64, v1),
+                            ("v2", Int64, v2)
+                        )
+                        .unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let expected = [
+                "+-----+----+----+",
+                "| pk1 | v1 | v2 |",
+                "+-----+----+----+",
+                "| 1   | 1  | 30 |",
+                "| 2   | 2  | 40 |",
+                "+-----+----+----+",
+            ];
+            let scan = || async {
+                storage
+                    .scan(ScanRequest {
+                        range: (0..10).into(),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap()
+            };
+            assert_batches_eq!(expected, &scan().await);
+
+            // Compaction merges rows by the same operators.
+            storage.compact(CompactRequest {}).await.unwrap();
+            while storage.manifest.all_ssts().await.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_batches_eq!(expected, &scan().await);
+
+            // Operators are persisted, and can't be changed on reopen.
+            drop(storage);
+            for merge_operators in [vec![], vec![("v2", "test_first_value")]] {
+                assert!(matches!(
+                    open(&merge_operators).await,
+                    Err(Error::InvalidSchema(_))
+                ));
+            }
+            let storage = open(&[("v1", "test_first_value")]).await.unwrap();
+            assert_eq!("test_first_value", storage.schema.load().merge_operator(1));
+        });
+    }
+
+    #[test]
+    fn test_storage_aggregate_merge_operators() {
+        let schema = arrow_schema!(
+            ("pk1", UInt8),
+            ("sum", Int64),
+            ("min", Int64),
+            ("max", Int64),
+            ("count", UInt64)
+        );
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            scheduler: SchedulerConfig {
+                input_sst_min_num: 2,
+                ..Default::default()
+            },
+            merge_operators: [
+                ("sum", SUM_OPERATOR),
+                ("min", MIN_OPERATOR),
+                ("max", MAX_OPERATOR),
+                ("count", COUNTER_OPERATOR),
+            ]
+            .into_iter()
+            .map(|(column, name)| (column.to_string(), name.to_string()))
+            .collect(),
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            // Raw samples are written with null count, and pre-aggregated
+            // ones with their count.
+            for (pk1, value, count) in [
+                (vec![1, 1, 2], vec![1, 5, 2], vec![None, None, None]),
+                (vec![1, 2], vec![3, 4], vec![Some(2), None]),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(
+                            ("pk1", UInt8, pk1),
+                            ("sum", Int64, value.clone()),
+                            ("min", Int64, value.clone()),
+                            ("max", Int64, value),
+                            ("count", UInt64, count)
+                        )
+                        .unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let expected = [
+                "+-----+-----+-----+-----+-------+",
+                "| pk1 | sum | min | max | count |",
+                "+-----+-----+-----+-----+-------+",
+                "| 1   | 9   | 1   | 5   | 4     |",
+                "| 2   | 6   | 2   | 4   | 2     |",
+                "+-----+-----+-----+-----+-------+",
+            ];
+            let scan = || async {
+                storage
+                    .scan(ScanRequest {
+                        range: (0..10).into(),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap()
+            };
+            assert_batches_eq!(expected, &scan().await);
+
+            // Compaction keeps one row for each primary key.
+            storage.compact(CompactRequest {}).await.unwrap();
+            let mut ssts = storage.manifest.all_ssts().await;
+            while ssts.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ssts = storage.manifest.all_ssts().await;
+            }
+            assert_eq!(2, ssts[0].meta().num_rows);
+            assert_batches_eq!(expected, &scan().await);
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_as_of_sequence() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let mut sequences = Vec::new();
+            for (pk1, value) in [(vec![1, 2], vec![1, 2]), (vec![2, 3], vec![20, 30])] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(("pk1", UInt8, pk1), ("value", Int64, value)).unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+                let ssts = storage.manifest.all_ssts().await;
+                sequences.push(ssts.iter().map(|f| f.meta().max_sequence).max().unwrap());
+            }
+            storage
+                .delete(DeleteRequest {
+                    range: (0..10).into(),
+                    predicate: vec![col("pk1").eq(lit(1_u8))],
+                })
+                .await
+                .unwrap();
+
+            let scan = |as_of_sequence| {
+                let storage = &storage;
+                async move {
+                    storage
+                        .scan(ScanRequest {
+                            range: (0..10).into(),
+                            predicate: vec![],
+                            projections: None,
+                            as_of_sequence,
+                            ..Default::default()
+                        })
+                        .await
+                        .unwrap()
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 1     |",
+                    "| 2   | 2     |",
+                    "+-----+-------+",
+                ],
+                &scan(Some(sequences[0])).await
+            );
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 1     |",
+                    "| 2   | 20    |",
+                    "| 3   | 30    |",
+                    "+-----+-------+",
+                ],
+                &scan(Some(sequences[1])).await
+            );
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 2   | 20    |",
+                    "| 3   | 30    |",
+                    "+-----+-------+",
+                ],
+                &scan(None).await
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_as_of_compacted_sequence() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            scheduler: SchedulerConfig {
+                input_sst_min_num: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for value in [1, 2] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(
+                            ("pk1", UInt8, vec![1]),
+                            ("value", Int64, vec![value])
+                        )
+                        .unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            let mut sequences = storage
+                .manifest
+                .all_ssts()
+                .await
+                .iter()
+                .map(|f| f.meta().max_sequence)
+                .collect::<Vec<_>>();
+            sequences.sort();
+
+            storage.compact(CompactRequest {}).await.unwrap();
+            while storage.manifest.all_ssts().await.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let scan = |as_of_sequence| {
+                storage.scan(ScanRequest {
+                    range: (0..10).into(),
+                    predicate: vec![],
+                    projections: None,
+                    as_of_sequence,
+                    ..Default::default()
+                })
+            };
+            // The first value is merged away by compaction.
+            assert!(matches!(
+                scan(Some(sequences[0])).await,
+                Err(Error::InvalidRequest(_))
+            ));
+            let batches = scan(Some(sequences[1]))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 2     |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_scan_pinned_version() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            scheduler: SchedulerConfig {
+                input_sst_min_num: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store.clone(),
+                schema,
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for (pk1, value) in [(vec![1, 2, 3], vec![1, 2, 3]), (vec![2, 3], vec![20, 30])] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(("pk1", UInt8, pk1), ("value", Int64, value)).unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            let old_paths = storage
+                .manifest
+                .all_ssts()
+                .await
+                .iter()
+                .map(|f| Path::from(storage.sst_path_gen.generate(f.id())))
+                .collect::<Vec<_>>();
+            assert_eq!(old_paths.len(), 2);
+
+            let stream = storage
+                .scan(ScanRequest {
+                    range: (0..10).into(),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            storage.compact(CompactRequest {}).await.unwrap();
+            while storage.manifest.all_ssts().await.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // Compacted ssts are kept while the stream pins them.
+            for path in &old_paths {
+                store.head(path).await.unwrap();
+            }
+
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 1     |",
+                    "| 2   | 20    |",
+                    "| 3   | 30    |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+
+            // Stream is consumed and dropped, compacted ssts are deleted.
+            for path in &old_paths {
+                while store.head(path).await.is_ok() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_delete_orphan_ssts() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let path = root_dir.path().to_string_lossy().to_string();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let open = || {
+                CloudObjectStorage::try_new(
+                    path.clone(),
+                    Duration::from_hours(2),
+                    store.clone(),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    StorageConfig::default(),
+                    runtimes.clone(),
+                )
+            };
+            let storage = open().await.unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![1, 2]))
+                        .unwrap(),
+                    time_range: (0..10).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+            let live_path = Path::from(
+                storage
+                    .sst_path_gen
+                    .generate(storage.manifest.all_ssts().await[0].id()),
+            );
+            // Such as a compacted sst pinned when the storage is closed.
+            let orphan_path = Path::from(storage.sst_path_gen.generate(SstFile::allocate_id()));
+            store
+                .put(&orphan_path, PutPayload::from_static(b"orphan"))
+                .await
+                .unwrap();
+            drop(storage);
+
+            let storage = open().await.unwrap();
+            assert!(store.head(&orphan_path).await.is_err());
+            store.head(&live_path).await.unwrap();
+            let stream = storage
+                .scan(ScanRequest {
+                    range: (0..10).into(),
+                    predicate: vec![],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 1   | 1     |",
+                    "| 2   | 2     |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_prune_ssts_by_primary_keys() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema,
+                1, // num_primary_keys
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for (pk1, value) in [
+                (vec![1, 2, 3], vec![1, 2, 3]),
+                (vec![10, 11, 12], vec![10, 11, 12]),
+                (vec![2, 11], vec![20, 110]),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch: record_batch!(("pk1", UInt8, pk1), ("value", Int64, value)).unwrap(),
+                        time_range: (0..10).into(),
+                        enable_check: true,
+                        idempotency_key: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let range = TimeRange::from(0..10);
+            let num_ssts = |predicate: Vec<Expr>| {
+                let storage = &storage;
+                let range = &range;
+                async move {
+                    storage
+                        .find_segment_inputs(range, &predicate)
+                        .await
+                        .1
+                        .values()
+                        .map(|inputs| inputs.ssts.len())
+                        .sum::<usize>()
+                }
+            };
+            assert_eq!(3, num_ssts(vec![]).await);
+            assert_eq!(2, num_ssts(vec![col("pk1").gt_eq(lit(10))]).await);
+            assert_eq!(1, num_ssts(vec![lit(1).eq(col("pk1"))]).await);
+            assert_eq!(
+                0,
+                num_ssts(vec![col("pk1").in_list(vec![lit(0), lit(20)], false)]).await
+            );
+            // Predicates on value columns can't prune ssts.
+            assert_eq!(3, num_ssts(vec![col("value").eq(lit(1))]).await);
+
+            // Rows of pruned ssts don't affect results.
+            let batches = storage
+                .scan(ScanRequest {
+                    range: (0..10).into(),
+                    predicate: vec![col("pk1").gt_eq(lit(10u8))],
+                    projections: None,
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_batches_eq!(
+                [
+                    "+-----+-------+",
+                    "| pk1 | value |",
+                    "+-----+-------+",
+                    "| 10  | 10    |",
+                    "| 11  | 110   |",
+                    "| 12  | 12    |",
+                    "+-----+-------+",
+                ],
+                &batches
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_write_stream() {
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            wal: WalConfig::ObjectStore,
+            // Small enough to spill sorted runs to disk.
+            write: WriteConfig {
+                sort_memory_limit: ReadableSize::kb(1),
+                ..Default::default()
+            },
+            // Every sst is rolled over after one batch.
+            scheduler: SchedulerConfig {
+                new_sst_max_size: ReadableSize(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        run_with_and_without_memtable(config, test_storage_write_stream_inner);
+    }
+
+    fn test_storage_write_stream_inner(config: StorageConfig) {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let env = TestEnv::new();
+        env.block_on(async {
+            let storage = env
+                .open_storage(
+                    Duration::from_millis(100),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    config,
+                )
+                .await
+                .unwrap();
+            storage
+                .write(WriteRequest {
+                    batch: record_batch!(
+                        ("pk1", UInt8, vec![1, 5]),
+                        ("ts", Int64, vec![10, 20]),
+                        ("value", Int64, vec![0, 0])
+                    )
+                    .unwrap(),
+                    time_range: (0..100).into(),
+                    enable_check: true,
+                    idempotency_key: None,
+                })
+                .await
+                .unwrap();
+
+            let batches = vec![
+                record_batch!(
+                    ("pk1", UInt8, vec![3, 1, 2]),
+                    ("ts", Int64, vec![150, 10, 20]),
+                    ("value", Int64, vec![1, 2, 3])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![4, 2, 0]),
+                    ("ts", Int64, vec![260, 160, 30]),
+                    ("value", Int64, vec![4, 5, 6])
+                )
+                .unwrap(),
+            ];
+            let stream = futures::stream::iter(batches.clone()).map(Ok);
+            let stream = Box::pin(RecordBatchStreamAdapter::new(schema.clone(), stream));
+            // Time range doesn't cover data.
+            assert!(storage
+                .write_stream(WriteStreamRequest {
+                    stream,
+                    time_range: (0..200).into(),
+                    enable_check: true,
+                })
+                .await
+                .is_err());
+            let stream = futures::stream::iter(batches.clone()).map(Ok);
+            let stream = Box::pin(RecordBatchStreamAdapter::new(schema.clone(), stream));
+            storage
+                .write_stream(WriteStreamRequest {
+                    stream,
+                    time_range: (0..300).into(),
+                    enable_check: true,
+                })
+                .await
+                .unwrap();
+
+            // Each sst only contains rows of one segment.
+            let ssts = storage.manifest.all_ssts().await;
+            assert!(ssts.len() >= 4);
+            for sst in ssts {
+                let time_range = &sst.meta().time_range;
+                assert_eq!(
+                    storage.segment_of(time_range),
+                    Timestamp(time_range.end.0 - 1).truncate_by(storage.segment_duration)
+                );
+            }
+
+            let expected = [
+                record_batch!(
+                    ("pk1", UInt8, vec![0, 1, 2, 5]),
+                    ("ts", Int64, vec![30, 10, 20, 20]),
+                    ("value", Int64, vec![6, 2, 3, 0])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![2, 3]),
+                    ("ts", Int64, vec![160, 150]),
+                    ("value", Int64, vec![5, 1])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![4]),
+                    ("ts", Int64, vec![260]),
+                    ("value", Int64, vec![4])
+                )
+                .unwrap(),
+            ];
+            for (segment, expected) in [0, 100, 200].into_iter().zip(expected) {
+                let result_stream = storage
+                    .scan(ScanRequest {
+                        range: (segment..segment + 100).into(),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                let batches = result_stream.try_collect::<Vec<_>>().await.unwrap();
+                let batch = concat_batches(&schema, &batches).unwrap();
+                assert_eq!(expected, batch);
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_ingest_external_files() {
+        let schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let config = StorageConfig {
+            timestamp_column: Some("ts".to_string()),
+            ..Default::default()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let root_path = root_dir.path().to_string_lossy().to_string();
+            let storage = CloudObjectStorage::try_new(
+                format!("{root_path}/storage"),
+                Duration::from_millis(100),
+                store.clone(),
+                schema.clone(),
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let put_file = |name: &str, batch: RecordBatch| {
+                let path = format!("{root_path}/external/{name}");
+                let mut buf = Vec::new();
+                let mut writer =
+                    parquet::arrow::ArrowWriter::try_new(&mut buf, batch.schema(), None).unwrap();
+                writer.write(&batch).unwrap();
+                writer.close().unwrap();
+                let store = store.clone();
+                async move {
+                    store
+                        .put(&Path::from(path.as_str()), buf.into())
+                        .await
+                        .unwrap();
+                    path
+                }
+            };
+
+            // Sorted with builtin columns, it's older than later writes.
+            let sorted = put_file(
+                "sorted.parquet",
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2]),
+                    ("ts", Int64, vec![10, 20]),
+                    ("value", Int64, vec![1, 2]),
+                    ("__seq__", UInt64, vec![1, 1]),
+                    ("__reserved__", UInt64, vec![None, None])
+                )
+                .unwrap(),
+            )
+            .await;
+            storage
+                .ingest_external_files(IngestRequest {
+                    files: vec![sorted],
+                    time_range: (0..100).into(),
+                })
+                .await
+                .unwrap();
+            let ssts = storage.manifest.all_ssts().await;
+            assert_eq!(1, ssts.len());
+            assert_eq!(1, ssts[0].meta().max_sequence);
+            assert_eq!(TimeRange::from(10..21), ssts[0].meta().time_range);
+
+            // Unknown columns are rejected.
+            let invalid = put_file(
+                "invalid.parquet",
+                record_batch!(("pk1", UInt8, vec![1]), ("ts", Int32, vec![10])).unwrap(),
+            )
+            .await;
+            assert!(storage
+                .ingest_external_files(IngestRequest {
+                    files: vec![invalid],
+                    time_range: (0..100).into(),
+                })
+                .await
+                .is_err());
+
+            // Unsorted without builtin columns, and value is missing.
+            let unsorted = put_file(
+                "unsorted.parquet",
+                record_batch!(
+                    ("pk1", UInt8, vec![3, 2, 1]),
+                    ("ts", Int64, vec![150, 30, 40])
+                )
+                .unwrap(),
+            )
+            .await;
+            storage
+                .ingest_external_files(IngestRequest {
+                    files: vec![unsorted],
+                    time_range: (0..200).into(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(3, storage.manifest.all_ssts().await.len());
+
+            let expected = [
+                record_batch!(
+                    ("pk1", UInt8, vec![1, 2]),
+                    ("ts", Int64, vec![40, 30]),
+                    ("value", Int64, vec![None, None])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![3]),
+                    ("ts", Int64, vec![150]),
+                    ("value", Int64, vec![None])
+                )
+                .unwrap(),
+            ];
+            for (segment, expected) in [0, 100].into_iter().zip(expected) {
+                let result_stream = storage
+                    .scan(ScanRequest {
+                        range: (segment..segment + 100).into(),
+                        predicate: vec![],
+                        projections: None,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                let batches = result_stream.try_collect::<Vec<_>>().await.unwrap();
+                let batch = concat_batches(&schema, &batches).unwrap();
+                assert_eq!(expected, batch);
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_sort_batch() {
+        let schema = arrow_schema!(("a", UInt8), ("b", UInt8), ("c", UInt8), ("c", UInt8));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1,
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let batch = record_batch!(
+                ("a", UInt8, vec![2, 1, 3, 4, 8, 6, 5, 7]),
+                ("b", UInt8, vec![1, 3, 4, 8, 2, 6, 5, 7]),
+                ("c", UInt8, vec![8, 6, 2, 4, 3, 1, 5, 7]),
+                ("d", UInt8, vec![2, 7, 4, 6, 1, 3, 5, 8])
+            )
+            .unwrap();
+
+            let mut sorted_batches = storage
+                .parquet_writer
+                .sort_batch(batch, false /* sort_seq */)
+                .await
+                .unwrap();
             let expected_bacth = record_batch!(
                 ("a", UInt8, vec![1, 2, 3, 4, 5, 6, 7, 8]),
                 ("b", UInt8, vec![3, 1, 4, 8, 5, 6, 7, 2]),
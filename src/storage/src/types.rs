@@ -16,21 +16,30 @@
 // under the License.
 
 use std::{
+    collections::HashMap,
     fmt,
     ops::{Add, Deref, Range},
-    sync::Arc,
+    sync::{Arc, RwLock},
     time::Duration,
 };
 
 use anyhow::Context;
 use arrow::{
-    array::{RecordBatch, UInt64Array},
-    datatypes::{DataType, Field, FieldRef, Schema, SchemaRef},
+    array::{new_null_array, Array, AsArray, Int64Array, RecordBatch, UInt64Array},
+    compute::{cast, max, min},
+    datatypes::{DataType, Field, FieldRef, Int64Type, Schema, SchemaRef, TimeUnit},
+    row::{RowConverter, SortField},
 };
 use object_store::ObjectStore;
 use tokio::runtime::Runtime;
 
-use crate::{config::UpdateMode, ensure, sst::FileId, Result};
+use crate::{
+    config::UpdateMode,
+    ensure,
+    operator::{find_merge_operator, BYTES_MERGE_OPERATOR, LAST_VALUE_OPERATOR},
+    sst::{FileId, PrimaryKeyStats},
+    Error, Result,
+};
 
 pub const BUILTIN_COLUMN_NUM: usize = 2;
 /// Seq column is a builtin column, and it will be appended to the end of
@@ -40,6 +49,36 @@ pub const SEQ_COLUMN_NAME: &str = "__seq__";
 /// tombstone/expiration bit-flags.
 pub const RESERVED_COLUMN_NAME: &str = "__reserved__";
 
+/// Kind of a row, stored in the reserved column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowKind {
+    /// Normal rows, reserved column is null for them.
+    Put,
+    /// Tombstone rows, which delete all older rows with the same primary keys
+    /// in the segment.
+    Delete,
+}
+
+impl RowKind {
+    const DELETE_FLAG: u64 = 1;
+
+    /// Decode kind from value of the reserved column.
+    pub fn from_reserved(reserved: &UInt64Array, idx: usize) -> Self {
+        if reserved.is_valid(idx) && reserved.value(idx) & Self::DELETE_FLAG != 0 {
+            RowKind::Delete
+        } else {
+            RowKind::Put
+        }
+    }
+
+    fn reserved_array(&self, num_rows: usize) -> UInt64Array {
+        match self {
+            RowKind::Put => UInt64Array::new_null(num_rows),
+            RowKind::Delete => UInt64Array::from_value(Self::DELETE_FLAG, num_rows),
+        }
+    }
+}
+
 pub type RuntimeRef = Arc<Runtime>;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -130,14 +169,42 @@ impl TimeRange {
         self.0.start = self.0.start.min(other.0.start);
         self.0.end = self.0.end.max(other.0.end);
     }
+
+    pub fn contains(&self, other: &TimeRange) -> bool {
+        self.0.start <= other.0.start && other.0.end <= self.0.end
+    }
 }
 
 pub type ObjectStoreRef = Arc<dyn ObjectStore>;
 
+/// Latest schema of the storage, shared by all components, which will be
+/// replaced when schema is altered.
+#[derive(Debug, Clone)]
+pub struct SharedSchema(Arc<RwLock<Arc<StorageSchema>>>);
+
+impl SharedSchema {
+    pub fn new(schema: StorageSchema) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(schema))))
+    }
+
+    pub fn load(&self) -> Arc<StorageSchema> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, schema: StorageSchema) {
+        *self.0.write().unwrap() = Arc::new(schema);
+    }
+}
+
 pub struct WriteResult {
     pub id: FileId,
     pub seq: u64,
     pub size: usize,
+    /// Computed from timestamp column, see [`WriteSummary`].
+    ///
+    /// [`WriteSummary`]: crate::write::WriteSummary
+    pub time_range: Option<TimeRange>,
+    pub primary_key_stats: Option<PrimaryKeyStats>,
 }
 
 /// The schema is like:
@@ -146,32 +213,89 @@ pub struct WriteResult {
 /// ```
 /// seq and reserved are builtin columns, and they will be appended to the end
 /// of the original schema.
+///
+/// Timestamp column is optional, when it's set, rows will be partitioned into
+/// segments by its value. It must be `Int64` or `Timestamp(Millisecond)`.
+///
+/// Rows are sorted and deduplicated by the sort key, which is composed of
+/// primary keys, followed by the timestamp column when `sort_by_timestamp` is
+/// enabled.
+///
+/// Value columns can be added or dropped by altering, which bumps the version.
+/// Ssts written with older versions are projected onto the latest one by
+/// column name when read.
 #[derive(Debug, Clone)]
 pub struct StorageSchema {
+    pub version: u64,
+    /// Columns dropped in previous versions.
+    pub dropped_columns: Vec<String>,
     pub arrow_schema: SchemaRef,
     pub num_primary_keys: usize,
     pub seq_idx: usize,
     pub reserved_idx: usize,
     pub value_idxes: Vec<usize>,
+    pub timestamp_idx: Option<usize>,
     pub update_mode: UpdateMode,
+    /// Append timestamp column to the sort key after primary keys.
+    pub sort_by_timestamp: bool,
+    /// Names of merge operators of value columns, keyed by column name,
+    /// columns not in it use the default one of `update_mode`.
+    pub merge_operators: HashMap<String, String>,
 }
 
 impl StorageSchema {
     pub fn try_new(
         arrow_schema: SchemaRef,
         num_primary_keys: usize,
+        timestamp_column: Option<&str>,
         update_mode: UpdateMode,
     ) -> Result<Self> {
-        ensure!(num_primary_keys > 0, "num_primary_keys should large than 0");
+        ensure!(
+            num_primary_keys > 0,
+            Error::InvalidSchema("num_primary_keys should large than 0".to_string())
+        );
 
         let fields = arrow_schema.fields();
         ensure!(
             !fields.iter().any(Self::is_builtin_field),
-            "schema should not use builtin columns name"
+            Error::InvalidSchema("schema should not use builtin columns name".to_string())
         );
+        let timestamp_idx = match timestamp_column {
+            Some(name) => {
+                let (idx, field) = arrow_schema.column_with_name(name).ok_or_else(|| {
+                    Error::InvalidSchema(format!("timestamp column not found, name:{name}"))
+                })?;
+                ensure!(
+                    matches!(
+                        field.data_type(),
+                        DataType::Int64 | DataType::Timestamp(TimeUnit::Millisecond, _)
+                    ),
+                    Error::InvalidSchema(format!(
+                        "invalid timestamp column type, name:{name}, type:{}",
+                        field.data_type()
+                    ))
+                );
+                Some(idx)
+            }
+            None => None,
+        };
 
         let value_idxes = (num_primary_keys..arrow_schema.fields.len()).collect::<Vec<_>>();
-        ensure!(!value_idxes.is_empty(), "no value column found");
+        ensure!(
+            !value_idxes.is_empty(),
+            Error::InvalidSchema("no value column found".to_string())
+        );
+        // Rows are merged by comparing primary keys in row format.
+        for field in &fields[..num_primary_keys] {
+            ensure!(
+                RowConverter::new(vec![SortField::new(field.data_type().clone())]).is_ok(),
+                Error::InvalidSchema(format!(
+                    "unsupported primary key type, name:{}, type:{}",
+                    field.name(),
+                    field.data_type()
+                ))
+            );
+        }
 
         let mut new_fields = arrow_schema.fields().clone().to_vec();
         new_fields.extend_from_slice(&[
@@ -186,40 +310,275 @@ impl StorageSchema {
             arrow_schema.metadata.clone(),
         ));
         Ok(Self {
+            version: 1,
+            dropped_columns: Vec::new(),
             arrow_schema,
             num_primary_keys,
             seq_idx,
             reserved_idx,
             value_idxes,
+            timestamp_idx,
             update_mode,
+            sort_by_timestamp: false,
+            merge_operators: HashMap::new(),
         })
     }
 
+    /// Rows with the same primary keys but different timestamps are kept
+    /// separately when enabled, it requires a timestamp column.
+    pub fn with_sort_by_timestamp(mut self, enable: bool) -> Result<Self> {
+        ensure!(
+            !enable || self.timestamp_idx.is_some(),
+            Error::InvalidSchema("sort_by_timestamp requires timestamp column".to_string())
+        );
+        self.sort_by_timestamp = enable;
+        Ok(self)
+    }
+
+    /// Set merge operators of value columns by name, operators should be
+    /// registered already.
+    pub fn with_merge_operators(mut self, operators: HashMap<String, String>) -> Result<Self> {
+        for (column, name) in &operators {
+            let (idx, _) = self.arrow_schema.column_with_name(column).ok_or_else(|| {
+                Error::InvalidSchema(format!(
+                    "column of merge operator not found, column:{column}"
+                ))
+            })?;
+            ensure!(
+                self.value_idxes.contains(&idx),
+                Error::InvalidSchema(format!(
+                    "merge operator is only for value columns, column:{column}"
+                ))
+            );
+            let factory = find_merge_operator(name).ok_or_else(|| {
+                Error::InvalidSchema(format!(
+                    "merge operator not registered, column:{column}, name:{name}"
+                ))
+            })?;
+            factory(vec![idx]).check_type(self.arrow_schema.field(idx).data_type())?;
+        }
+        self.merge_operators = operators;
+        Ok(self)
+    }
+
+    /// Name of the merge operator of value column at `idx`.
+    pub fn merge_operator(&self, idx: usize) -> &str {
+        let column = self.arrow_schema.field(idx).name();
+        match self.merge_operators.get(column) {
+            Some(name) => name,
+            None => match self.update_mode {
+                UpdateMode::Overwrite => LAST_VALUE_OPERATOR,
+                UpdateMode::Append => BYTES_MERGE_OPERATOR,
+            },
+        }
+    }
+
+    /// Indexes of columns in the sort key.
+    pub fn sort_key_idxes(&self) -> Vec<usize> {
+        let mut idxes = (0..self.num_primary_keys).collect::<Vec<_>>();
+        if let Some(idx) = self.timestamp_idx {
+            if self.sort_by_timestamp && idx >= self.num_primary_keys {
+                idxes.push(idx);
+            }
+        }
+        idxes
+    }
+
     pub fn is_builtin_field(f: &FieldRef) -> bool {
         f.name() == SEQ_COLUMN_NAME || f.name() == RESERVED_COLUMN_NAME
     }
 
-    /// Primary keys and builtin columns are required when query.
+    pub fn timestamp_column(&self) -> Option<&str> {
+        self.timestamp_idx
+            .map(|idx| self.arrow_schema.field(idx).name().as_str())
+    }
+
+    /// Schema defined by user, without builtin columns.
+    pub fn user_schema(&self) -> SchemaRef {
+        let fields = &self.arrow_schema.fields()[..self.seq_idx];
+        Arc::new(Schema::new_with_metadata(
+            fields.to_vec(),
+            self.arrow_schema.metadata.clone(),
+        ))
+    }
+
+    /// Returns a new version of schema with columns added and dropped.
+    ///
+    /// Only nullable value columns can be added, and dropped columns can't be
+    /// added again, since old ssts may still contain them.
+    pub fn alter(&self, add_columns: &[FieldRef], drop_columns: &[String]) -> Result<Self> {
+        let user_schema = self.user_schema();
+        let timestamp_column = self
+            .timestamp_idx
+            .map(|idx| self.arrow_schema.field(idx).name().clone());
+        for name in drop_columns {
+            let (idx, _) = user_schema.column_with_name(name).ok_or_else(|| {
+                Error::InvalidSchema(format!("column to drop not found, name:{name}"))
+            })?;
+            ensure!(
+                idx >= self.num_primary_keys,
+                Error::InvalidSchema(format!("primary key column can't be dropped, name:{name}"))
+            );
+            ensure!(
+                Some(idx) != self.timestamp_idx,
+                Error::InvalidSchema(format!("timestamp column can't be dropped, name:{name}"))
+            );
+        }
+        for field in add_columns {
+            let name = field.name();
+            ensure!(
+                field.is_nullable(),
+                Error::InvalidSchema(format!("added column should be nullable, name:{name}"))
+            );
+            ensure!(
+                user_schema.column_with_name(name).is_none(),
+                Error::InvalidSchema(format!("column already exists, name:{name}"))
+            );
+            ensure!(
+                !self.dropped_columns.contains(name),
+                Error::InvalidSchema(format!("column is dropped before, name:{name}"))
+            );
+        }
+
+        let fields = user_schema
+            .fields()
+            .iter()
+            .filter(|f| !drop_columns.contains(f.name()))
+            .chain(add_columns)
+            .cloned()
+            .collect::<Vec<_>>();
+        let arrow_schema = Arc::new(Schema::new_with_metadata(
+            fields,
+            user_schema.metadata.clone(),
+        ));
+        let mut schema = Self::try_new(
+            arrow_schema,
+            self.num_primary_keys,
+            timestamp_column.as_deref(),
+            self.update_mode,
+        )?
+        .with_sort_by_timestamp(self.sort_by_timestamp)?
+        .with_merge_operators(
+            self.merge_operators
+                .iter()
+                .filter(|(column, _)| !drop_columns.contains(column))
+                .map(|(column, name)| (column.clone(), name.clone()))
+                .collect(),
+        )?;
+        schema.version = self.version + 1;
+        schema.dropped_columns = self
+            .dropped_columns
+            .iter()
+            .chain(drop_columns)
+            .cloned()
+            .collect();
+
+        Ok(schema)
+    }
+
+    /// Project batch written by older versions onto this schema, columns are
+    /// matched by name, missing ones are filled with nulls, and dropped ones
+    /// are removed.
+    ///
+    /// The result contains builtin columns only when the input has.
+    pub fn project_batch(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        let target_schema = if batch.schema().column_with_name(SEQ_COLUMN_NAME).is_some() {
+            self.arrow_schema.clone()
+        } else {
+            self.user_schema()
+        };
+        if batch.schema().fields() == target_schema.fields() {
+            return Ok(batch);
+        }
+
+        let num_rows = batch.num_rows();
+        let columns = target_schema
+            .fields()
+            .iter()
+            .map(|field| match batch.column_by_name(field.name()) {
+                Some(column) => {
+                    ensure!(
+                        column.data_type() == field.data_type(),
+                        Error::InvalidSchema(format!(
+                            "column type mismatch, name:{}, expected:{}, actual:{}",
+                            field.name(),
+                            field.data_type(),
+                            column.data_type()
+                        ))
+                    );
+                    Ok(column.clone())
+                }
+                None => {
+                    ensure!(
+                        field.is_nullable(),
+                        Error::InvalidSchema(format!(
+                            "non-nullable column is missing, name:{}",
+                            field.name()
+                        ))
+                    );
+                    Ok(new_null_array(field.data_type(), num_rows))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let batch =
+            RecordBatch::try_new(target_schema, columns).context("build projected batch")?;
+        Ok(batch)
+    }
+
+    /// Returns values of timestamp column in milliseconds, `None` means there
+    /// is no timestamp column.
+    pub fn timestamps(&self, batch: &RecordBatch) -> Result<Option<Int64Array>> {
+        let Some(idx) = self.timestamp_idx else {
+            return Ok(None);
+        };
+
+        let column = batch.column(idx);
+        ensure!(
+            column.null_count() == 0,
+            Error::InvalidRequest("timestamp column should not contain null".to_string())
+        );
+        let column = cast(column, &DataType::Int64).context("cast timestamp column")?;
+        Ok(Some(column.as_primitive::<Int64Type>().clone()))
+    }
+
+    /// Returns time range of all rows computed from timestamp column, `None`
+    /// means there is no timestamp column or batch is empty.
+    pub fn time_range(&self, batch: &RecordBatch) -> Result<Option<TimeRange>> {
+        let Some(timestamps) = self.timestamps(batch)? else {
+            return Ok(None);
+        };
+
+        let time_range = min(&timestamps)
+            .zip(max(&timestamps))
+            .map(|(min, max)| TimeRange::new(min.into(), (max + 1).into()));
+        Ok(time_range)
+    }
+
+    /// Sort key, timestamp and builtin columns are required when query.
     pub fn fill_required_projections(&self, projection: &mut Option<Vec<usize>>) {
         if let Some(proj) = projection.as_mut() {
-            for i in 0..self.num_primary_keys {
+            for i in self.sort_key_idxes().into_iter().chain(self.timestamp_idx) {
                 if !proj.contains(&i) {
                     proj.push(i);
                 }
             }
-            // For builtin columns, reserved column is not used for now,
-            // so only add seq column.
-            if !proj.contains(&self.seq_idx) {
-                proj.push(self.seq_idx);
-            }
+            // Builtin columns are required by merge, and they must be at the
+            // end, since they will be removed from the end after merge.
+            proj.retain(|idx| *idx != self.seq_idx && *idx != self.reserved_idx);
+            proj.push(self.seq_idx);
+            proj.push(self.reserved_idx);
         }
     }
 
     /// Builtin columns are always appended to the end of the schema.
+    ///
+    /// Batch may be written with older versions of schema, which is kept.
     pub fn fill_builtin_columns(
         &self,
         record_batch: RecordBatch,
         sequence: u64,
+        kind: RowKind,
     ) -> Result<RecordBatch> {
         let num_rows = record_batch.num_rows();
         if num_rows == 0 {
@@ -229,10 +588,17 @@ impl StorageSchema {
         let mut columns = record_batch.columns().to_vec();
         let seq_array = UInt64Array::from_iter_values((0..num_rows).map(|_| sequence));
         columns.push(Arc::new(seq_array));
-        let reserved_array = UInt64Array::new_null(num_rows);
-        columns.push(Arc::new(reserved_array));
-
-        let new_batch = RecordBatch::try_new(self.arrow_schema.clone(), columns)
+        columns.push(Arc::new(kind.reserved_array(num_rows)));
+
+        let schema = record_batch.schema();
+        let fields = schema
+            .fields()
+            .iter()
+            .chain(&self.arrow_schema.fields()[self.seq_idx..])
+            .cloned()
+            .collect::<Vec<_>>();
+        let schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata.clone()));
+        let new_batch = RecordBatch::try_new(schema, columns)
             .context("construct record batch with seq column")?;
 
         Ok(new_batch)
@@ -241,7 +607,11 @@ impl StorageSchema {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{arrow_schema, record_batch};
+    use crate::{
+        arrow_schema,
+        operator::{HLL_OPERATOR, SUM_OPERATOR},
+        record_batch,
+    };
 
     #[test]
     fn test_timestamp_truncate_by() {
@@ -263,13 +633,78 @@ mod tests {
     #[test]
     fn test_build_storage_schema() {
         let arrow_schema = arrow_schema!(("pk1", UInt8), ("pk2", UInt8), ("value", Int64));
-        let schema = StorageSchema::try_new(arrow_schema.clone(), 2, UpdateMode::Append).unwrap();
+        let schema =
+            StorageSchema::try_new(arrow_schema.clone(), 2, None, UpdateMode::Append).unwrap();
         assert_eq!(schema.value_idxes, vec![2]);
         assert_eq!(schema.seq_idx, 3);
         assert_eq!(schema.reserved_idx, 4);
 
         // No value column exists
-        assert!(StorageSchema::try_new(arrow_schema, 3, UpdateMode::Append).is_err());
+        assert!(StorageSchema::try_new(arrow_schema.clone(), 3, None, UpdateMode::Append).is_err());
+
+        // Primary keys must be comparable in row format.
+        let key_schema = Arc::new(Schema::new(vec![
+            Field::new("pk1", DataType::Utf8, true),
+            Field::new(
+                "pk2",
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                true,
+            ),
+            Field::new("pk3", DataType::FixedSizeBinary(4), true),
+            Field::new(
+                "pk4",
+                DataType::FixedSizeList(Arc::new(Field::new("item", DataType::Int32, true)), 2),
+                true,
+            ),
+            Field::new("value", DataType::Int64, true),
+        ]));
+        assert!(StorageSchema::try_new(key_schema.clone(), 3, None, UpdateMode::Overwrite).is_ok());
+        assert!(matches!(
+            StorageSchema::try_new(key_schema, 4, None, UpdateMode::Overwrite),
+            Err(Error::InvalidSchema(_))
+        ));
+
+        // Timestamp column must exist and be int64.
+        assert!(
+            StorageSchema::try_new(arrow_schema.clone(), 2, Some("ts"), UpdateMode::Append)
+                .is_err()
+        );
+        assert!(
+            StorageSchema::try_new(arrow_schema.clone(), 2, Some("pk1"), UpdateMode::Append)
+                .is_err()
+        );
+
+        // Timestamp column is required to sort by.
+        assert!(matches!(
+            schema.clone().with_sort_by_timestamp(true),
+            Err(Error::InvalidSchema(_))
+        ));
+        assert_eq!(vec![0, 1], schema.sort_key_idxes());
+
+        // Merge operators are only for registered ones of value columns, with
+        // types supported by them.
+        assert_eq!(BYTES_MERGE_OPERATOR, schema.merge_operator(2));
+        let with_operators = schema
+            .clone()
+            .with_merge_operators(HashMap::from([(
+                "value".to_string(),
+                LAST_VALUE_OPERATOR.to_string(),
+            )]))
+            .unwrap();
+        assert_eq!(LAST_VALUE_OPERATOR, with_operators.merge_operator(2));
+        for (column, name) in [
+            ("pk1", LAST_VALUE_OPERATOR),
+            ("v9", LAST_VALUE_OPERATOR),
+            ("value", "not_registered"),
+            ("value", HLL_OPERATOR),
+        ] {
+            assert!(matches!(
+                schema
+                    .clone()
+                    .with_merge_operators(HashMap::from([(column.to_string(), name.to_string())])),
+                Err(Error::InvalidSchema(_))
+            ));
+        }
 
         let batch = record_batch!(
             ("pk1", UInt8, vec![11, 11, 9, 10]),
@@ -278,7 +713,9 @@ mod tests {
         )
         .unwrap();
         let sequence = 999;
-        let new_batch = schema.fill_builtin_columns(batch, sequence).unwrap();
+        let new_batch = schema
+            .fill_builtin_columns(batch.clone(), sequence, RowKind::Put)
+            .unwrap();
         let expected_batch = record_batch!(
             ("pk1", UInt8, vec![11, 11, 9, 10]),
             ("pk2", UInt8, vec![100, 99, 1, 2]),
@@ -289,15 +726,137 @@ mod tests {
         .unwrap();
         assert_eq!(new_batch, expected_batch);
 
+        let new_batch = schema
+            .fill_builtin_columns(batch, sequence, RowKind::Delete)
+            .unwrap();
+        let reserved = new_batch.column(schema.reserved_idx).as_primitive();
+        for idx in 0..new_batch.num_rows() {
+            assert_eq!(RowKind::Delete, RowKind::from_reserved(reserved, idx));
+        }
+
         let mut testcases = [
             (None, None),
-            (Some(vec![]), Some(vec![0, 1, 3])),
-            (Some(vec![1]), Some(vec![1, 0, 3])),
-            (Some(vec![2]), Some(vec![2, 0, 1, 3])),
+            (Some(vec![]), Some(vec![0, 1, 3, 4])),
+            (Some(vec![1]), Some(vec![1, 0, 3, 4])),
+            (Some(vec![2]), Some(vec![2, 0, 1, 3, 4])),
+            (Some(vec![4, 2, 3]), Some(vec![2, 0, 1, 3, 4])),
         ];
         for (input, expected) in testcases.iter_mut() {
             schema.fill_required_projections(input);
             assert_eq!(input, expected);
         }
     }
+
+    #[test]
+    fn test_alter_storage_schema() {
+        let arrow_schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("v1", Int64));
+        let schema = StorageSchema::try_new(arrow_schema, 1, Some("ts"), UpdateMode::Overwrite)
+            .unwrap()
+            .with_merge_operators(HashMap::from([(
+                "v1".to_string(),
+                SUM_OPERATOR.to_string(),
+            )]))
+            .unwrap();
+        let v2 = Arc::new(Field::new("v2", DataType::Utf8, true));
+        let new_schema = schema.alter(&[v2.clone()], &["v1".to_string()]).unwrap();
+        assert_eq!(2, new_schema.version);
+        // Operators of dropped columns are removed.
+        assert!(new_schema.merge_operators.is_empty());
+        assert_eq!(
+            arrow_schema!(("pk1", UInt8), ("ts", Int64), ("v2", Utf8)),
+            new_schema.user_schema()
+        );
+        assert_eq!(vec![1, 2], new_schema.value_idxes);
+        assert_eq!(Some(1), new_schema.timestamp_idx);
+
+        // Invalid alters.
+        let not_null = Arc::new(Field::new("v3", DataType::Utf8, false));
+        for (add_columns, drop_columns) in [
+            (vec![not_null], vec![]),
+            (vec![v2.clone()], vec![]),
+            (vec![], vec!["pk1".to_string()]),
+            (vec![], vec!["ts".to_string()]),
+            (vec![], vec!["v3".to_string()]),
+        ] {
+            assert!(new_schema.alter(&add_columns, &drop_columns).is_err());
+        }
+        // Dropped column can't be added again.
+        let v1 = Arc::new(Field::new("v1", DataType::Int64, true));
+        assert!(new_schema.alter(&[v1], &[]).is_err());
+
+        // Batch of old schema is projected onto new one.
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![1, 2]),
+            ("ts", Int64, vec![10, 20]),
+            ("v1", Int64, vec![3, 4])
+        )
+        .unwrap();
+        let batch = schema.fill_builtin_columns(batch, 1, RowKind::Put).unwrap();
+        let expected = record_batch!(
+            ("pk1", UInt8, vec![1, 2]),
+            ("ts", Int64, vec![10, 20]),
+            ("v2", Utf8, vec![None::<&str>; 2]),
+            (SEQ_COLUMN_NAME, UInt64, vec![1; 2]),
+            (RESERVED_COLUMN_NAME, UInt64, vec![None; 2])
+        )
+        .unwrap();
+        assert_eq!(expected, new_schema.project_batch(batch).unwrap());
+    }
+
+    #[test]
+    fn test_storage_schema_timestamps() {
+        let arrow_schema = arrow_schema!(("pk1", UInt8), ("ts", Int64), ("value", Int64));
+        let schema =
+            StorageSchema::try_new(arrow_schema, 1, Some("ts"), UpdateMode::Overwrite).unwrap();
+        assert_eq!(Some(1), schema.timestamp_idx);
+
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 12]),
+            ("ts", Int64, vec![Some(10), Some(20)]),
+            ("value", Int64, vec![1, 2])
+        )
+        .unwrap();
+        let timestamps = schema.timestamps(&batch).unwrap().unwrap();
+        assert_eq!(&[10, 20], timestamps.values().as_ref());
+        assert_eq!(
+            Some(TimeRange::from(10..21)),
+            schema.time_range(&batch).unwrap()
+        );
+        assert_eq!(None, schema.time_range(&batch.slice(0, 0)).unwrap());
+
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 12]),
+            ("ts", Int64, vec![Some(10), None]),
+            ("value", Int64, vec![1, 2])
+        )
+        .unwrap();
+        assert!(schema.timestamps(&batch).is_err());
+    }
+
+    #[test]
+    fn test_sort_key_idxes() {
+        let arrow_schema = arrow_schema!(("pk1", UInt8), ("value", Int64), ("ts", Int64));
+        let schema =
+            StorageSchema::try_new(arrow_schema.clone(), 1, Some("ts"), UpdateMode::Overwrite)
+                .unwrap();
+        assert_eq!(vec![0], schema.sort_key_idxes());
+        let mut projection = Some(vec![1]);
+        schema.fill_required_projections(&mut projection);
+        assert_eq!(Some(vec![1, 0, 2, 3, 4]), projection);
+
+        let schema = schema.with_sort_by_timestamp(true).unwrap();
+        assert_eq!(vec![0, 2], schema.sort_key_idxes());
+        // Kept after altering.
+        let schema = schema
+            .alter(&[Arc::new(Field::new("v2", DataType::Int64, true))], &[])
+            .unwrap();
+        assert_eq!(vec![0, 2], schema.sort_key_idxes());
+
+        // Timestamp column is in primary keys already.
+        let schema = StorageSchema::try_new(arrow_schema, 2, Some("value"), UpdateMode::Overwrite)
+            .unwrap()
+            .with_sort_by_timestamp(true)
+            .unwrap();
+        assert_eq!(vec![0, 1], schema.sort_key_idxes());
+    }
 }
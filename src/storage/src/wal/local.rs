@@ -0,0 +1,279 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    wal::{decode_entries, Wal, WalEntry},
+    Result,
+};
+
+const SEGMENT_SUFFIX: &str = ".wal";
+
+struct Segment {
+    path: PathBuf,
+    /// `None` means this segment is not replayed yet.
+    last_sequence: Option<u64>,
+}
+
+struct ActiveSegment {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    last_sequence: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Sorted by sequence.
+    sealed: Vec<Segment>,
+    active: Option<ActiveSegment>,
+}
+
+impl Inner {
+    fn seal_active(&mut self) {
+        if let Some(active) = self.active.take() {
+            self.sealed.push(Segment {
+                path: active.path,
+                last_sequence: Some(active.last_sequence),
+            });
+        }
+    }
+}
+
+/// WAL stored in local disk.
+///
+/// The log is split into segments named by the first sequence in them, each
+/// append is fsynced before return.
+pub struct LocalDiskWal {
+    dir: PathBuf,
+    segment_max_size: u64,
+    inner: Mutex<Inner>,
+}
+
+impl LocalDiskWal {
+    pub async fn try_new(dir: String, segment_max_size: u64) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("create wal dir, path:{}", dir.display()))?;
+
+        let mut paths = Vec::new();
+        let mut read_dir = fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("read wal dir, path:{}", dir.display()))?;
+        while let Some(entry) = read_dir.next_entry().await.context("read wal dir entry")? {
+            let path = entry.path();
+            if path.to_string_lossy().ends_with(SEGMENT_SUFFIX) {
+                paths.push(path);
+            }
+        }
+        // Segment name is zero padded, so it's also sorted by sequence.
+        paths.sort();
+        debug!(segments = ?paths, "Open local wal");
+
+        let sealed = paths
+            .into_iter()
+            .map(|path| Segment {
+                path,
+                last_sequence: None,
+            })
+            .collect();
+        Ok(Self {
+            dir,
+            segment_max_size,
+            inner: Mutex::new(Inner {
+                sealed,
+                active: None,
+            }),
+        })
+    }
+
+    async fn create_segment(&self, first_sequence: u64) -> Result<ActiveSegment> {
+        let path = self
+            .dir
+            .join(format!("{first_sequence:020}{SEGMENT_SUFFIX}"));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("create wal segment, path:{}", path.display()))?;
+        // Make the new file entry durable.
+        File::open(&self.dir)
+            .await
+            .context("open wal dir")?
+            .sync_all()
+            .await
+            .context("sync wal dir")?;
+
+        Ok(ActiveSegment {
+            file,
+            path,
+            size: 0,
+            last_sequence: first_sequence,
+        })
+    }
+}
+
+#[async_trait]
+impl Wal for LocalDiskWal {
+    async fn append(&self, entry: &WalEntry) -> Result<()> {
+        let buf = entry.encode()?;
+        let mut inner = self.inner.lock().await;
+        if inner
+            .active
+            .as_ref()
+            .is_some_and(|active| active.size >= self.segment_max_size)
+        {
+            inner.seal_active();
+        }
+        if inner.active.is_none() {
+            inner.active = Some(self.create_segment(entry.sequence).await?);
+        }
+
+        let active = inner.active.as_mut().unwrap();
+        active
+            .file
+            .write_all(&buf)
+            .await
+            .with_context(|| format!("write wal segment, path:{}", active.path.display()))?;
+        active
+            .file
+            .sync_data()
+            .await
+            .with_context(|| format!("sync wal segment, path:{}", active.path.display()))?;
+        active.size += buf.len() as u64;
+        active.last_sequence = entry.sequence;
+
+        Ok(())
+    }
+
+    async fn replay(&self) -> Result<Vec<WalEntry>> {
+        let mut inner = self.inner.lock().await;
+        let mut entries = Vec::new();
+        for segment in &mut inner.sealed {
+            let bytes = fs::read(&segment.path)
+                .await
+                .with_context(|| format!("read wal segment, path:{}", segment.path.display()))?;
+            let (segment_entries, torn) = decode_entries(Bytes::from(bytes))?;
+            if torn {
+                warn!(path = ?segment.path, "Ignore torn entry in wal segment");
+            }
+            segment.last_sequence = Some(
+                segment_entries
+                    .last()
+                    .map(|entry| entry.sequence)
+                    .unwrap_or_default(),
+            );
+            entries.extend(segment_entries);
+        }
+
+        Ok(entries)
+    }
+
+    async fn truncate(&self, sequence: u64) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        // Otherwise each write followed by truncate creates a new segment.
+        // Entries left in the active segment are skipped when replayed, since
+        // they are already persisted.
+        if inner.active.as_ref().is_some_and(|active| {
+            active.last_sequence <= sequence && active.size >= self.segment_max_size
+        }) {
+            inner.seal_active();
+        }
+
+        while let Some(segment) = inner.sealed.first() {
+            match segment.last_sequence {
+                Some(last_sequence) if last_sequence <= sequence => {}
+                _ => break,
+            }
+            fs::remove_file(&segment.path)
+                .await
+                .with_context(|| format!("delete wal segment, path:{}", segment.path.display()))?;
+            debug!(path = ?segment.path, "Delete wal segment");
+            inner.sealed.remove(0);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{record_batch, types::RowKind};
+
+    fn build_entry(sequence: u64) -> WalEntry {
+        WalEntry {
+            sequence,
+            kind: RowKind::Put,
+            batches: vec![(
+                (1..10).into(),
+                record_batch!(("pk1", UInt8, vec![11, 9]), ("value", Int64, vec![2, 4])).unwrap(),
+            )],
+            write_keys: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_wal() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let dir = root_dir.path().to_string_lossy().to_string();
+        let entries = (1..=10).map(build_entry).collect::<Vec<_>>();
+        {
+            // Small segment size, so each entry is in its own segment.
+            let wal = LocalDiskWal::try_new(dir.clone(), 1).await.unwrap();
+            assert!(wal.replay().await.unwrap().is_empty());
+            for entry in &entries {
+                wal.append(entry).await.unwrap();
+            }
+            wal.truncate(3).await.unwrap();
+        }
+
+        let wal = LocalDiskWal::try_new(dir.clone(), 1).await.unwrap();
+        assert_eq!(entries[3..], wal.replay().await.unwrap());
+        wal.append(&build_entry(11)).await.unwrap();
+        wal.truncate(11).await.unwrap();
+        assert!(wal.replay().await.unwrap().is_empty());
+
+        let mut read_dir = fs::read_dir(&dir).await.unwrap();
+        assert!(read_dir.next_entry().await.unwrap().is_none());
+
+        // The active segment is kept until it's full.
+        let wal = LocalDiskWal::try_new(dir.clone(), 1 << 20).await.unwrap();
+        for entry in &entries[..2] {
+            wal.append(entry).await.unwrap();
+            wal.truncate(entry.sequence).await.unwrap();
+        }
+        let mut read_dir = fs::read_dir(&dir).await.unwrap();
+        assert!(read_dir.next_entry().await.unwrap().is_some());
+        assert!(read_dir.next_entry().await.unwrap().is_none());
+        let wal = LocalDiskWal::try_new(dir, 1 << 20).await.unwrap();
+        assert_eq!(entries[..2], wal.replay().await.unwrap());
+    }
+}
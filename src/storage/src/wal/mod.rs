@@ -0,0 +1,322 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Write-ahead log for the storage.
+//!
+//! Every write is appended to the WAL and persisted before it's acknowledged,
+//! entries are truncated once the data they carry is flushed to SSTs and
+//! recorded in the manifest.
+
+mod local;
+mod object_store;
+
+use std::{
+    io::{Cursor, Read},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use arrow::{
+    array::RecordBatch,
+    ipc::{reader::StreamReader, writer::StreamWriter},
+};
+use async_trait::async_trait;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+pub use local::LocalDiskWal;
+pub use object_store::ObjectStoreWal;
+
+use crate::{
+    config::WalConfig,
+    ensure,
+    manifest::WriteKey,
+    types::{ObjectStoreRef, RowKind, TimeRange},
+    Error, Result,
+};
+
+pub type WalRef = Arc<dyn Wal>;
+
+#[async_trait]
+pub trait Wal: Send + Sync {
+    /// Append entry to the log, the entry is durable when this returns.
+    ///
+    /// Entries must be appended with increasing sequence.
+    async fn append(&self, entry: &WalEntry) -> Result<()>;
+
+    /// Read all entries not truncated yet, ordered by sequence.
+    async fn replay(&self) -> Result<Vec<WalEntry>>;
+
+    /// Remove entries whose sequence is less than or equal to `sequence`.
+    async fn truncate(&self, sequence: u64) -> Result<()>;
+}
+
+pub async fn open_wal(
+    config: &WalConfig,
+    root_dir: &str,
+    store: ObjectStoreRef,
+) -> Result<Option<WalRef>> {
+    let wal: WalRef = match config {
+        WalConfig::Disabled => return Ok(None),
+        WalConfig::Local(config) => Arc::new(
+            LocalDiskWal::try_new(config.dir.clone(), config.segment_max_size.as_byte()).await?,
+        ),
+        WalConfig::ObjectStore => Arc::new(ObjectStoreWal::new(root_dir, store)),
+    };
+
+    Ok(Some(wal))
+}
+
+/// Entry of one write, which may contain multiple batches to be applied
+/// atomically, all batches should share the same schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalEntry {
+    pub sequence: u64,
+    pub kind: RowKind,
+    pub batches: Vec<(TimeRange, RecordBatch)>,
+    /// Idempotency keys of the write, they are recorded in manifest along
+    /// with batches when flushed.
+    pub write_keys: Vec<WriteKey>,
+}
+
+/// The layout for one entry in the log:
+/// ```plaintext
+/// +------------+-------------+-----------+------------------------------+
+/// | magic(u32) | length(u32) | crc(u32)  | payload(length bytes)        |
+/// +------------+-------------+-----------+------------------------------+
+/// ```
+/// payload is composed of:
+/// ```plaintext
+/// +---------------+----------+---------------+--------------------+----------+-----------------------+---------------------------+
+/// | sequence(u64) | kind(u8) | num_keys(u32) | write_key*num_keys | num(u32) | time_range(i64*2)*num | num batches in IPC format |
+/// +---------------+----------+---------------+--------------------+----------+-----------------------+---------------------------+
+/// ```
+/// crc is computed over the payload, kind is 0 for puts and 1 for deletes.
+/// See [`WriteKey`] for the layout of write keys.
+impl WalEntry {
+    pub const HEADER_LENGTH: usize = 4 /*magic*/ + 4 /*length*/ + 4 /*crc*/;
+    pub const MAGIC: u32 = 0xCAFE_567A;
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        payload
+            .write_u64::<LittleEndian>(self.sequence)
+            .context("write shall not fail.")?;
+        let kind = match self.kind {
+            RowKind::Put => 0,
+            RowKind::Delete => 1,
+        };
+        payload.write_u8(kind).context("write shall not fail.")?;
+        payload
+            .write_u32::<LittleEndian>(self.write_keys.len() as u32)
+            .context("write shall not fail.")?;
+        for write_key in &self.write_keys {
+            write_key.write_to(&mut payload)?;
+        }
+        payload
+            .write_u32::<LittleEndian>(self.batches.len() as u32)
+            .context("write shall not fail.")?;
+        for (time_range, _) in &self.batches {
+            payload
+                .write_i64::<LittleEndian>(*time_range.start)
+                .context("write shall not fail.")?;
+            payload
+                .write_i64::<LittleEndian>(*time_range.end)
+                .context("write shall not fail.")?;
+        }
+        if let Some((_, first)) = self.batches.first() {
+            let mut writer = StreamWriter::try_new(&mut payload, &first.schema())
+                .context("create ipc writer")?;
+            for (_, batch) in &self.batches {
+                writer.write(batch).context("write ipc batch")?;
+            }
+            writer.finish().context("finish ipc writer")?;
+        }
+
+        let mut buf = Vec::with_capacity(Self::HEADER_LENGTH + payload.len());
+        buf.write_u32::<LittleEndian>(Self::MAGIC)
+            .context("write shall not fail.")?;
+        buf.write_u32::<LittleEndian>(payload.len() as u32)
+            .context("write shall not fail.")?;
+        buf.write_u32::<LittleEndian>(crc32fast::hash(&payload))
+            .context("write shall not fail.")?;
+        buf.extend_from_slice(&payload);
+
+        Ok(buf)
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(payload);
+        let sequence = cursor
+            .read_u64::<LittleEndian>()
+            .context("read entry sequence")?;
+        let kind = match cursor.read_u8().context("read entry kind")? {
+            0 => RowKind::Put,
+            1 => RowKind::Delete,
+            v => {
+                return Err(Error::Corruption(format!(
+                    "unknown wal entry kind, value:{v}"
+                )))
+            }
+        };
+        let num_keys = cursor
+            .read_u32::<LittleEndian>()
+            .context("read entry write key num")?;
+        let mut write_keys = Vec::with_capacity(num_keys as usize);
+        for _ in 0..num_keys {
+            write_keys.push(WriteKey::try_new(&mut cursor)?);
+        }
+        let num = cursor
+            .read_u32::<LittleEndian>()
+            .context("read entry batch num")? as usize;
+        let mut time_ranges = Vec::with_capacity(num);
+        for _ in 0..num {
+            let start = cursor
+                .read_i64::<LittleEndian>()
+                .context("read entry start")?;
+            let end = cursor
+                .read_i64::<LittleEndian>()
+                .context("read entry end")?;
+            time_ranges.push(TimeRange::from(start..end));
+        }
+        let mut batches = Vec::with_capacity(num);
+        if num > 0 {
+            let reader = StreamReader::try_new(cursor, None).context("create ipc reader")?;
+            for (time_range, batch) in time_ranges.into_iter().zip(reader) {
+                batches.push((time_range, batch.context("read ipc batch")?));
+            }
+            ensure!(
+                batches.len() == num,
+                Error::Corruption(format!(
+                    "record batch is missing in entry, expected:{num}, found:{}",
+                    batches.len()
+                ))
+            );
+        }
+
+        Ok(Self {
+            sequence,
+            kind,
+            batches,
+            write_keys,
+        })
+    }
+}
+
+/// Decode entries from a log segment.
+///
+/// A torn entry at the tail is expected when crash happens during append, so
+/// it's ignored, the returned bool indicates whether such entry exists.
+pub fn decode_entries(bytes: Bytes) -> Result<(Vec<WalEntry>, bool)> {
+    let mut entries = Vec::new();
+    let mut cursor = Cursor::new(bytes.as_ref());
+    while (cursor.position() as usize) < bytes.len() {
+        let remaining = bytes.len() - cursor.position() as usize;
+        if remaining < WalEntry::HEADER_LENGTH {
+            return Ok((entries, true));
+        }
+        let magic = cursor.read_u32::<LittleEndian>().context("read magic")?;
+        ensure!(
+            magic == WalEntry::MAGIC,
+            Error::Corruption(format!(
+                "invalid wal entry magic, offset:{}",
+                cursor.position() - 4
+            ))
+        );
+        let length = cursor.read_u32::<LittleEndian>().context("read length")? as usize;
+        let crc = cursor.read_u32::<LittleEndian>().context("read crc")?;
+        if remaining - WalEntry::HEADER_LENGTH < length {
+            return Ok((entries, true));
+        }
+
+        let mut payload = vec![0; length];
+        cursor.read_exact(&mut payload).context("read payload")?;
+        if crc32fast::hash(&payload) != crc {
+            // Only the last entry is allowed to be broken.
+            ensure!(
+                cursor.position() as usize == bytes.len(),
+                Error::Corruption(format!(
+                    "wal entry checksum mismatch, offset:{}",
+                    cursor.position() as usize - length - WalEntry::HEADER_LENGTH
+                ))
+            );
+            return Ok((entries, true));
+        }
+        entries.push(WalEntry::decode_payload(&payload)?);
+    }
+
+    Ok((entries, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{record_batch, types::Timestamp};
+
+    fn build_entry(sequence: u64) -> WalEntry {
+        let batch = record_batch!(
+            ("pk1", UInt8, vec![11, 11, 9]),
+            ("value", Binary, vec![b"1", b"2", b"3"])
+        )
+        .unwrap();
+        WalEntry {
+            sequence,
+            kind: if sequence % 2 == 0 {
+                RowKind::Delete
+            } else {
+                RowKind::Put
+            },
+            // Entry may contain multiple batches.
+            batches: (0..sequence)
+                .map(|i| {
+                    let start = i as i64 * 10;
+                    ((start..start + 10).into(), batch.clone())
+                })
+                .collect(),
+            write_keys: (0..sequence)
+                .map(|i| WriteKey {
+                    key: format!("key-{sequence}-{i}"),
+                    timestamp: Timestamp(i as i64),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_entry_encoding() {
+        let entries = (1..4).map(build_entry).collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        for entry in &entries {
+            buf.extend(entry.encode().unwrap());
+        }
+        let (decoded, torn) = decode_entries(Bytes::from(buf.clone())).unwrap();
+        assert!(!torn);
+        assert_eq!(entries, decoded);
+
+        // Torn tail is ignored.
+        buf.truncate(buf.len() - 3);
+        let (decoded, torn) = decode_entries(Bytes::from(buf.clone())).unwrap();
+        assert!(torn);
+        assert_eq!(entries[..2], decoded);
+
+        // Corruption in the middle is an error.
+        buf[WalEntry::HEADER_LENGTH + 1] ^= 0xFF;
+        assert!(matches!(
+            decode_entries(Bytes::from(buf)),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
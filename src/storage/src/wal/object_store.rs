@@ -0,0 +1,195 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use async_trait::async_trait;
+use common::StoreContext;
+use futures::TryStreamExt;
+use object_store::{path::Path, PutPayload};
+use tracing::{debug, warn};
+
+use crate::{
+    types::ObjectStoreRef,
+    wal::{decode_entries, Wal, WalEntry},
+    Error, Result,
+};
+
+pub const PREFIX_PATH: &str = "wal";
+
+/// WAL stored in object store.
+///
+/// Since object store doesn't support append, every append is persisted as
+/// a new segment object named by the sequence of the entry:
+/// ```plaintext
+/// {root_path}/wal/{sequence}
+/// ```
+pub struct ObjectStoreWal {
+    dir: Path,
+    store: ObjectStoreRef,
+}
+
+impl ObjectStoreWal {
+    pub fn new(root_dir: &str, store: ObjectStoreRef) -> Self {
+        Self {
+            dir: Path::from(format!("{root_dir}/{PREFIX_PATH}")),
+            store,
+        }
+    }
+
+    fn segment_path(&self, sequence: u64) -> Path {
+        Path::from(format!("{}/{sequence:020}", self.dir))
+    }
+
+    /// Returns segments sorted by sequence.
+    async fn list_segments(&self) -> Result<Vec<(u64, Path)>> {
+        let mut segments = self
+            .store
+            .list(Some(&self.dir))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await
+            .with_store_context(|| format!("list wal segments, dir:{}", self.dir))?
+            .into_iter()
+            .map(|location| {
+                let sequence = location
+                    .filename()
+                    .and_then(|name| name.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        Error::Corruption(format!("invalid wal segment, path:{location}"))
+                    })?;
+                Ok((sequence, location))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        segments.sort_unstable_by_key(|(sequence, _)| *sequence);
+
+        Ok(segments)
+    }
+}
+
+#[async_trait]
+impl Wal for ObjectStoreWal {
+    async fn append(&self, entry: &WalEntry) -> Result<()> {
+        let buf = entry.encode()?;
+        let path = self.segment_path(entry.sequence);
+        self.store
+            .put(&path, PutPayload::from(buf))
+            .await
+            .with_store_context(|| format!("write wal segment, path:{path}"))?;
+
+        Ok(())
+    }
+
+    async fn replay(&self) -> Result<Vec<WalEntry>> {
+        let mut entries = Vec::new();
+        for (_, path) in self.list_segments().await? {
+            let bytes = self
+                .store
+                .get(&path)
+                .await
+                .with_store_context(|| format!("get wal segment, path:{path}"))?
+                .bytes()
+                .await
+                .with_store_context(|| format!("read wal segment, path:{path}"))?;
+            let (segment_entries, torn) = decode_entries(bytes)?;
+            if torn {
+                warn!(path = ?path, "Ignore torn entry in wal segment");
+            }
+            entries.extend(segment_entries);
+        }
+
+        Ok(entries)
+    }
+
+    async fn truncate(&self, sequence: u64) -> Result<()> {
+        for (segment_sequence, path) in self.list_segments().await? {
+            if segment_sequence > sequence {
+                break;
+            }
+            self.store
+                .delete(&path)
+                .await
+                .with_store_context(|| format!("delete wal segment, path:{path}"))?;
+            debug!(path = ?path, "Delete wal segment");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use object_store::{memory::InMemory, ObjectStore};
+
+    use super::*;
+    use crate::{record_batch, types::RowKind};
+
+    fn build_entry(sequence: u64) -> WalEntry {
+        WalEntry {
+            sequence,
+            kind: RowKind::Put,
+            batches: vec![(
+                (1..10).into(),
+                record_batch!(("pk1", UInt8, vec![11, 9]), ("value", Int64, vec![2, 4])).unwrap(),
+            )],
+            write_keys: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_store_wal() {
+        let store = Arc::new(InMemory::new());
+        let entries = (1..=10).map(build_entry).collect::<Vec<_>>();
+        {
+            let wal = ObjectStoreWal::new("test", store.clone());
+            assert!(wal.replay().await.unwrap().is_empty());
+            for entry in &entries {
+                wal.append(entry).await.unwrap();
+            }
+            wal.truncate(3).await.unwrap();
+        }
+
+        let wal = ObjectStoreWal::new("test", store.clone());
+        assert_eq!(entries[3..], wal.replay().await.unwrap());
+
+        // Torn entry at the tail of a segment is ignored.
+        let mut buf = build_entry(11).encode().unwrap();
+        let torn = build_entry(12).encode().unwrap();
+        buf.extend_from_slice(&torn[..torn.len() / 2]);
+        store
+            .put(&wal.segment_path(11), PutPayload::from(buf))
+            .await
+            .unwrap();
+        let mut expected = entries[3..].to_vec();
+        expected.push(build_entry(11));
+        assert_eq!(expected, wal.replay().await.unwrap());
+
+        // Broken entry not at the tail is corruption.
+        let mut buf = build_entry(13).encode().unwrap();
+        buf[0] ^= 0xff;
+        buf.extend(build_entry(14).encode().unwrap());
+        store
+            .put(&wal.segment_path(13), PutPayload::from(buf))
+            .await
+            .unwrap();
+        assert!(matches!(wal.replay().await, Err(Error::Corruption(_))));
+
+        wal.truncate(13).await.unwrap();
+        assert!(wal.replay().await.unwrap().is_empty());
+        assert!(wal.list_segments().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use arrow::{
+    array::{AsArray, RecordBatch},
+    datatypes::{SchemaRef, UInt64Type},
+};
+use common::StoreContext;
+use datafusion::{
+    common::DFSchema,
+    execution::{
+        context::ExecutionProps, runtime_env::RuntimeEnvBuilder, SendableRecordBatchStream,
+        TaskContext,
+    },
+    functions_aggregate::min_max::{MaxAccumulator, MinAccumulator},
+    logical_expr::{Accumulator, Expr},
+    physical_expr::LexOrdering,
+    physical_plan::{
+        execute_stream,
+        memory::MemoryExec,
+        sorts::sort::SortExec,
+        streaming::{PartitionStream, StreamingTableExec},
+    },
+    physical_planner::create_physical_sort_exprs,
+    prelude::{ident, SessionConfig, SessionContext},
+};
+use futures::{Stream, StreamExt};
+use object_store::path::Path;
+use parquet::{
+    arrow::{async_writer::ParquetObjectWriter, AsyncArrowWriter},
+    file::{metadata::KeyValue, properties::WriterProperties},
+};
+
+use crate::{
+    sst::{FileId, PrimaryKeyStats, SstPathGenerator, MERGED_METADATA_KEY},
+    types::{ObjectStoreRef, RowKind, SharedSchema, StorageSchema, TimeRange, SEQ_COLUMN_NAME},
+    Result,
+};
+
+/// Summary of a written sst.
+#[derive(Debug)]
+pub struct WriteSummary {
+    pub size: usize,
+    pub num_rows: usize,
+    /// Computed from timestamp column, `None` means there is no timestamp
+    /// column or no rows written.
+    pub time_range: Option<TimeRange>,
+    /// `None` means no rows written or primary keys are of unsupported types.
+    pub primary_key_stats: Option<PrimaryKeyStats>,
+    /// Rows are marked merged and there are no tombstones.
+    pub deduplicated: bool,
+}
+
+pub struct ParquetWriter {
+    store: ObjectStoreRef,
+    schema: SharedSchema,
+    sst_path_gen: Arc<SstPathGenerator>,
+    write_props: WriterProperties,
+}
+
+impl ParquetWriter {
+    pub fn new(
+        store: ObjectStoreRef,
+        schema: SharedSchema,
+        sst_path_gen: Arc<SstPathGenerator>,
+        write_props: WriterProperties,
+    ) -> Self {
+        Self {
+            store,
+            schema,
+            sst_path_gen,
+            write_props,
+        }
+    }
+
+    /// Build sort exprs by sort key, `prefix` is sorted before sort key if
+    /// given.
+    fn build_sort_exprs(
+        schema: &StorageSchema,
+        df_schema: &DFSchema,
+        prefix: Option<Expr>,
+        sort_seq: bool,
+    ) -> Result<LexOrdering> {
+        let mut sort_exprs = prefix
+            .map(|expr| expr.sort(true /* asc */, true /* nulls_first */))
+            .into_iter()
+            .collect::<Vec<_>>();
+        sort_exprs.extend(schema.sort_key_idxes().into_iter().map(|i| {
+            ident(schema.arrow_schema.field(i).name())
+                .sort(true /* asc */, true /* nulls_first */)
+        }));
+        if sort_seq {
+            sort_exprs.push(ident(SEQ_COLUMN_NAME).sort(true, true));
+        }
+        let sort_exprs =
+            create_physical_sort_exprs(&sort_exprs, df_schema, &ExecutionProps::default())
+                .context("create physical sort exprs")?;
+
+        Ok(sort_exprs)
+    }
+
+    /// Sort batch by primary keys, `sort_seq` should only be true when batch
+    /// contains builtin columns.
+    pub async fn sort_batch(
+        &self,
+        batch: RecordBatch,
+        sort_seq: bool,
+    ) -> Result<SendableRecordBatchStream> {
+        let ctx = SessionContext::default();
+        let storage_schema = self.schema.load();
+        let schema = batch.schema();
+        let df_schema =
+            DFSchema::try_from(storage_schema.arrow_schema.clone()).context("build DFSchema")?;
+        let sort_exprs = Self::build_sort_exprs(&storage_schema, &df_schema, None, sort_seq)?;
+        let batch_plan =
+            MemoryExec::try_new(&[vec![batch]], schema, None).context("build batch plan")?;
+        let physical_plan = Arc::new(SortExec::new(sort_exprs, Arc::new(batch_plan)));
+
+        let res =
+            execute_stream(physical_plan, ctx.task_ctx()).context("execute sort physical plan")?;
+        Ok(res)
+    }
+
+    /// Sort stream without builtin columns by `prefix` and primary keys.
+    ///
+    /// Sorted runs are spilled to disk once memory used exceeds
+    /// `memory_limit`, so the stream doesn't need to fit in memory.
+    pub fn sort_stream(
+        &self,
+        stream: SendableRecordBatchStream,
+        prefix: Option<Expr>,
+        memory_limit: usize,
+    ) -> Result<SendableRecordBatchStream> {
+        let runtime = RuntimeEnvBuilder::new()
+            .with_memory_limit(memory_limit, 1.0)
+            .build_arc()
+            .context("build sort runtime")?;
+        // Memory reserved for merging spilled runs, default value is too
+        // large for a small limit.
+        let config = SessionConfig::default().with_sort_spill_reservation_bytes(memory_limit / 4);
+        let ctx = SessionContext::new_with_config_rt(config, runtime);
+        let storage_schema = self.schema.load();
+        let schema = stream.schema();
+        let df_schema = DFSchema::try_from(schema.clone()).context("build DFSchema")?;
+        let sort_exprs = Self::build_sort_exprs(&storage_schema, &df_schema, prefix, false)?;
+        let stream_plan = StreamingTableExec::try_new(
+            schema,
+            vec![Arc::new(OnceStream::new(stream))],
+            None,  // projection
+            None,  // output_ordering
+            false, // infinite
+            None,  // limit
+        )
+        .context("build stream plan")?;
+        let physical_plan = Arc::new(SortExec::new(sort_exprs, Arc::new(stream_plan)));
+
+        let res =
+            execute_stream(physical_plan, ctx.task_ctx()).context("execute sort physical plan")?;
+        Ok(res)
+    }
+
+    /// Create a writer for a new sst with the latest schema.
+    pub fn open(&self, file_id: FileId) -> Result<SstWriter> {
+        let file_path = Path::from(self.sst_path_gen.generate(file_id));
+        let schema = self.schema.load();
+        let object_store_writer = ParquetObjectWriter::new(self.store.clone(), file_path.clone());
+        let writer = AsyncArrowWriter::try_new(
+            object_store_writer,
+            schema.arrow_schema.clone(),
+            Some(self.write_props.clone()),
+        )
+        .context("create arrow writer")?;
+        let primary_key_bounds = schema.arrow_schema.fields()[..schema.num_primary_keys]
+            .iter()
+            .map(|field| {
+                let data_type = field.data_type();
+                Ok((
+                    MinAccumulator::try_new(data_type).context("create min accumulator")?,
+                    MaxAccumulator::try_new(data_type).context("create max accumulator")?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(SstWriter {
+            store: self.store.clone(),
+            file_path,
+            schema,
+            writer,
+            num_rows: 0,
+            time_range: None,
+            primary_key_bounds,
+            merged: false,
+            has_tombstones: false,
+        })
+    }
+
+    /// Write batches to a new sst with the latest schema.
+    ///
+    /// Batches should be sorted and builtin columns should be filled, they
+    /// will be projected onto the latest schema if they are written with
+    /// older ones.
+    pub async fn write<S>(&self, file_id: FileId, mut batches: S) -> Result<WriteSummary>
+    where
+        S: Stream<Item = Result<RecordBatch>> + Unpin,
+    {
+        let mut writer = self.open(file_id)?;
+        while let Some(batch) = batches.next().await {
+            writer.write(batch?).await?;
+        }
+
+        writer.close().await
+    }
+}
+
+/// Writer of one sst, created by [`ParquetWriter::open`].
+pub struct SstWriter {
+    store: ObjectStoreRef,
+    file_path: Path,
+    schema: Arc<StorageSchema>,
+    writer: AsyncArrowWriter<ParquetObjectWriter>,
+    num_rows: usize,
+    time_range: Option<TimeRange>,
+    /// Min and max of each primary key column.
+    primary_key_bounds: Vec<(MinAccumulator, MaxAccumulator)>,
+    merged: bool,
+    has_tombstones: bool,
+}
+
+impl SstWriter {
+    /// Batch should be sorted and builtin columns should be filled.
+    pub async fn write(&mut self, batch: RecordBatch) -> Result<()> {
+        let batch = self.schema.project_batch(batch)?;
+        if let Some(batch_range) = self.schema.time_range(&batch)? {
+            match self.time_range.as_mut() {
+                Some(time_range) => time_range.merge(&batch_range),
+                None => self.time_range = Some(batch_range),
+            }
+        }
+        for (idx, (min, max)) in self.primary_key_bounds.iter_mut().enumerate() {
+            let column = [batch.column(idx).clone()];
+            min.update_batch(&column)
+                .context("update min primary key")?;
+            max.update_batch(&column)
+                .context("update max primary key")?;
+        }
+        if !self.has_tombstones {
+            // Reserved column is always the last one.
+            let reserved = batch
+                .column(batch.num_columns() - 1)
+                .as_primitive::<UInt64Type>();
+            self.has_tombstones = (0..batch.num_rows())
+                .any(|idx| RowKind::from_reserved(reserved, idx) == RowKind::Delete);
+        }
+        self.num_rows += batch.num_rows();
+        self.writer
+            .write(&batch)
+            .await
+            .context("write arrow batch")?;
+
+        Ok(())
+    }
+
+    /// Mark rows written are merged, see [`MERGED_METADATA_KEY`].
+    pub fn mark_merged(&mut self) {
+        self.merged = true;
+        self.writer.append_key_value_metadata(KeyValue::new(
+            MERGED_METADATA_KEY.to_string(),
+            "true".to_string(),
+        ));
+    }
+
+    /// Estimated size of the sst, including data not flushed yet.
+    pub fn estimated_size(&self) -> usize {
+        self.writer.bytes_written() + self.writer.in_progress_size()
+    }
+
+    pub async fn close(mut self) -> Result<WriteSummary> {
+        let primary_key_stats = if self.num_rows == 0 {
+            None
+        } else {
+            let (mut mins, mut maxs) = (Vec::new(), Vec::new());
+            for (min, max) in &mut self.primary_key_bounds {
+                mins.push(min.evaluate().context("evaluate min primary key")?);
+                maxs.push(max.evaluate().context("evaluate max primary key")?);
+            }
+            PrimaryKeyStats::new(mins, maxs)
+        };
+        self.writer.close().await.context("close arrow writer")?;
+        let object_meta = self
+            .store
+            .head(&self.file_path)
+            .await
+            .with_store_context(|| format!("get object meta, path:{}", self.file_path))?;
+
+        Ok(WriteSummary {
+            size: object_meta.size,
+            num_rows: self.num_rows,
+            time_range: self.time_range,
+            primary_key_stats,
+            deduplicated: self.merged && !self.has_tombstones,
+        })
+    }
+}
+
+/// Partition stream which can only be executed once.
+pub(crate) struct OnceStream {
+    schema: SchemaRef,
+    stream: Mutex<Option<SendableRecordBatchStream>>,
+}
+
+impl OnceStream {
+    pub fn new(stream: SendableRecordBatchStream) -> Self {
+        Self {
+            schema: stream.schema(),
+            stream: Mutex::new(Some(stream)),
+        }
+    }
+}
+
+impl fmt::Debug for OnceStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnceStream")
+            .field("schema", &self.schema)
+            .finish()
+    }
+}
+
+impl PartitionStream for OnceStream {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        self.stream
+            .lock()
+            .unwrap()
+            .take()
+            .expect("OnceStream should only be executed once")
+    }
+}
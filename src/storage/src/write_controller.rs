@@ -0,0 +1,287 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Write controller slows down or stops writes when background jobs, such as
+//! manifest merge and compaction, can't catch up with them.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{
+    config::{ManifestConfig, WriteStallConfig},
+    manifest::ManifestRef,
+    types::TimeRange,
+    Error, Result,
+};
+
+/// Pressure of background jobs caused by writes.
+#[derive(Debug, Default)]
+struct Pressure {
+    delta_num: usize,
+    /// Max number of ssts within segments written to.
+    max_ssts_per_segment: usize,
+    compaction_memory: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Pass,
+    Delay(String),
+    Stop(String),
+}
+
+pub struct WriteController {
+    config: WriteStallConfig,
+    manifest: ManifestRef,
+    soft_delta_num: usize,
+    hard_delta_num: usize,
+    compaction_memory: Arc<AtomicU64>,
+    compaction_memory_limit: u64,
+}
+
+impl WriteController {
+    pub fn new(
+        config: WriteStallConfig,
+        manifest_config: &ManifestConfig,
+        manifest: ManifestRef,
+        compaction_memory: Arc<AtomicU64>,
+        compaction_memory_limit: u64,
+    ) -> Self {
+        Self {
+            config,
+            manifest,
+            soft_delta_num: manifest_config.soft_merge_threshold,
+            hard_delta_num: manifest_config.hard_merge_threshold,
+            compaction_memory,
+            compaction_memory_limit,
+        }
+    }
+
+    /// Wait until the write to `time_ranges` is allowed.
+    ///
+    /// Write is delayed for a while under pressure, and blocked until the
+    /// pressure is relieved when stopped, [`Error::WriteStalled`] is returned
+    /// if it's blocked longer than `max_stall_duration`. Only segments written
+    /// to are considered for ssts, others don't grow by the write.
+    pub async fn admit(&self, time_ranges: &[TimeRange]) -> Result<()> {
+        if !self.config.enable {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        loop {
+            let mut max_ssts_per_segment = 0;
+            for time_range in time_ranges {
+                max_ssts_per_segment =
+                    max_ssts_per_segment.max(self.manifest.max_ssts_per_segment(time_range).await);
+            }
+            let pressure = Pressure {
+                delta_num: self.manifest.delta_num(),
+                max_ssts_per_segment,
+                compaction_memory: self.compaction_memory.load(Ordering::Relaxed),
+            };
+            match self.decide(&pressure) {
+                Decision::Pass => return Ok(()),
+                Decision::Delay(reason) => {
+                    debug!(reason, "Delay write");
+                    sleep(self.config.slowdown_delay.0).await;
+                    return Ok(());
+                }
+                Decision::Stop(reason) => {
+                    if start.elapsed() >= self.config.max_stall_duration.0 {
+                        warn!(reason, "Write stalled");
+                        return Err(Error::WriteStalled(reason));
+                    }
+                    sleep(self.config.slowdown_delay.0).await;
+                }
+            }
+        }
+    }
+
+    fn decide(&self, pressure: &Pressure) -> Decision {
+        // Manifest update fails once delta files exceed the hard threshold.
+        if pressure.delta_num >= self.hard_delta_num {
+            return Decision::Stop(format!(
+                "too many manifest delta files, value:{}, limit:{}",
+                pressure.delta_num, self.hard_delta_num
+            ));
+        }
+        if pressure.max_ssts_per_segment >= self.config.stop_ssts_per_segment {
+            return Decision::Stop(format!(
+                "too many ssts in one segment, value:{}, limit:{}",
+                pressure.max_ssts_per_segment, self.config.stop_ssts_per_segment
+            ));
+        }
+
+        if pressure.delta_num > self.soft_delta_num {
+            return Decision::Delay(format!(
+                "manifest delta files exceed soft limit, value:{}, limit:{}",
+                pressure.delta_num, self.soft_delta_num
+            ));
+        }
+        if pressure.max_ssts_per_segment >= self.config.slowdown_ssts_per_segment {
+            return Decision::Delay(format!(
+                "ssts in one segment exceed slowdown limit, value:{}, limit:{}",
+                pressure.max_ssts_per_segment, self.config.slowdown_ssts_per_segment
+            ));
+        }
+        let memory_limit = (self.compaction_memory_limit as f64
+            * self.config.slowdown_compaction_memory_ratio) as u64;
+        if pressure.compaction_memory >= memory_limit {
+            return Decision::Delay(format!(
+                "compaction memory exceeds slowdown limit, value:{}, limit:{memory_limit}",
+                pressure.compaction_memory
+            ));
+        }
+
+        Decision::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::ReadableDuration;
+    use object_store::local::LocalFileSystem;
+
+    use super::*;
+    use crate::{manifest::Manifest, sst::FileMeta};
+
+    #[test]
+    fn test_write_controller() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let manifest_config = ManifestConfig {
+            soft_merge_threshold: 5,
+            hard_merge_threshold: 10,
+            ..Default::default()
+        };
+        let config = WriteStallConfig {
+            slowdown_ssts_per_segment: 20,
+            stop_ssts_per_segment: 50,
+            slowdown_delay: ReadableDuration::millis(1),
+            max_stall_duration: ReadableDuration::millis(10),
+            ..Default::default()
+        };
+        runtime.clone().block_on(async move {
+            let manifest = Manifest::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Arc::new(LocalFileSystem::new()),
+                runtime,
+                Duration::from_secs(3600),
+                manifest_config.clone(),
+            )
+            .await
+            .unwrap();
+            let manifest = Arc::new(manifest);
+            let compaction_memory = Arc::new(AtomicU64::new(0));
+            let new_controller = |config| {
+                WriteController::new(
+                    config,
+                    &manifest_config,
+                    manifest.clone(),
+                    compaction_memory.clone(),
+                    100, // compaction_memory_limit
+                )
+            };
+            let controller = new_controller(config.clone());
+
+            // (delta_num, max_ssts_per_segment, compaction_memory)
+            let cases = [
+                ((0, 0, 0), "pass"),
+                ((6, 0, 0), "delay"),
+                ((10, 0, 0), "stop"),
+                ((0, 20, 0), "delay"),
+                ((0, 50, 0), "stop"),
+                ((0, 0, 89), "pass"),
+                ((0, 0, 90), "delay"),
+                ((6, 50, 0), "stop"),
+            ];
+            for ((delta_num, max_ssts_per_segment, compaction_memory), expected) in cases {
+                let decision = controller.decide(&Pressure {
+                    delta_num,
+                    max_ssts_per_segment,
+                    compaction_memory,
+                });
+                let actual = match decision {
+                    Decision::Pass => "pass",
+                    Decision::Delay(_) => "delay",
+                    Decision::Stop(_) => "stop",
+                };
+                assert_eq!(
+                    expected, actual,
+                    "case:{delta_num},{max_ssts_per_segment},{compaction_memory}"
+                );
+            }
+
+            let ranges = [TimeRange::from(0..10)];
+            controller.admit(&ranges).await.unwrap();
+            // Delayed write is still allowed.
+            compaction_memory.store(100, Ordering::Relaxed);
+            controller.admit(&ranges).await.unwrap();
+            compaction_memory.store(0, Ordering::Relaxed);
+
+            // Always stopped.
+            let controller = new_controller(WriteStallConfig {
+                stop_ssts_per_segment: 0,
+                ..config.clone()
+            });
+            assert!(matches!(
+                controller.admit(&ranges).await,
+                Err(Error::WriteStalled(_))
+            ));
+
+            // Only segments written to are stopped by too many ssts.
+            let controller = new_controller(WriteStallConfig {
+                stop_ssts_per_segment: 1,
+                ..config
+            });
+            manifest
+                .add_file(
+                    1,
+                    FileMeta {
+                        max_sequence: 1,
+                        num_rows: 1,
+                        size: 1,
+                        time_range: (0..10).into(),
+                        primary_key_stats: None,
+                        deduplicated: false,
+                        merged: false,
+                    },
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                controller.admit(&ranges).await,
+                Err(Error::WriteStalled(_))
+            ));
+            controller
+                .admit(&[TimeRange::from(3_600_000..3_600_010)])
+                .await
+                .unwrap();
+        });
+    }
+}